@@ -0,0 +1,288 @@
+use std::fmt::Write;
+
+/// Standard colours (and their bright versions), close to what most terminals use
+const PALETTE: [&str; 16] = [
+    "#000000", "#cd3131", "#0dbc79", "#e5e510", "#2472c8", "#bc3fbc", "#11a8cd", "#e5e5e5",
+    "#666666", "#f14c4c", "#23d18b", "#f5f543", "#3b8eea", "#d670d6", "#29b8db", "#ffffff",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Color {
+    /// Index into the 256 colour palette
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+impl Color {
+    fn to_css(self) -> String {
+        match self {
+            Color::Indexed(index @ 0..=15) => PALETTE[index as usize].to_owned(),
+            Color::Indexed(index @ 16..=231) => {
+                // 6x6x6 colour cube
+                let level = |value: u8| if value == 0 { 0 } else { 55 + value * 40 };
+                let index = index - 16;
+                format!("#{:02x}{:02x}{:02x}", level(index / 36), level((index / 6) % 6), level(index % 6))
+            }
+            Color::Indexed(index) => {
+                let level = 8 + (index - 232) * 10; // greyscale ramp
+                format!("#{:02x}{:02x}{:02x}", level, level, level)
+            }
+            Color::Rgb(red, green, blue) => format!("#{:02x}{:02x}{:02x}", red, green, blue),
+        }
+    }
+}
+
+/// Text attributes set by SGR ('Select Graphic Rendition') escape sequences
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Style {
+    bold: bool,
+    faint: bool,
+    italic: bool,
+    underline: bool,
+    inverse: bool,
+    strikethrough: bool,
+    foreground: Option<Color>,
+    background: Option<Color>,
+}
+
+impl Style {
+    fn apply(&mut self, params: &[u16]) {
+        let mut params = params.iter().copied();
+
+        while let Some(param) = params.next() {
+            match param {
+                0 => *self = Style::default(),
+                1 => self.bold = true,
+                2 => self.faint = true,
+                3 => self.italic = true,
+                4 => self.underline = true,
+                7 => self.inverse = true,
+                9 => self.strikethrough = true,
+                22 => {
+                    self.bold = false;
+                    self.faint = false;
+                }
+                23 => self.italic = false,
+                24 => self.underline = false,
+                27 => self.inverse = false,
+                29 => self.strikethrough = false,
+                30..=37 => self.foreground = Some(Color::Indexed((param - 30) as u8)),
+                38 => self.foreground = extended_color(&mut params),
+                39 => self.foreground = None,
+                40..=47 => self.background = Some(Color::Indexed((param - 40) as u8)),
+                48 => self.background = extended_color(&mut params),
+                49 => self.background = None,
+                90..=97 => self.foreground = Some(Color::Indexed((param - 90 + 8) as u8)),
+                100..=107 => self.background = Some(Color::Indexed((param - 100 + 8) as u8)),
+                _ => {} // not supported (eg, blinking)
+            }
+        }
+    }
+
+    /// CSS declarations for the style (empty for the default style)
+    fn to_css(self) -> String {
+        let (foreground, background) = if self.inverse {
+            (Some(self.background.unwrap_or(Color::Indexed(0))), Some(self.foreground.unwrap_or(Color::Indexed(7))))
+        } else {
+            (self.foreground, self.background)
+        };
+
+        let mut css = String::new();
+
+        if let Some(color) = foreground {
+            write!(css, "color:{};", color.to_css()).unwrap();
+        }
+        if let Some(color) = background {
+            write!(css, "background-color:{};", color.to_css()).unwrap();
+        }
+        if self.bold {
+            css.push_str("font-weight:bold;");
+        }
+        if self.faint {
+            css.push_str("opacity:0.7;");
+        }
+        if self.italic {
+            css.push_str("font-style:italic;");
+        }
+        match (self.underline, self.strikethrough) {
+            (true, true) => css.push_str("text-decoration:underline line-through;"),
+            (true, false) => css.push_str("text-decoration:underline;"),
+            (false, true) => css.push_str("text-decoration:line-through;"),
+            (false, false) => {}
+        }
+
+        css
+    }
+}
+
+/// Colour from '38;5;n' or '38;2;r;g;b' (the same for backgrounds, with 48)
+fn extended_color<I>(params: &mut I) -> Option<Color>
+    where I: Iterator<Item=u16> {
+    match params.next() {
+        Some(5) => params.next().map(|index| Color::Indexed(index.min(255) as u8)),
+        Some(2) => {
+            let mut component = || params.next().map(|value| value.min(255) as u8);
+            match (component(), component(), component()) {
+                (Some(red), Some(green), Some(blue)) => Some(Color::Rgb(red, green, blue)),
+                _ => None
+            }
+        }
+        _ => None
+    }
+}
+
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    push_escaped(&mut escaped, text);
+    escaped
+}
+
+fn push_escaped(html: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            c => html.push(c),
+        }
+    }
+}
+
+/// Converts lines of terminal output to HTML, one at a time. Styles carry on from one line to
+/// the next (like a terminal), but each line of HTML is complete on its own so it can be sent
+/// as soon as it's ready.
+#[derive(Default)]
+pub struct AnsiToHtml {
+    style: Style,
+}
+
+impl AnsiToHtml {
+    pub fn new() -> AnsiToHtml {
+        AnsiToHtml::default()
+    }
+
+    /// Styled spans for the text (with any other escape sequences, such as cursor movement, removed)
+    pub fn convert(&mut self, text: &str) -> String {
+        let mut html = String::new();
+        let mut plain = String::new(); // text waiting to be written in the current style
+
+        let mut chars = text.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            if c != '\x1b' {
+                plain.push(c);
+                continue;
+            }
+
+            match chars.peek() {
+                Some('[') => {
+                    chars.next();
+                    // parameters, then intermediate bytes, then a final byte (ECMA-48)
+                    let mut sequence = String::new();
+                    let mut last = None;
+                    for c in chars.by_ref() {
+                        if ('\x40'..='\x7e').contains(&c) {
+                            last = Some(c);
+                            break;
+                        }
+                        sequence.push(c);
+                    }
+
+                    if last == Some('m') {
+                        self.flush(&mut html, &mut plain);
+                        let params: Vec<u16> = sequence.split(';')
+                            .map(|param| param.parse().unwrap_or(0)) // empty means zero
+                            .collect();
+                        self.style.apply(&params);
+                    }
+                }
+                Some(']') => {
+                    // operating system command (eg, setting the window title), ended by BEL or ST
+                    chars.next();
+                    while let Some(c) = chars.next() {
+                        if c == '\x07' || (c == '\x1b' && chars.next_if_eq(&'\\').is_some()) {
+                            break;
+                        }
+                    }
+                }
+                Some(_) => {
+                    // any intermediate bytes (eg, choosing a character set), then a final byte
+                    while chars.next_if(|c| ('\x20'..='\x2f').contains(c)).is_some() {}
+                    chars.next();
+                }
+                None => {}
+            }
+        }
+
+        self.flush(&mut html, &mut plain);
+
+        html
+    }
+
+    fn flush(&self, html: &mut String, plain: &mut String) {
+        if plain.is_empty() {
+            return;
+        }
+
+        let css = self.style.to_css();
+
+        if css.is_empty() {
+            push_escaped(html, plain);
+        } else {
+            write!(html, "<span style=\"{}\">", css).unwrap();
+            push_escaped(html, plain);
+            html.push_str("</span>");
+        }
+
+        plain.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_html() {
+        assert_eq!(escape_html("<b>\"Tom\" & 'Jerry'</b>"), "&lt;b&gt;&quot;Tom&quot; &amp; &#39;Jerry&#39;&lt;/b&gt;");
+    }
+
+    #[test]
+    fn test_convert_plain() {
+        let mut converter = AnsiToHtml::new();
+
+        assert_eq!(converter.convert("Hello <world>\n"), "Hello &lt;world&gt;\n");
+        assert_eq!(converter.convert(""), "");
+    }
+
+    #[test]
+    fn test_convert_colors() {
+        let mut converter = AnsiToHtml::new();
+
+        assert_eq!(converter.convert("\x1b[31mError\x1b[0m: failed\n"),
+                   "<span style=\"color:#cd3131;\">Error</span>: failed\n");
+        assert_eq!(converter.convert("\x1b[1;92mOK\x1b[m"),
+                   "<span style=\"color:#23d18b;font-weight:bold;\">OK</span>");
+        assert_eq!(converter.convert("\x1b[38;5;196mA\x1b[48;2;1;2;3mB"),
+                   "<span style=\"color:#ff0000;\">A</span><span style=\"color:#ff0000;background-color:#010203;\">B</span>");
+        assert_eq!(converter.convert("\x1b[38;5;244mgrey"), "<span style=\"color:#808080;background-color:#010203;\">grey</span>");
+    }
+
+    #[test]
+    fn test_style_carries_over_lines() {
+        let mut converter = AnsiToHtml::new();
+
+        assert_eq!(converter.convert("\x1b[4mstart\n"), "<span style=\"text-decoration:underline;\">start\n</span>");
+        assert_eq!(converter.convert("end\x1b[24m\n"), "<span style=\"text-decoration:underline;\">end</span>\n");
+    }
+
+    #[test]
+    fn test_convert_removes_other_sequences() {
+        let mut converter = AnsiToHtml::new();
+
+        assert_eq!(converter.convert("\x1b]0;title\x07\x1b[2K\x1b(Bdone\x1b[1A"), "done");
+        assert_eq!(converter.convert("\x1b]8;;http://x\x1b\\link"), "link");
+    }
+}
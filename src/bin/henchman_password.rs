@@ -1,10 +1,54 @@
 extern crate henchman;
 extern crate rpassword;
 
+use std::env;
+
+use getopts::Options;
+
 use henchman::password;
 
+fn print_usage(program: &str, opts: Options) {
+    let brief = format!("Usage: {} [options]", program);
+    print!("{}", opts.usage(&brief));
+}
+
 fn main() {
-    let plaintext = rpassword::prompt_password("Password: ").unwrap();
-    let hashed = password::hash_password(&plaintext);
-    println!("{}", hashed);
-}
\ No newline at end of file
+    let args: Vec<String> = env::args().collect();
+    let program = args[0].clone();
+
+    let mut opts = Options::new();
+    opts.optopt("t", "token", "Generate a new API token (rather than hashing a password)", "NAME");
+    opts.optflag("h", "help", "Print this help");
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(f) => {
+            eprintln!("Error: {}\n", f);
+            print_usage(&program, opts);
+            std::process::exit(1);
+        }
+    };
+
+    if matches.opt_present("help") {
+        print_usage(&program, opts);
+        return;
+    }
+
+    match matches.opt_str("token") {
+        Some(name) => {
+            if !password::is_valid_token_name(&name) {
+                eprintln!("Error: token names may only contain letters, numbers, '-' and '_'");
+                std::process::exit(1);
+            }
+
+            let (token, hashed) = password::generate_token(&name);
+            println!("Token: {}", token);
+            println!("Hash: {}", hashed);
+        }
+        None => {
+            let plaintext = rpassword::prompt_password("Password: ").unwrap();
+            let hashed = password::hash_password(&plaintext);
+            println!("{}", hashed);
+        }
+    }
+}
@@ -0,0 +1,348 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use crate::ServerConfig;
+use crate::server::ServerError;
+use crate::server_file::{self, ServerToml};
+use crate::task::TaskDef;
+use crate::task_file::{find_task_files, TaskFileToml};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    /// Probably a mistake, but the server will still start
+    Warning,
+}
+
+#[derive(Debug)]
+pub struct Problem {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.severity {
+            Severity::Error => write!(f, "error: {}", self.message),
+            Severity::Warning => write!(f, "warning: {}", self.message),
+        }
+    }
+}
+
+pub struct CheckReport {
+    /// Number of task files loaded successfully
+    pub tasks: usize,
+    pub problems: Vec<Problem>,
+}
+
+impl CheckReport {
+    pub fn has_errors(&self) -> bool {
+        self.problems.iter().any(|problem| problem.severity == Severity::Error)
+    }
+
+    fn error(&mut self, message: String) {
+        self.problems.push(Problem { severity: Severity::Error, message });
+    }
+
+    fn warning(&mut self, message: String) {
+        self.problems.push(Problem { severity: Severity::Warning, message });
+    }
+}
+
+/// Load the server configuration and every task file, reporting all problems found (rather than
+/// stopping at the first, like the server does when starting)
+pub fn check_config(config: &ServerConfig) -> CheckReport {
+    let mut report = CheckReport { tasks: 0, problems: vec![] };
+
+    let server_toml = match server_file::load_toml::<ServerToml>(&config.config) {
+        Ok(server_toml) => server_toml,
+        Err(err) => {
+            report.error(err.to_string());
+            return report; // don't know where to find tasks
+        }
+    };
+
+    if let Some(listen) = server_toml.server.as_ref().and_then(|server| server.listen.as_ref()) {
+        if listen.parse::<SocketAddr>().is_err() {
+            report.error(format!("Invalid listen address: {}", listen));
+        }
+    }
+
+    if let Err(err) = crate::load_exec_settings(config, &server_toml) {
+        report.error(err.to_string());
+    }
+
+    if let Err(err) = crate::load_notify_settings(config, &server_toml) {
+        report.error(err.to_string());
+    }
+
+    if let Some(smtp) = crate::load_smtp_settings(&server_toml).filter(|smtp| smtp.has_insecure_auth()) {
+        if smtp.allow_insecure_auth {
+            report.warning("[smtp] password is sent without STARTTLS".to_owned());
+        } else {
+            report.error("[smtp] has a username but no STARTTLS, the password would be sent in the clear (set allow-insecure-auth if that's intended)".to_owned());
+        }
+    }
+
+    let known_roles = check_auth(&server_toml, &mut report);
+
+    let secrets = match crate::load_secrets(config, &server_toml) {
+        Ok(secrets) => Some(secrets),
+        Err(err) => {
+            report.error(err.to_string());
+            None
+        }
+    };
+
+    let task_dir: PathBuf = server_toml.server.as_ref()
+        .and_then(|server| server.dir.as_ref())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(crate::DEFAULT_TASK_DIR));
+
+    let task_dir = crate::resolve_config_path(config, task_dir);
+
+    let task_files = match find_task_files(&task_dir) {
+        Ok(task_files) => task_files,
+        Err(err) => {
+            report.error(err.to_string());
+            return report;
+        }
+    };
+
+    let mut task_paths = HashMap::<String, PathBuf>::new();
+
+    for path in task_files {
+        let task_def = match TaskFileToml::load(&path) {
+            Ok(task_def) => task_def,
+            Err(err) => {
+                report.error(err.to_string());
+                continue;
+            }
+        };
+
+        report.tasks += 1;
+
+        // tasks in different sub-directories can end up with the same name (only one would be loaded)
+        if let Some(other) = task_paths.insert(task_def.name.clone(), path.clone()) {
+            report.error(format!("Task {}: defined by both {} and {}",
+                                 task_def.name, other.to_string_lossy(), path.to_string_lossy()));
+        }
+
+        check_task(&task_def, known_roles.as_ref(), &mut report);
+
+        if !task_def.email.is_empty() && server_toml.smtp.is_none() {
+            report.error(format!("Task {}: has email recipients, but there's no [smtp] server configured", task_def.name));
+        }
+
+        if let Some(secrets) = &secrets {
+            let mut unknown: Vec<&String> = task_def.exec.secret_names().filter(|name| !secrets.contains(name)).collect();
+            unknown.sort();
+
+            for name in unknown {
+                report.error(format!("Task {}: secret {} is not defined", task_def.name, name));
+            }
+        }
+    }
+
+    report
+}
+
+/// Check users and tokens one by one (so each problem is reported), returning all roles granted
+/// to anyone (or `None` if authentication is disabled, when roles aren't used)
+fn check_auth(server_toml: &ServerToml, report: &mut CheckReport) -> Option<HashSet<String>> {
+    let auth = match &server_toml.auth {
+        Some(auth) => auth,
+        None => {
+            return Some(HashSet::new());
+        }
+    };
+
+    for user in &auth.users {
+        if let Err(err) = crate::load_users(vec![user.clone()]) {
+            report.error(err.to_string());
+        }
+    }
+
+    for token in &auth.tokens {
+        if let Err(err) = crate::load_tokens(vec![token.clone()]) {
+            report.error(err.to_string());
+        }
+    }
+
+    if !auth.enabled.unwrap_or(true) {
+        return None;
+    }
+
+    let mut roles: HashSet<String> = HashSet::new();
+
+    roles.extend(auth.users.iter().flat_map(|user| user.roles.iter().cloned()));
+    roles.extend(auth.tokens.iter().flat_map(|token| token.roles.iter().cloned()));
+
+    if auth.guest.unwrap_or(false) {
+        roles.extend(auth.guest_roles.iter().cloned());
+    }
+
+    Some(roles)
+}
+
+fn check_task(task_def: &TaskDef, known_roles: Option<&HashSet<String>>, report: &mut CheckReport) {
+    let mut env_names = HashSet::<&str>::new();
+
+    for param in &task_def.parameters {
+        if let Some(default) = &param.default {
+            if !param.validate(&crate::server::param_to_string(default)) {
+                report.error(format!("Task {}: default value of parameter {} is not valid", task_def.name, param.name));
+            }
+        }
+
+        if let Some(env) = &param.env {
+            if !env_names.insert(env) {
+                report.error(format!("Task {}: environment variable {} is used by more than one parameter", task_def.name, env));
+            }
+
+            if task_def.exec.env.contains_key(env) {
+                report.error(format!("Task {}: environment variable {} is set by both [exec.env] and parameter {}", task_def.name, env, param.name));
+            }
+        }
+    }
+
+    for schedule in &task_def.schedules {
+        if let Err(ServerError::BadRequest(reason)) = crate::server::validate_params(schedule.params.clone(), &task_def.parameters) {
+            report.error(format!("Task {}: schedule {} has invalid parameters ({})", task_def.name, schedule.cron, reason));
+        }
+    }
+
+    if !task_def.exec.dir.is_dir() {
+        report.error(format!("Task {}: directory not found: {}", task_def.name, task_def.exec.dir.to_string_lossy()));
+    } else if find_command(&task_def.exec.command, &task_def.exec.dir).is_none() {
+        report.error(format!("Task {}: command not found or not executable: {}", task_def.name, task_def.exec.command));
+    }
+
+    if let (Some(known_roles), Some(roles_allowed)) = (known_roles, &task_def.auth.roles_allowed) {
+        let mut unknown: Vec<&String> = roles_allowed.difference(known_roles).collect();
+        unknown.sort();
+
+        for role in unknown {
+            report.warning(format!("Task {}: role {} is not granted to any user, token or guest", task_def.name, role));
+        }
+    }
+}
+
+/// Commands containing a path are relative to the task directory, otherwise they're found on the 'PATH'
+fn find_command(command: &str, dir: &Path) -> Option<PathBuf> {
+    if command.contains(std::path::MAIN_SEPARATOR) || command.contains('/') {
+        Some(dir.join(command)).filter(|path| is_executable(path))
+    } else {
+        std::env::var_os("PATH").and_then(|paths| {
+            std::env::split_paths(&paths)
+                .map(|path| path.join(command))
+                .find(|path| is_executable(path))
+        })
+    }
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    std::fs::metadata(path)
+        .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CARGO_MANIFEST_DIR: &str = env!("CARGO_MANIFEST_DIR");
+
+    fn check(config_file: &str) -> CheckReport {
+        check_config(&ServerConfig {
+            config: format!("{}/tests/resources/{}", CARGO_MANIFEST_DIR, config_file).into()
+        })
+    }
+
+    fn messages(report: &CheckReport) -> Vec<String> {
+        let mut messages: Vec<String> = report.problems.iter().map(|problem| problem.to_string()).collect();
+        messages.sort();
+        messages
+    }
+
+    #[test]
+    fn test_check_valid_config() {
+        let report = check("server_guest.toml");
+
+        assert!(!report.has_errors(), "Unexpected problems: {:?}", messages(&report));
+
+        let task_dir = PathBuf::from(format!("{}/tests/resources/tasks", CARGO_MANIFEST_DIR));
+        assert_eq!(report.tasks, find_task_files(&task_dir).unwrap().len());
+    }
+
+    #[test]
+    fn test_check_insecure_smtp() {
+        let dir: PathBuf = format!("{}/target/test-check-smtp", CARGO_MANIFEST_DIR).into();
+
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("tasks")).unwrap();
+
+        let server_toml = "[server]\ndir = \"tasks\"\n\n[smtp]\nhost = \"smtp.example.com\"\nstarttls = false\nusername = \"henchman\"\npassword = \"secret\"\nfrom = \"henchman@example.com\"\n";
+
+        std::fs::write(dir.join("server.toml"), server_toml).unwrap();
+
+        let report = check_config(&ServerConfig { config: dir.join("server.toml") });
+
+        assert!(messages(&report).contains(&"error: [smtp] has a username but no STARTTLS, the password would be sent in the clear (set allow-insecure-auth if that's intended)".to_owned()));
+
+        std::fs::write(dir.join("server.toml"), format!("{}allow-insecure-auth = true\n", server_toml)).unwrap();
+
+        let report = check_config(&ServerConfig { config: dir.join("server.toml") });
+
+        assert!(!report.has_errors(), "Unexpected problems: {:?}", messages(&report));
+        assert!(messages(&report).contains(&"warning: [smtp] password is sent without STARTTLS".to_owned()));
+    }
+
+    #[test]
+    fn test_check_reports_all_problems() {
+        let report = check("check/server.toml");
+
+        assert!(report.has_errors());
+        assert_eq!(report.tasks, 8);
+
+        let messages = messages(&report);
+
+        assert_eq!(messages.len(), 15, "Unexpected problems: {:?}", messages);
+
+        assert!(messages.iter().any(|x| x.starts_with("error: Error parsing file:") && x.contains("broken.task.toml")));
+        assert!(messages.contains(&"error: Invalid listen address: localhost".to_owned()));
+        assert!(messages.iter().any(|x| x.starts_with("error: max-concurrent-runs must be at least 1 (") && x.contains("server.toml")));
+        assert!(messages.contains(&"error: Invalid password hash for username: bad".to_owned()));
+        assert!(messages.contains(&"error: Invalid hash for token: bad".to_owned()));
+        assert!(messages.contains(&"error: Task bad_default: default value of parameter count is not valid".to_owned()));
+        assert!(messages.contains(&"error: Task duplicate_env: environment variable VALUE is used by more than one parameter".to_owned()));
+        assert!(messages.contains(&"error: Task bad_schedule: schedule @daily has invalid parameters (Invalid parameter value: count)".to_owned()));
+        assert!(messages.iter().any(|x| x.starts_with("error: concurrency must be at least 1 (") && x.contains("no_concurrency.task.toml")));
+        assert!(messages.contains(&"error: Task no_smtp: has email recipients, but there's no [smtp] server configured".to_owned()));
+        assert!(messages.contains(&"error: Task unknown_secret: secret aws_key is not defined".to_owned()));
+        assert!(messages.contains(&"error: Task unknown_secret: environment variable REGION is set by both [exec.env] and parameter region".to_owned()));
+        assert!(messages.iter().any(|x| x.starts_with("error: Task missing_dir: directory not found:")));
+        assert!(messages.contains(&"error: Task not_executable: command not found or not executable: ./not_executable.task.toml".to_owned()));
+        assert!(messages.contains(&"warning: Task unknown_role: role NOBODY is not granted to any user, token or guest".to_owned()));
+    }
+
+    #[test]
+    fn test_find_command() {
+        let dir: PathBuf = format!("{}/tests/resources/tasks/bin", CARGO_MANIFEST_DIR).into();
+
+        assert_eq!(find_command("./example1.sh", &dir), Some(dir.join("./example1.sh")));
+        assert!(find_command("./missing.sh", &dir).is_none());
+        assert!(find_command("sh", &dir).is_some());
+        assert!(find_command("no-such-command-anywhere", &dir).is_none());
+    }
+}
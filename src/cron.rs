@@ -0,0 +1,225 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{Datelike, DateTime, Duration, LocalResult, NaiveDate, NaiveDateTime, TimeZone, Timelike};
+
+/// Don't look further ahead than this for a match (eg, '0 0 30 2 *' never matches)
+const MAX_YEARS_AHEAD: i32 = 5;
+
+const MONTH_NAMES: [&str; 12] = ["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"];
+const WEEKDAY_NAMES: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// Standard five field cron expression (minute, hour, day of month, month, day of week), or one
+/// of the '@daily' style shortcuts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronSchedule {
+    expression: String,
+    minutes: Field,
+    hours: Field,
+    days: Field,
+    months: Field,
+    weekdays: Field,
+}
+
+/// Set of allowed values (as bits), and whether it was restricted at all ('*' isn't)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Field {
+    values: u64,
+    any: bool,
+}
+
+impl Field {
+    fn contains(&self, value: u32) -> bool {
+        self.values & (1 << value) != 0
+    }
+}
+
+impl FromStr for CronSchedule {
+    type Err = String;
+
+    fn from_str(expression: &str) -> Result<CronSchedule, String> {
+        let expanded = match expression.trim() {
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            "@monthly" => "0 0 1 * *",
+            "@weekly" => "0 0 * * 0",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@hourly" => "0 * * * *",
+            other => other,
+        };
+
+        let fields: Vec<&str> = expanded.split_whitespace().collect();
+
+        if fields.len() != 5 {
+            return Err(format!("expected 5 fields, found {}", fields.len()));
+        }
+
+        let mut weekdays = parse_field(fields[4], 0, 7, &WEEKDAY_NAMES, 0)?;
+        if weekdays.contains(7) {
+            weekdays.values = (weekdays.values & !(1 << 7)) | 1; // Sunday is either 0 or 7
+        }
+
+        Ok(CronSchedule {
+            expression: expression.trim().to_owned(),
+            minutes: parse_field(fields[0], 0, 59, &[], 0)?,
+            hours: parse_field(fields[1], 0, 23, &[], 0)?,
+            days: parse_field(fields[2], 1, 31, &[], 0)?,
+            months: parse_field(fields[3], 1, 12, &MONTH_NAMES, 1)?,
+            weekdays,
+        })
+    }
+}
+
+impl fmt::Display for CronSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.expression)
+    }
+}
+
+/// Comma separated list of values, ranges ('1-5') and steps ('*/15' or '0-30/10')
+fn parse_field(field: &str, min: u32, max: u32, names: &[&str], first_name: u32) -> Result<Field, String> {
+    let parse_value = |value: &str| -> Result<u32, String> {
+        let number = match names.iter().position(|name| name.eq_ignore_ascii_case(value)) {
+            Some(index) => index as u32 + first_name,
+            None => value.parse::<u32>().map_err(|_| format!("invalid value: {}", value))?
+        };
+        if number < min || number > max {
+            return Err(format!("value out of range ({}-{}): {}", min, max, value));
+        }
+        Ok(number)
+    };
+
+    let mut values = 0u64;
+
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => {
+                let step = step.parse::<u32>().ok()
+                    .filter(|step| *step > 0)
+                    .ok_or_else(|| format!("invalid step: {}", step))?;
+                (range, Some(step))
+            }
+            None => (item, None)
+        };
+
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (parse_value(start)?, parse_value(end)?)
+        } else {
+            let start = parse_value(range)?;
+            (start, if step.is_some() { max } else { start }) // '5/10' means from 5 onwards
+        };
+
+        if start > end {
+            return Err(format!("invalid range: {}", range));
+        }
+
+        for value in (start..=end).step_by(step.unwrap_or(1) as usize) {
+            values |= 1 << value;
+        }
+    }
+
+    Ok(Field { values, any: field.starts_with('*') })
+}
+
+impl CronSchedule {
+    /// If both days of the month and of the week are restricted, either can match (like cron)
+    fn matches_day(&self, date: NaiveDate) -> bool {
+        let day = self.days.contains(date.day());
+        let weekday = self.weekdays.contains(date.weekday().num_days_from_sunday());
+
+        match (self.days.any, self.weekdays.any) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+
+    /// First matching time after the given one (to the minute, never the same minute)
+    pub fn next_after(&self, after: NaiveDateTime) -> Option<NaiveDateTime> {
+        let mut time = after.date().and_hms_opt(after.hour(), after.minute(), 0)? + Duration::minutes(1);
+
+        let last_year = after.year() + MAX_YEARS_AHEAD;
+
+        while time.year() <= last_year {
+            let date = time.date();
+
+            if !self.months.contains(date.month()) {
+                let (year, month) = if date.month() == 12 { (date.year() + 1, 1) } else { (date.year(), date.month() + 1) };
+                time = NaiveDate::from_ymd_opt(year, month, 1)?.and_hms_opt(0, 0, 0)?;
+            } else if !self.matches_day(date) {
+                time = date.succ_opt()?.and_hms_opt(0, 0, 0)?;
+            } else if !self.hours.contains(time.hour()) {
+                time = date.and_hms_opt(time.hour(), 0, 0)? + Duration::hours(1);
+            } else if !self.minutes.contains(time.minute()) {
+                time += Duration::minutes(1);
+            } else {
+                return Some(time);
+            }
+        }
+
+        None
+    }
+
+    /// Next time in the time zone (times skipped by daylight saving changes are skipped here too,
+    /// and repeated times only match the first time around)
+    pub fn next_after_in<Tz: TimeZone>(&self, after: &DateTime<Tz>) -> Option<DateTime<Tz>> {
+        let timezone = after.timezone();
+        let mut naive = after.naive_local();
+
+        loop {
+            naive = self.next_after(naive)?;
+            match timezone.from_local_datetime(&naive) {
+                LocalResult::Single(time) => return Some(time),
+                LocalResult::Ambiguous(earliest, _) if earliest > *after => return Some(earliest),
+                LocalResult::Ambiguous(..) | LocalResult::None => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(value: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    fn next(expression: &str, after: &str) -> Option<NaiveDateTime> {
+        expression.parse::<CronSchedule>().unwrap().next_after(time(after))
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!("* * * *".parse::<CronSchedule>().is_err());
+        assert!("60 * * * *".parse::<CronSchedule>().is_err());
+        assert!("* * 0 * *".parse::<CronSchedule>().is_err());
+        assert!("*/0 * * * *".parse::<CronSchedule>().is_err());
+        assert!("5-1 * * * *".parse::<CronSchedule>().is_err());
+        assert!("* * * foo *".parse::<CronSchedule>().is_err());
+        assert!("@sometimes".parse::<CronSchedule>().is_err());
+    }
+
+    #[test]
+    fn test_next_after() {
+        assert_eq!(next("* * * * *", "2024-01-01 10:00"), Some(time("2024-01-01 10:01")));
+        assert_eq!(next("*/15 * * * *", "2024-01-01 10:01"), Some(time("2024-01-01 10:15")));
+        assert_eq!(next("30 3 * * *", "2024-01-01 10:00"), Some(time("2024-01-02 03:30")));
+        assert_eq!(next("0 9-17/4 * * *", "2024-01-01 13:00"), Some(time("2024-01-01 17:00")));
+        assert_eq!(next("0 0 1 jan,JUL *", "2024-01-01 00:00"), Some(time("2024-07-01 00:00")));
+        assert_eq!(next("@monthly", "2024-12-15 08:00"), Some(time("2025-01-01 00:00")));
+        assert_eq!(next("0 0 29 2 *", "2024-03-01 00:00"), Some(time("2028-02-29 00:00")));
+        assert_eq!(next("0 0 30 2 *", "2024-01-01 00:00"), None);
+    }
+
+    #[test]
+    fn test_next_after_weekdays() {
+        // 2024-01-01 was a Monday
+        assert_eq!(next("0 8 * * mon-fri", "2024-01-05 09:00"), Some(time("2024-01-08 08:00")));
+        assert_eq!(next("0 0 * * 7", "2024-01-01 00:00"), Some(time("2024-01-07 00:00")));
+        assert_eq!(next("@weekly", "2024-01-01 00:00"), Some(time("2024-01-07 00:00")));
+        // either day of the month or of the week
+        assert_eq!(next("0 0 15 * fri", "2024-01-01 00:00"), Some(time("2024-01-05 00:00")));
+        assert_eq!(next("0 0 15 * fri", "2024-01-13 00:00"), Some(time("2024-01-15 00:00")));
+    }
+}
@@ -0,0 +1,507 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use base64::Engine;
+
+use chrono::{DateTime, Utc};
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+use tokio_rustls::rustls;
+
+use crate::history::RunRecord;
+use crate::task::EmailDef;
+
+pub const DEFAULT_PORT: u16 = 587;
+pub const DEFAULT_PORT_PLAIN: u16 = 25;
+
+/// Give up on a mail server that takes longer than this to take a message
+const SEND_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub struct SmtpSettings {
+    pub host: String,
+    pub port: u16,
+    pub starttls: bool,
+    /// Username and password
+    pub credentials: Option<(String, String)>,
+    /// Whether credentials may be sent without STARTTLS
+    pub allow_insecure_auth: bool,
+    pub from: String,
+}
+
+impl SmtpSettings {
+    /// Would log in over a connection anyone in between can read
+    pub fn has_insecure_auth(&self) -> bool {
+        self.credentials.is_some() && !self.starttls
+    }
+}
+
+#[derive(Debug)]
+pub enum SmtpError {
+    Io(std::io::Error),
+    Tls(String),
+    /// Server said no (or something unexpected)
+    Reply(String),
+    StartTlsNotSupported,
+    /// Credentials would be sent without TLS (and that isn't allowed)
+    InsecureAuth,
+}
+
+impl SmtpError {
+    /// Worth trying again later
+    fn is_transient(&self) -> bool {
+        match self {
+            SmtpError::Io(_) => true,
+            SmtpError::Reply(reply) => reply.starts_with('4'),
+            SmtpError::Tls(_) | SmtpError::StartTlsNotSupported | SmtpError::InsecureAuth => false,
+        }
+    }
+}
+
+impl fmt::Display for SmtpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SmtpError::Io(err) => write!(f, "{}", err),
+            SmtpError::Tls(err) => write!(f, "TLS error: {}", err),
+            SmtpError::Reply(reply) => write!(f, "unexpected reply: {}", reply),
+            SmtpError::StartTlsNotSupported => write!(f, "server doesn't support STARTTLS"),
+            SmtpError::InsecureAuth => write!(f, "not sending credentials without STARTTLS (unless allow-insecure-auth is set)"),
+        }
+    }
+}
+
+impl From<std::io::Error> for SmtpError {
+    fn from(err: std::io::Error) -> SmtpError {
+        SmtpError::Io(err)
+    }
+}
+
+/// Sends '[[email]]' notifications of finished runs
+pub struct Mailer {
+    smtp: Arc<SmtpSettings>,
+    /// For links to runs (eg, 'https://henchman.example.com')
+    server_url: Option<String>,
+}
+
+impl Mailer {
+    pub fn new(smtp: SmtpSettings, server_url: Option<String>) -> Mailer {
+        if smtp.has_insecure_auth() && !smtp.allow_insecure_auth {
+            warn!("Not sending email, [smtp] credentials would be sent without STARTTLS (unless allow-insecure-auth is set)");
+        }
+
+        Mailer { smtp: Arc::new(smtp), server_url }
+    }
+
+    /// Email the recipients of the task (in the background)
+    pub fn run_finished(&self, record: &RunRecord, output_tail: &str, email: &[EmailDef]) {
+        for email in email.iter().filter(|email| email.on.matches(record.succeeded())) {
+            let message = message(&self.smtp.from, &email.to, record, output_tail, self.server_url.as_deref(), Utc::now());
+            tokio::spawn(send_with_retries(self.smtp.clone(), email.to.clone(), message));
+        }
+    }
+}
+
+async fn send_with_retries(smtp: Arc<SmtpSettings>, to: Vec<String>, message: String) -> bool {
+    let mut backoff = crate::notify::INITIAL_BACKOFF;
+
+    for attempt in 0..=crate::notify::DEFAULT_RETRIES {
+        if attempt > 0 {
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+
+        let result = tokio::time::timeout(SEND_TIMEOUT, send_mail(&smtp, &to, &message)).await
+            .unwrap_or_else(|_| Err(SmtpError::Io(std::io::ErrorKind::TimedOut.into())));
+
+        match result {
+            Ok(()) => {
+                info!("Sent email to {}", to.join(", "));
+                return true;
+            }
+            Err(err) if err.is_transient() => {
+                warn!("Error sending email via {}: {}", smtp.host, err);
+            }
+            Err(err) => {
+                error!("Error sending email via {}: {}", smtp.host, err);
+                return false; // no point trying again
+            }
+        }
+    }
+
+    error!("Gave up sending email to {}", to.join(", "));
+    false
+}
+
+/// Just the address part of 'Name <address>'
+fn address(mailbox: &str) -> &str {
+    match (mailbox.rfind('<'), mailbox.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &mailbox[start + 1..end],
+        _ => mailbox.trim()
+    }
+}
+
+/// Header value, encoded if it isn't plain ASCII (RFC 2047)
+fn encode_header(value: &str) -> String {
+    if value.chars().all(|c| c.is_ascii() && !c.is_ascii_control()) {
+        value.to_owned()
+    } else {
+        format!("=?utf-8?B?{}?=", base64::engine::general_purpose::STANDARD.encode(value))
+    }
+}
+
+/// Message (headers and body) for a finished run
+fn message(from: &str,
+           to: &[String],
+           record: &RunRecord,
+           output_tail: &str,
+           server_url: Option<&str>,
+           date: DateTime<Utc>) -> String {
+    let outcome = if record.succeeded() {
+        "succeeded".to_owned()
+    } else {
+        crate::run::trailer(record).trim_matches(|c| c == '[' || c == ']').to_lowercase()
+    };
+
+    let subject = format!("Task {} {}", record.task, if record.succeeded() { "succeeded" } else { "failed" });
+
+    let mut params: Vec<String> = record.params.iter().map(|(name, value)| format!("{}={}", name, value)).collect();
+    params.sort();
+
+    let duration = record.finished_at
+        .and_then(|finished_at| (finished_at - record.started_at).to_std().ok())
+        .map(crate::utils::format_duration)
+        .unwrap_or_default();
+
+    let mut body = format!("Task: {}\nRun: {}\nStarted by: {}\nStarted at: {}\nParameters: {}\nDuration: {}\nOutcome: {}\n",
+                           record.task, record.id, record.username, record.started_at.to_rfc3339(),
+                           if params.is_empty() { "none".to_owned() } else { params.join(", ") },
+                           duration, outcome);
+
+    if let Some(server_url) = server_url {
+        body.push_str(&format!("\n{}/web/runs/{}\n", server_url.trim_end_matches('/'), record.id));
+    }
+
+    if !output_tail.is_empty() {
+        body.push_str(&format!("\nLast lines of output:\n\n{}", output_tail));
+    }
+
+    let domain = address(from).rsplit('@').next().unwrap_or("localhost");
+
+    let headers = [
+        ("From", from.to_owned()),
+        ("To", to.join(", ")),
+        ("Subject", encode_header(&subject)),
+        ("Date", date.to_rfc2822()),
+        ("Message-ID", format!("<{}@{}>", uuid::Uuid::new_v4(), domain)),
+        ("MIME-Version", "1.0".to_owned()),
+        ("Content-Type", "text/plain; charset=utf-8".to_owned()),
+        ("Content-Transfer-Encoding", "8bit".to_owned()),
+    ];
+
+    let mut message = String::new();
+
+    for (name, value) in headers {
+        message.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    message.push_str("\r\n");
+
+    // lines end with CRLF, and a line starting with a dot gets another one (otherwise it could end the message)
+    for line in body.lines() {
+        if line.starts_with('.') {
+            message.push('.');
+        }
+        message.push_str(line);
+        message.push_str("\r\n");
+    }
+
+    message
+}
+
+fn tls_connector() -> tokio_rustls::TlsConnector {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+        rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(anchor.subject, anchor.spki, anchor.name_constraints)
+    }));
+
+    let config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    tokio_rustls::TlsConnector::from(Arc::new(config))
+}
+
+/// Send the message (which already has all its headers) to the recipients
+async fn send_mail(smtp: &SmtpSettings, to: &[String], message: &str) -> Result<(), SmtpError> {
+    if smtp.has_insecure_auth() && !smtp.allow_insecure_auth {
+        return Err(SmtpError::InsecureAuth);
+    }
+
+    let stream = TcpStream::connect((smtp.host.as_str(), smtp.port)).await?;
+
+    let mut conn = BufReader::new(stream);
+
+    let helo = format!("EHLO {}", address(&smtp.from).rsplit('@').next().unwrap_or("localhost"));
+
+    reply(&mut conn, 220).await?;
+    let extensions = command(&mut conn, &helo, 250).await?;
+
+    if !smtp.starttls {
+        return transaction(&mut conn, smtp, to, message).await;
+    }
+
+    if !extensions.iter().any(|extension| extension.eq_ignore_ascii_case("STARTTLS")) {
+        return Err(SmtpError::StartTlsNotSupported);
+    }
+
+    command(&mut conn, "STARTTLS", 220).await?;
+
+    let server_name = rustls::ServerName::try_from(smtp.host.as_str())
+        .map_err(|err| SmtpError::Tls(err.to_string()))?;
+
+    let stream = tls_connector().connect(server_name, conn.into_inner()).await
+        .map_err(|err| SmtpError::Tls(err.to_string()))?;
+
+    let mut conn = BufReader::new(stream);
+
+    command(&mut conn, &helo, 250).await?; // (extensions can change after switching to TLS)
+
+    transaction(&mut conn, smtp, to, message).await
+}
+
+async fn transaction<S>(conn: &mut BufReader<S>, smtp: &SmtpSettings, to: &[String], message: &str) -> Result<(), SmtpError>
+    where S: AsyncRead + AsyncWrite + Unpin {
+    if let Some((username, password)) = &smtp.credentials {
+        let token = base64::engine::general_purpose::STANDARD.encode(format!("\0{}\0{}", username, password));
+        command(conn, &format!("AUTH PLAIN {}", token), 235).await?;
+    }
+
+    command(conn, &format!("MAIL FROM:<{}>", address(&smtp.from)), 250).await?;
+
+    for recipient in to {
+        command(conn, &format!("RCPT TO:<{}>", address(recipient)), 250).await?;
+    }
+
+    command(conn, "DATA", 354).await?;
+
+    conn.get_mut().write_all(message.as_bytes()).await?;
+    command(conn, ".", 250).await?;
+
+    let _ = command(conn, "QUIT", 221).await; // (already sent)
+
+    Ok(())
+}
+
+/// Send a command, returning the lines of the reply (without their codes)
+async fn command<S>(conn: &mut BufReader<S>, line: &str, expected: u16) -> Result<Vec<String>, SmtpError>
+    where S: AsyncRead + AsyncWrite + Unpin {
+    conn.get_mut().write_all(format!("{}\r\n", line).as_bytes()).await?;
+    conn.get_mut().flush().await?;
+    reply(conn, expected).await
+}
+
+/// Read a reply (lines like '250-...' until one like '250 ...')
+async fn reply<S>(conn: &mut BufReader<S>, expected: u16) -> Result<Vec<String>, SmtpError>
+    where S: AsyncRead + AsyncWrite + Unpin {
+    let mut lines = vec![];
+
+    loop {
+        let mut line = String::new();
+        if conn.read_line(&mut line).await? == 0 {
+            return Err(SmtpError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+        }
+        let line = line.trim_end();
+
+        let code = line.get(..3).and_then(|code| code.parse::<u16>().ok())
+            .ok_or_else(|| SmtpError::Reply(line.to_owned()))?;
+
+        if code != expected {
+            return Err(SmtpError::Reply(line.to_owned()));
+        }
+
+        lines.push(line.get(4..).unwrap_or_default().to_owned());
+
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(lines);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use tokio::net::TcpListener;
+
+    use crate::task::NotifyOn;
+
+    fn record() -> RunRecord {
+        let started_at = DateTime::parse_from_rfc3339("2024-01-01T03:00:00Z").unwrap().with_timezone(&Utc);
+        RunRecord {
+            id: "1234".to_owned(),
+            task: "nightly".to_owned(),
+            username: "scheduler".to_owned(),
+            params: HashMap::from([("target".to_owned(), "prod".to_owned())]),
+            secret_params: vec![],
+            started_at,
+            finished_at: Some(started_at + chrono::Duration::seconds(90)),
+            exit_code: Some(2),
+            signal: None,
+            timed_out_after_secs: None,
+            cancelled_by: None,
+            queue_position: None,
+        }
+    }
+
+    #[test]
+    fn test_message() {
+        let message = message("Henchman <henchman@example.com>", &["oncall@example.com".to_owned()], &record(),
+                              "Working...\n.hidden\nFailed!\n", Some("https://henchman.example.com/"), Utc::now());
+
+        let (headers, body) = message.split_once("\r\n\r\n").unwrap();
+
+        assert!(headers.contains("From: Henchman <henchman@example.com>\r\n"));
+        assert!(headers.contains("To: oncall@example.com\r\n"));
+        assert!(headers.contains("Subject: Task nightly failed\r\n"));
+        assert!(headers.contains("@example.com>\r\n"));
+
+        assert!(body.contains("Started by: scheduler\r\n"));
+        assert!(body.contains("Parameters: target=prod\r\n"));
+        assert!(body.contains("Duration: 1m30s\r\n"));
+        assert!(body.contains("Outcome: exit code: 2\r\n"));
+        assert!(body.contains("\r\nhttps://henchman.example.com/web/runs/1234\r\n"));
+        assert!(body.ends_with("Working...\r\n..hidden\r\nFailed!\r\n"));
+
+        assert_eq!(encode_header("Task café failed"), "=?utf-8?B?VGFzayBjYWbDqSBmYWlsZWQ=?=");
+    }
+
+    /// Local stand-in for a mail server, returning everything the client sent
+    async fn fake_smtp(extensions: &'static [&'static str]) -> (u16, tokio::task::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let received = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut conn = BufReader::new(stream);
+            let mut received = vec![];
+            let mut in_data = false;
+
+            conn.get_mut().write_all(b"220 localhost ready\r\n").await.unwrap();
+
+            loop {
+                let mut line = String::new();
+                if conn.read_line(&mut line).await.unwrap() == 0 {
+                    break;
+                }
+                let line = line.trim_end_matches("\r\n").to_owned();
+                received.push(line.clone());
+
+                let reply = if in_data {
+                    if line != "." {
+                        continue;
+                    }
+                    in_data = false;
+                    "250 queued\r\n".to_owned()
+                } else if line.starts_with("EHLO") {
+                    let mut reply = "250-localhost\r\n".to_owned();
+                    for extension in extensions {
+                        reply.push_str(&format!("250-{}\r\n", extension));
+                    }
+                    reply + "250 8BITMIME\r\n"
+                } else if line.starts_with("AUTH") {
+                    "235 ok\r\n".to_owned()
+                } else if line == "DATA" {
+                    in_data = true;
+                    "354 go ahead\r\n".to_owned()
+                } else if line == "QUIT" {
+                    conn.get_mut().write_all(b"221 bye\r\n").await.unwrap();
+                    break;
+                } else {
+                    "250 ok\r\n".to_owned()
+                };
+
+                conn.get_mut().write_all(reply.as_bytes()).await.unwrap();
+            }
+
+            received
+        });
+
+        (port, received)
+    }
+
+    fn smtp(port: u16, starttls: bool) -> SmtpSettings {
+        SmtpSettings {
+            host: "127.0.0.1".to_owned(),
+            port,
+            starttls,
+            credentials: Some(("henchman".to_owned(), "secret".to_owned())),
+            allow_insecure_auth: true, // (the fake server doesn't do TLS)
+            from: "Henchman <henchman@example.com>".to_owned(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_mail() {
+        let (port, received) = fake_smtp(&["AUTH PLAIN"]).await;
+
+        let to = vec!["oncall@example.com".to_owned(), "Boss <boss@example.com>".to_owned()];
+
+        send_mail(&smtp(port, false), &to, "Subject: Hi\r\n\r\n.dot\r\n").await.unwrap();
+
+        assert_eq!(received.await.unwrap(), vec![
+            "EHLO example.com",
+            "AUTH PLAIN AGhlbmNobWFuAHNlY3JldA==",
+            "MAIL FROM:<henchman@example.com>",
+            "RCPT TO:<oncall@example.com>",
+            "RCPT TO:<boss@example.com>",
+            "DATA",
+            "Subject: Hi",
+            "",
+            ".dot",
+            ".",
+            "QUIT",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_send_mail_requires_starttls() {
+        let (port, _) = fake_smtp(&[]).await;
+
+        let result = send_mail(&smtp(port, true), &["oncall@example.com".to_owned()], "Subject: Hi\r\n\r\n").await;
+
+        assert!(matches!(result, Err(SmtpError::StartTlsNotSupported)), "{:?}", result);
+    }
+
+    #[tokio::test]
+    async fn test_send_mail_refuses_insecure_auth() {
+        let smtp = SmtpSettings { allow_insecure_auth: false, ..smtp(1, false) };
+
+        // (not even connecting)
+        let result = send_mail(&smtp, &["oncall@example.com".to_owned()], "Subject: Hi\r\n\r\n").await;
+
+        assert!(matches!(result, Err(SmtpError::InsecureAuth)), "{:?}", result);
+    }
+
+    #[tokio::test]
+    async fn test_run_finished_matches_outcome() {
+        let (port, received) = fake_smtp(&[]).await;
+
+        let mailer = Mailer::new(smtp(port, false), None);
+
+        // (only the failure one is sent, the fake server takes a single connection)
+        mailer.run_finished(&record(), "", &[
+            EmailDef { to: vec!["happy@example.com".to_owned()], on: NotifyOn::Success },
+            EmailDef { to: vec!["oncall@example.com".to_owned()], on: NotifyOn::Failure },
+        ]);
+
+        let received = received.await.unwrap();
+
+        assert!(received.contains(&"RCPT TO:<oncall@example.com>".to_owned()));
+        assert!(received.contains(&"Subject: Task nightly failed".to_owned()));
+    }
+}
@@ -0,0 +1,228 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::io::{Error as IoError, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+
+use serde::{Deserialize, Serialize};
+
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+
+const RUN_FILE: &str = "run.json";
+
+/// Which stream of the process a line of output came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+/// Output files stored alongside each run record
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFile {
+    /// Both streams, interleaved as they were sent to the client
+    Combined,
+    Stdout,
+    Stderr,
+}
+
+impl OutputFile {
+    fn file_name(&self) -> &'static str {
+        match self {
+            OutputFile::Combined => "output.log",
+            OutputFile::Stdout => "stdout.log",
+            OutputFile::Stderr => "stderr.log",
+        }
+    }
+}
+
+/// Persisted record of a single task execution (stored as 'run.json' in the run directory)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunRecord {
+    pub id: String,
+    pub task: String,
+    pub username: String,
+    pub params: HashMap<String, String>,
+    /// Names of the parameters whose values are redacted
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub secret_params: Vec<String>,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub exit_code: Option<i32>,
+    /// Signal that ended the process (if it didn't exit normally)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signal: Option<i32>,
+    /// Set if the process was stopped for running too long
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timed_out_after_secs: Option<u64>,
+    /// Set if somebody stopped the run before it finished
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cancelled_by: Option<String>,
+    /// Place in the queue while waiting to start (not stored)
+    #[serde(skip)]
+    pub queue_position: Option<usize>,
+}
+
+/// How a run turned out (so far)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunOutcome {
+    /// Queued or still executing
+    Running,
+    Success,
+    Failure,
+}
+
+impl RunRecord {
+    /// Exited with code zero, without being stopped
+    pub fn succeeded(&self) -> bool {
+        self.exit_code == Some(0) && self.timed_out_after_secs.is_none() && self.cancelled_by.is_none()
+    }
+
+    pub fn outcome(&self) -> RunOutcome {
+        if self.finished_at.is_none() {
+            RunOutcome::Running
+        } else if self.succeeded() {
+            RunOutcome::Success
+        } else {
+            RunOutcome::Failure
+        }
+    }
+}
+
+/// Execution history stored on disk, one directory per run:
+///
+/// ```text
+/// <dir>/<run id>/run.json
+/// <dir>/<run id>/output.log
+/// <dir>/<run id>/stdout.log
+/// <dir>/<run id>/stderr.log
+/// ```
+#[derive(Debug, Clone)]
+pub struct History {
+    dir: PathBuf,
+}
+
+/// Open output files of a run that is currently executing
+pub struct RunOutput {
+    combined: File,
+    stdout: File,
+    stderr: File,
+}
+
+/// Run IDs are used as directory names, so be strict about what we accept (no path traversal)
+pub fn is_valid_run_id(id: &str) -> bool {
+    uuid::Uuid::parse_str(id).is_ok()
+}
+
+impl History {
+    pub fn new(dir: PathBuf) -> History {
+        History { dir }
+    }
+
+    fn run_dir(&self, id: &str) -> PathBuf {
+        self.dir.join(id)
+    }
+
+    pub fn output_path(&self, id: &str, file: OutputFile) -> PathBuf {
+        self.run_dir(id).join(file.file_name())
+    }
+
+    /// Create the directory for a new run, save its initial record and open its output files
+    pub async fn create(&self, record: &RunRecord) -> Result<RunOutput, IoError> {
+        fs::create_dir_all(self.run_dir(&record.id)).await?;
+
+        self.save(record).await?;
+
+        Ok(RunOutput {
+            combined: File::create(self.output_path(&record.id, OutputFile::Combined)).await?,
+            stdout: File::create(self.output_path(&record.id, OutputFile::Stdout)).await?,
+            stderr: File::create(self.output_path(&record.id, OutputFile::Stderr)).await?,
+        })
+    }
+
+    pub async fn save(&self, record: &RunRecord) -> Result<(), IoError> {
+        let bytes = serde_json::to_vec_pretty(record)?;
+
+        // write then rename so readers never see a partially written record
+        let path = self.run_dir(&record.id).join(RUN_FILE);
+        let tmp_path = path.with_extension("json.tmp");
+
+        fs::write(&tmp_path, bytes).await?;
+        fs::rename(&tmp_path, &path).await
+    }
+
+    pub async fn load(&self, id: &str) -> Result<Option<RunRecord>, IoError> {
+        if !is_valid_run_id(id) {
+            return Ok(None);
+        }
+
+        load_record(&self.run_dir(id).join(RUN_FILE)).await
+    }
+
+    /// All stored runs, most recent first
+    pub async fn list(&self) -> Result<Vec<RunRecord>, IoError> {
+        let mut entries = match fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                return Ok(vec![]); // nothing has been run yet
+            }
+            Err(err) => {
+                return Err(err);
+            }
+        };
+
+        let mut result = Vec::<RunRecord>::new();
+
+        while let Some(entry) = entries.next_entry().await? {
+            if !entry.file_type().await?.is_dir() {
+                continue;
+            }
+
+            match load_record(&entry.path().join(RUN_FILE)).await {
+                Ok(Some(record)) => result.push(record),
+                Ok(None) => {}
+                Err(err) => {
+                    // don't let one corrupted record hide all the others
+                    warn!("Error reading run record: {:?} ({})", entry.path(), err);
+                }
+            }
+        }
+
+        result.sort_by_key(|record| Reverse(record.started_at));
+
+        Ok(result)
+    }
+}
+
+async fn load_record(path: &Path) -> Result<Option<RunRecord>, IoError> {
+    let bytes = match fs::read(path).await {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            return Ok(None);
+        }
+        Err(err) => {
+            return Err(err);
+        }
+    };
+
+    Ok(Some(serde_json::from_slice(&bytes)?))
+}
+
+impl RunOutput {
+    pub async fn write(&mut self, stream: OutputStream, line: &str) -> Result<(), IoError> {
+        self.combined.write_all(line.as_bytes()).await?;
+
+        match stream {
+            OutputStream::Stdout => self.stdout.write_all(line.as_bytes()).await,
+            OutputStream::Stderr => self.stderr.write_all(line.as_bytes()).await,
+        }
+    }
+
+    pub async fn flush(&mut self) -> Result<(), IoError> {
+        self.combined.flush().await?;
+        self.stdout.flush().await?;
+        self.stderr.flush().await
+    }
+}
@@ -2,6 +2,10 @@
 /// API JSON interface (as opposed to configuration file JSON format)
 ///
 
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize)]
@@ -16,6 +20,13 @@ pub struct TaskJson {
     pub description: Option<String>,
     pub method: Vec<MethodJson>,
     pub parameters: Vec<TaskParameterJson>,
+    /// Accepts input (when run via WebSocket)
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub stdin: bool,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
 }
 
 #[derive(Serialize, Deserialize)]
@@ -45,6 +56,199 @@ pub enum TaskParameterTypeJson {
     String,
     Number,
     Boolean,
+    Secret,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RunJson {
+    pub id: String,
+    pub task: String,
+    pub user: String,
+    pub params: HashMap<String, String>,
+    /// (values of these are redacted in 'params')
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub secret_params: Vec<String>,
+    pub started_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signal: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timed_out_after_secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cancelled_by: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue_position: Option<usize>,
+}
+
+/// Sent to '[[notify]]' URLs when a run finishes
+#[derive(Serialize, Deserialize)]
+pub struct RunNotificationJson {
+    pub task: String,
+    pub run_id: String,
+    pub user: String,
+    pub params: HashMap<String, String>,
+    pub status: RunStatusJson,
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    pub duration_secs: Option<f64>,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    /// Last lines of output (both streams)
+    pub output_tail: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RunStatusJson {
+    Success,
+    Failure,
+}
+
+/// Page of runs, most recent first
+#[derive(Serialize, Deserialize)]
+pub struct RunHistoryJson {
+    pub runs: Vec<RunJson>,
+    /// Number of runs matching the filters (on all pages)
+    pub total: usize,
+    /// Starting from one
+    pub page: usize,
+    pub per_page: usize,
+}
+
+/// Entry from a task's '[[schedule]]' section
+#[derive(Serialize, Deserialize)]
+pub struct ScheduleJson {
+    pub task: String,
+    pub cron: String,
+    pub params: HashMap<String, String>,
+    pub missed: MissedPolicyJson,
+    pub overlap: OverlapPolicyJson,
+    /// Not set if the scheduler is disabled (or the schedule never matches again)
+    pub next_run_at: Option<DateTime<Utc>>,
+    /// Last run started by the schedule (since the server started)
+    pub last_run_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MissedPolicyJson {
+    Skip,
+    RunOnce,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverlapPolicyJson {
+    Skip,
+    Queue,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RunQueuedJson {
+    pub position: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RunStartedJson {
+    pub run_id: String,
+    /// Not known for runs replayed from history
+    pub pid: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RunExitJson {
+    /// Not set if the process was ended by a signal
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timed_out_after_secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cancelled_by: Option<String>,
+}
+
+/// One line of NDJSON output
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum RunEventJson {
+    Queued {
+        position: usize,
+    },
+    Started {
+        run_id: String,
+        pid: Option<u32>,
+    },
+    Output {
+        seq: usize,
+        stream: OutputStreamJson,
+        time: Option<DateTime<Utc>>,
+        /// Without the line ending
+        line: String,
+    },
+    Exit {
+        run_id: String,
+        exit_code: Option<i32>,
+        signal: Option<i32>,
+        duration_secs: Option<f64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        timed_out_after_secs: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        cancelled_by: Option<String>,
+    },
+}
+
+/// Frame sent by WebSocket clients (only for tasks that accept input)
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum RunInputJson {
+    /// Data for the process's standard input (sent as is, so usually ending with a new line)
+    Input {
+        data: String,
+    },
+    /// End of input
+    Eof,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputStreamJson {
+    Stdout,
+    Stderr,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct QueueJson {
+    /// Not set if there's no limit
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_concurrent_runs: Option<usize>,
+    pub running: usize,
+    pub queued: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SessionJson {
+    pub username: String,
+    pub roles: Vec<String>,
+    /// Not logged in (guest access)
+    pub guest: bool,
+    /// Only for users logged in via the login page (needed for any POST requests)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub csrf_token: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ReloadJson {
+    pub reloaded: bool,
+    /// Why the previous definitions were kept
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Definitions in effect after the reload
+    pub tasks: usize,
+    pub users: usize,
+    pub tokens: usize,
+    pub secrets: usize,
 }
 
 #[cfg(test)]
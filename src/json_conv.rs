@@ -1,5 +1,7 @@
+use crate::history::{OutputStream, RunRecord};
 use crate::json::*;
-use crate::task::{TaskDef, TaskMethod, TaskParameterType, TaskParameterValue, TaskDefParameter};
+use crate::run::RunEvent;
+use crate::task::{MissedPolicy, OverlapPolicy, TaskDef, TaskMethod, TaskParameterType, TaskParameterValue, TaskDefParameter};
 
 impl From<&TaskMethod> for MethodJson {
     fn from(model: &TaskMethod) -> Self {
@@ -12,6 +14,39 @@ impl From<&TaskMethod> for MethodJson {
     }
 }
 
+impl From<OutputStream> for OutputStreamJson {
+    fn from(model: OutputStream) -> Self {
+        match model {
+            OutputStream::Stdout =>
+                OutputStreamJson::Stdout,
+            OutputStream::Stderr =>
+                OutputStreamJson::Stderr,
+        }
+    }
+}
+
+impl From<MissedPolicy> for MissedPolicyJson {
+    fn from(model: MissedPolicy) -> Self {
+        match model {
+            MissedPolicy::Skip =>
+                MissedPolicyJson::Skip,
+            MissedPolicy::RunOnce =>
+                MissedPolicyJson::RunOnce,
+        }
+    }
+}
+
+impl From<OverlapPolicy> for OverlapPolicyJson {
+    fn from(model: OverlapPolicy) -> Self {
+        match model {
+            OverlapPolicy::Skip =>
+                OverlapPolicyJson::Skip,
+            OverlapPolicy::Queue =>
+                OverlapPolicyJson::Queue,
+        }
+    }
+}
+
 impl From<&TaskParameterType> for TaskParameterTypeJson {
     fn from(model: &TaskParameterType) -> Self {
         match model {
@@ -21,6 +56,8 @@ impl From<&TaskParameterType> for TaskParameterTypeJson {
                 TaskParameterTypeJson::Number,
             TaskParameterType::Boolean =>
                 TaskParameterTypeJson::Boolean,
+            TaskParameterType::Secret =>
+                TaskParameterTypeJson::Secret,
         }
     }
 }
@@ -45,7 +82,10 @@ impl From<&TaskDefParameter> for TaskParameterJson {
         TaskParameterJson {
             name: model.name.clone(),
             required: model.required,
-            default: model.default.as_ref().map(|x| x.into()),
+            default: match model._type {
+                TaskParameterType::Secret => None, // (still used when the parameter isn't given)
+                _ => model.default.as_ref().map(|x| x.into()),
+            },
             _type: (&model._type).into(),
             _enum: model._enum.iter().map(|x| x.into()).collect(),
         }
@@ -58,5 +98,67 @@ pub fn to_task_json(model: &TaskDef) -> TaskJson {
         description: model.description.clone(),
         method: model.method.iter().map(Into::into).collect(),
         parameters: model.parameters.iter().map(Into::into).collect(),
+        stdin: model.exec.stdin,
+    }
+}
+
+pub fn to_run_json(model: &RunRecord) -> RunJson {
+    RunJson {
+        id: model.id.clone(),
+        task: model.task.clone(),
+        user: model.username.clone(),
+        params: model.params.clone(),
+        secret_params: model.secret_params.clone(),
+        started_at: model.started_at,
+        finished_at: model.finished_at,
+        exit_code: model.exit_code,
+        signal: model.signal,
+        timed_out_after_secs: model.timed_out_after_secs,
+        cancelled_by: model.cancelled_by.clone(),
+        queue_position: model.queue_position,
+    }
+}
+
+pub fn to_run_notification_json(model: &RunRecord, output_tail: String) -> RunNotificationJson {
+    RunNotificationJson {
+        task: model.task.clone(),
+        run_id: model.id.clone(),
+        user: model.username.clone(),
+        params: model.params.clone(),
+        status: if model.succeeded() { RunStatusJson::Success } else { RunStatusJson::Failure },
+        exit_code: model.exit_code,
+        signal: model.signal,
+        duration_secs: model.finished_at
+            .map(|finished_at| (finished_at - model.started_at).num_milliseconds() as f64 / 1000.0),
+        started_at: model.started_at,
+        finished_at: model.finished_at,
+        output_tail,
+    }
+}
+
+pub fn to_run_event_json(model: &RunEvent) -> RunEventJson {
+    match model {
+        RunEvent::Queued(position) => RunEventJson::Queued {
+            position: *position,
+        },
+        RunEvent::Started { run_id, pid } => RunEventJson::Started {
+            run_id: run_id.clone(),
+            pid: *pid,
+        },
+        RunEvent::Output(line) => RunEventJson::Output {
+            seq: line.seq,
+            stream: line.stream.into(),
+            time: line.time,
+            line: line.text.strip_suffix('\n').unwrap_or(&line.text).to_owned(),
+        },
+        RunEvent::Finished(record) => RunEventJson::Exit {
+            run_id: record.id.clone(),
+            exit_code: record.exit_code,
+            signal: record.signal,
+            duration_secs: record.finished_at
+                .map(|finished_at| (finished_at - record.started_at).num_milliseconds() as f64 / 1000.0),
+            timed_out_after_secs: record.timed_out_after_secs,
+            cancelled_by: record.cancelled_by.clone(),
+        },
     }
 }
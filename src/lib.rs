@@ -12,29 +12,56 @@ use std::net::SocketAddr;
 use std::path::{PathBuf, Path};
 use std::sync::{Arc, RwLock};
 use std::error::{Error as StdError};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
-use futures::{TryFutureExt, FutureExt};
+use chrono::{DateTime, NaiveDate, Utc};
+
+use futures::FutureExt;
 
 use hyper::Server;
 use hyper::service::{make_service_fn, service_fn};
 
 use task_file::{find_task_files, ConfigFileError};
 
+use crate::history::History;
+use crate::email::{Mailer, SmtpSettings};
+use crate::notify::Notifier;
+use crate::run::ActiveRun;
+use crate::scheduler::{Clock, Scheduler, SchedulerSettings};
+use crate::secret::Secrets;
+use crate::slots::Slots;
 use crate::task::TaskDef;
 use crate::task_file::TaskFileToml;
-use crate::server_file::ServerToml;
+use crate::server_file::{ServerAuthTokenToml, ServerAuthUserToml, ServerToml};
 
+mod ansi;
+pub mod check;
+mod cron;
+mod email;
+mod history;
 mod interleave;
 mod json;
 mod json_conv;
+mod ndjson;
+mod notify;
 pub mod password;
+mod process;
+mod output;
+mod reload;
+mod run;
+mod scheduler;
+mod secret;
 mod server;
 mod server_file;
+mod session;
+mod slots;
+mod sse;
 mod task;
 mod task_file;
 mod utils;
 mod web;
+mod webhook;
+mod websocket;
 
 #[derive(Debug)]
 enum AppError {
@@ -54,13 +81,23 @@ impl std::error::Error for AppError {}
 // current directory by default
 const DEFAULT_TASK_DIR: &'static str = ".";
 const DEFAULT_LISTEN_ADDR: &'static str = "0.0.0.0:8080";
+const DEFAULT_ADMIN_ROLE: &str = "ADMIN";
+const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(10);
 
+#[derive(Debug, Clone)]
 pub struct ServerConfig {
     pub config: PathBuf,
 }
 
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub enum CredentialType {
+    Password,
+    Token,
+}
+
 #[derive(Debug, PartialEq, Eq, Hash)]
 pub struct CachedCredential {
+    pub credential_type: CredentialType,
     pub username: String,
     pub password_hash: Vec<u8>,
 }
@@ -69,31 +106,125 @@ pub struct UserSession {
     pub expires_at: Instant,
 }
 
+/// Logged in via the login page (identified by session cookie)
+pub struct LoginSession {
+    pub username: String,
+    pub csrf_token: String,
+    pub expires_at: Instant,
+}
+
 pub struct UserDef {
     pub username: String,
     pub password: password::PasswordParts,
     pub roles: HashSet<String>,
 }
 
+pub struct AuthSettings {
+    pub enabled: bool,
+    pub guest: bool,
+    pub guest_roles: HashSet<String>,
+    /// Roles allowed to administer the server (eg, reload configuration)
+    pub admin_roles: HashSet<String>,
+}
+
+pub struct ExecSettings {
+    /// Tasks without their own timeout run for as long as they like if not set
+    pub timeout: Option<Duration>,
+    pub grace_period: Duration,
+    pub max_concurrent_runs: Option<usize>,
+}
+
+pub struct TokenDef {
+    pub name: String,
+    pub hash: password::PasswordParts,
+    pub roles: HashSet<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
 pub struct Shared {
-    // pub config: ServerConfig,
+    pub config: ServerConfig,
+    pub auth: AuthSettings,
+    pub exec: ExecSettings,
+    pub task_dir: RwLock<PathBuf>,
     pub tasks: RwLock<HashMap<String, TaskDef>>,
     pub users: RwLock<HashMap<String, UserDef>>,
+    pub tokens: RwLock<HashMap<String, TokenDef>>,
+    pub secrets: RwLock<Secrets>,
     pub sessions: RwLock<HashMap<CachedCredential, UserSession>>,
+    pub login_sessions: RwLock<HashMap<String, LoginSession>>,
+    pub history: Option<History>,
+    pub runs: RwLock<HashMap<String, Arc<ActiveRun>>>,
+    pub slots: Arc<Slots>,
+    pub scheduler: Scheduler,
+    pub notifier: Notifier,
 }
 
 pub struct TaskRequest {
     pub name: String,
-    pub method: http::Method,
+    /// Not set for runs started via WebSocket (which aren't restricted to the task's methods)
+    pub method: Option<http::Method>,
     pub params: HashMap<String, String>,
 }
 
-#[derive(Debug)]
 pub struct TaskExec {
+    pub name: String,
+    pub detach: bool,
+    pub params: HashMap<String, String>,
+    /// Names of parameters with the 'secret' type
+    pub secret_params: HashSet<String>,
     pub command: String,
     pub args: Vec<String>,
     pub dir: PathBuf,
     pub env: HashMap<String, String>,
+    /// Names of secrets by environment variable (added to the environment when spawning the process)
+    pub secret_env: HashMap<String, String>,
+    pub timeout: Option<Duration>,
+    pub grace_period: Duration,
+    pub concurrency: Option<usize>,
+    pub lock: Option<String>,
+    pub queue: bool,
+    pub stdin: bool,
+    pub notify: Vec<task::NotifyDef>,
+    pub email: Vec<task::EmailDef>,
+}
+
+impl TaskExec {
+    /// Values of secret parameters (masked wherever they turn up)
+    pub fn secret_values(&self) -> Vec<String> {
+        self.secret_params.iter()
+            .filter_map(|name| self.params.get(name).cloned())
+            .collect()
+    }
+}
+
+/// (secret values are redacted, as runs are logged)
+impl fmt::Debug for TaskExec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secret_values = self.secret_values();
+
+        let env: HashMap<&String, &str> = self.env.iter()
+            .map(|(name, value)| (name, if secret_values.contains(value) { secret::REDACTED } else { value.as_str() }))
+            .collect();
+
+        f.debug_struct("TaskExec")
+            .field("name", &self.name)
+            .field("detach", &self.detach)
+            .field("params", &secret::redact_params(&self.params, &self.secret_params))
+            .field("command", &self.command)
+            .field("args", &self.args)
+            .field("dir", &self.dir)
+            .field("env", &env)
+            .field("secret_env", &self.secret_env)
+            .field("timeout", &self.timeout)
+            .field("grace_period", &self.grace_period)
+            .field("concurrency", &self.concurrency)
+            .field("lock", &self.lock)
+            .field("queue", &self.queue)
+            .field("stdin", &self.stdin)
+            .field("notify", &self.notify)
+            .field("email", &self.email)
+            .finish()
+    }
 }
 
 impl fmt::Display for ConfigFileError {
@@ -108,6 +239,30 @@ impl fmt::Display for ConfigFileError {
                 write!(f, "Invalid task file name: {}", path.to_string_lossy()),
             ConfigFileError::InvalidPasswordHash { username } =>
                 write!(f, "Invalid password hash for username: {}", username),
+            ConfigFileError::InvalidTokenName { name } =>
+                write!(f, "Invalid token name: {}", name),
+            ConfigFileError::InvalidTokenHash { name } =>
+                write!(f, "Invalid hash for token: {}", name),
+            ConfigFileError::InvalidTokenExpiry { name } =>
+                write!(f, "Invalid expiry for token: {}", name),
+            ConfigFileError::InvalidDuration { value, path } =>
+                write!(f, "Invalid duration: {} ({})", value, path.to_string_lossy()),
+            ConfigFileError::InvalidMaxConcurrentRuns(path) =>
+                write!(f, "max-concurrent-runs must be at least 1 ({})", path.to_string_lossy()),
+            ConfigFileError::InvalidConcurrency(path) =>
+                write!(f, "concurrency must be at least 1 ({})", path.to_string_lossy()),
+            ConfigFileError::InvalidSchedule { value, reason, path } =>
+                write!(f, "Invalid schedule: {}, {} ({})", value, reason, path.to_string_lossy()),
+            ConfigFileError::InvalidHeaderName { value, path } =>
+                write!(f, "Invalid header name: {} ({})", value, path.to_string_lossy()),
+            ConfigFileError::InvalidUrl { value, path } =>
+                write!(f, "Invalid URL: {} ({})", value, path.to_string_lossy()),
+            ConfigFileError::InvalidTemplate { reason, path } =>
+                write!(f, "Invalid template: {} ({})", reason, path.to_string_lossy()),
+            ConfigFileError::InsecurePermissions(path) =>
+                write!(f, "File can be read by other users (should only be readable by its owner): {}", path.to_string_lossy()),
+            ConfigFileError::UnknownSecret { name, task } =>
+                write!(f, "Unknown secret: {} (task {})", name, task),
         }
     }
 }
@@ -137,6 +292,171 @@ fn load_tasks(task_dir: &Path) -> Result<HashMap<String, TaskDef>, GenericError>
     Ok(tasks_by_name)
 }
 
+fn load_users(users: Vec<ServerAuthUserToml>) -> Result<HashMap<String, UserDef>, ConfigFileError> {
+    let mut result = HashMap::<String, UserDef>::new();
+    // using a loop rather than 'map()' for simpler control flow on error
+    for user in users {
+        result.insert(user.username.clone(), UserDef {
+            username: user.username.clone(),
+            password: password::parse_password(&user.password).map_err(|err| {
+                error!("Error parsing password for user: {} ({:?})", user.username.clone(), err);
+                ConfigFileError::InvalidPasswordHash {
+                    username: user.username.clone()
+                }
+            })?,
+            roles: user.roles.clone().into_iter().collect(),
+        });
+    }
+    Ok(result)
+}
+
+fn load_tokens(tokens: Vec<ServerAuthTokenToml>) -> Result<HashMap<String, TokenDef>, ConfigFileError> {
+    let mut result = HashMap::<String, TokenDef>::new();
+    for token in tokens {
+        if !password::is_valid_token_name(&token.name) {
+            return Err(ConfigFileError::InvalidTokenName { name: token.name });
+        }
+
+        let expires_at = match &token.expires {
+            Some(expires) => Some(parse_expiry(expires).ok_or_else(|| {
+                ConfigFileError::InvalidTokenExpiry { name: token.name.clone() }
+            })?),
+            None => None
+        };
+
+        result.insert(token.name.clone(), TokenDef {
+            name: token.name.clone(),
+            hash: password::parse_password(&token.hash).map_err(|err| {
+                error!("Error parsing hash for token: {} ({:?})", token.name, err);
+                ConfigFileError::InvalidTokenHash {
+                    name: token.name.clone()
+                }
+            })?,
+            roles: token.roles.into_iter().collect(),
+            expires_at,
+        });
+    }
+    Ok(result)
+}
+
+/// TOML date-times (eg, '2030-01-01T00:00:00Z') or dates (expiring at the start of that day, UTC)
+fn parse_expiry(value: &toml::value::Datetime) -> Option<DateTime<Utc>> {
+    let value = value.to_string();
+
+    DateTime::parse_from_rfc3339(&value).map(|x| x.with_timezone(&Utc))
+        .ok()
+        .or_else(|| {
+            NaiveDate::parse_from_str(&value, "%Y-%m-%d").ok()
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+                .map(|x| x.and_utc())
+        })
+}
+
+fn load_exec_settings(config: &ServerConfig, server_toml: &ServerToml) -> Result<ExecSettings, ConfigFileError> {
+    let to_duration = |value: Option<&String>| -> Result<Option<Duration>, ConfigFileError> {
+        match value {
+            Some(value) => utils::parse_duration(value).map(Some).ok_or_else(|| {
+                ConfigFileError::InvalidDuration { value: value.clone(), path: config.config.clone() }
+            }),
+            None => Ok(None)
+        }
+    };
+
+    let exec = server_toml.exec.as_ref();
+
+    let max_concurrent_runs = exec.and_then(|exec| exec.max_concurrent_runs);
+
+    if max_concurrent_runs == Some(0) {
+        return Err(ConfigFileError::InvalidMaxConcurrentRuns(config.config.clone()));
+    }
+
+    Ok(ExecSettings {
+        timeout: to_duration(exec.and_then(|exec| exec.timeout.as_ref()))?,
+        grace_period: to_duration(exec.and_then(|exec| exec.grace_period.as_ref()))?
+            .unwrap_or(DEFAULT_GRACE_PERIOD),
+        max_concurrent_runs,
+    })
+}
+
+fn load_notify_settings(config: &ServerConfig, server_toml: &ServerToml) -> Result<Vec<task::NotifyDef>, ConfigFileError> {
+    server_toml.notify.iter()
+        .map(|notify| task_file::to_notify_def(notify.clone(), &config.config))
+        .collect()
+}
+
+fn load_smtp_settings(server_toml: &ServerToml) -> Option<SmtpSettings> {
+    server_toml.smtp.as_ref().map(|smtp| {
+        let starttls = smtp.starttls.unwrap_or(true);
+        SmtpSettings {
+            host: smtp.host.clone(),
+            port: smtp.port.unwrap_or(if starttls { email::DEFAULT_PORT } else { email::DEFAULT_PORT_PLAIN }),
+            starttls,
+            credentials: smtp.username.clone().map(|username| (username, smtp.password.clone().unwrap_or_default())),
+            allow_insecure_auth: smtp.allow_insecure_auth.unwrap_or(false),
+            from: smtp.from.clone(),
+        }
+    })
+}
+
+/// Definitions that can be reloaded while the server is running (see `reload`)
+pub struct Definitions {
+    pub task_dir: PathBuf,
+    pub tasks: HashMap<String, TaskDef>,
+    pub users: HashMap<String, UserDef>,
+    pub tokens: HashMap<String, TokenDef>,
+    pub secrets: Secrets,
+}
+
+fn load_secrets(config: &ServerConfig, server_toml: &ServerToml) -> Result<Secrets, ConfigFileError> {
+    let secrets = server_toml.secrets.as_ref();
+
+    let file = secrets.and_then(|secrets| secrets.file.as_ref()).map(|file| resolve_config_path(config, file.into()));
+    let dir = secrets.and_then(|secrets| secrets.dir.as_ref()).map(|dir| resolve_config_path(config, dir.into()));
+
+    Secrets::load(file.as_deref(), dir.as_deref())
+}
+
+fn load_definitions(config: &ServerConfig, server_toml: ServerToml) -> Result<Definitions, GenericError> {
+    let secrets = load_secrets(config, &server_toml).map_err(box_error)?;
+
+    let task_dir: PathBuf = server_toml.server
+        .and_then(|server| server.dir)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_TASK_DIR));
+
+    let task_dir = resolve_config_path(config, task_dir);
+
+    let tasks = load_tasks(&task_dir)?;
+
+    for task in tasks.values() {
+        if let Some(name) = task.exec.secret_names().find(|name| !secrets.contains(name)) {
+            return Err(box_error(ConfigFileError::UnknownSecret { name: name.clone(), task: task.name.clone() }));
+        }
+    }
+
+    let (users, tokens) = match server_toml.auth {
+        None => (HashMap::new(), HashMap::new()),
+        Some(auth) => (
+            load_users(auth.users).map_err(box_error)?,
+            load_tokens(auth.tokens).map_err(box_error)?,
+        )
+    };
+
+    Ok(Definitions { task_dir, tasks, users, tokens, secrets })
+}
+
+/// Resolve directories relative to server config if not an absolute path
+fn resolve_config_path(config: &ServerConfig, path: PathBuf) -> PathBuf {
+    if path.is_absolute() {
+        path
+    } else {
+        match config.config.parent() {
+            Some(config_dir) => config_dir.join(path),
+            None => path
+        }
+    }
+}
+
 async fn shutdown_signal() -> () {
     tokio::signal::ctrl_c()
         .await
@@ -156,57 +476,71 @@ pub async fn run_server<F, SF>(config: ServerConfig, on_bind: F, shutdown_fut: S
         None => Ok(DEFAULT_LISTEN_ADDR.parse().unwrap()),
     }.map_err(box_error)?;
 
-    let task_dir: PathBuf = server_toml.server
-        .and_then(|server| server.dir)
-        .map(|x| PathBuf::from(x))
-        .unwrap_or(PathBuf::from(DEFAULT_TASK_DIR));
+    let history = server_toml.history.as_ref()
+        .map(|history| History::new(resolve_config_path(&config, PathBuf::from(&history.dir))));
 
-    // resolve tasks directory relative to server config if not an absolute path
-    let task_dir_resolved = if task_dir.is_absolute() {
-        task_dir
-    } else {
-        match config.config.parent() {
-            Some(config_dir) => config_dir.join(task_dir),
-            None => task_dir
-        }
+    let watch = server_toml.server.as_ref().and_then(|server| server.watch).unwrap_or(false);
+
+    let auth = AuthSettings {
+        enabled: server_toml.auth.as_ref().and_then(|auth| auth.enabled).unwrap_or(true),
+        guest: server_toml.auth.as_ref().and_then(|auth| auth.guest).unwrap_or(false),
+        guest_roles: server_toml.auth.as_ref().map(|auth| auth.guest_roles.iter().cloned().collect()).unwrap_or_default(),
+        admin_roles: server_toml.auth.as_ref().and_then(|auth| auth.admin_roles.clone())
+            .map(|roles| roles.into_iter().collect())
+            .unwrap_or_else(|| HashSet::from([DEFAULT_ADMIN_ROLE.to_owned()])),
     };
 
-    let tasks_by_name = load_tasks(&task_dir_resolved)?;
-
-    let users: Vec<UserDef> = match server_toml.auth {
-        None => vec![],
-        Some(auth) => {
-            let mut result = Vec::<UserDef>::new();
-            // using a loop rather than 'map()' for simpler control flow on error
-            for user in auth.users {
-                result.push(UserDef {
-                    username: user.username.clone(),
-                    password: password::parse_password(&user.password).map_err(|err| {
-                        error!("Error parsing password for user: {} ({:?})", user.username.clone(), err);
-                        ConfigFileError::InvalidPasswordHash {
-                            username: user.username.clone()
-                        }
-                    }).map_err(box_error)?,
-                    roles: user.roles.clone().into_iter().collect(),
-                });
-            }
-            result
-        }
+    if !auth.enabled {
+        warn!("Authentication is disabled (anyone can run any task)");
+    }
+
+    let exec = load_exec_settings(&config, &server_toml).map_err(box_error)?;
+
+    let notify = load_notify_settings(&config, &server_toml).map_err(box_error)?;
+
+    let server_url = server_toml.server.as_ref().and_then(|server| server.url.clone());
+    let mailer = load_smtp_settings(&server_toml).map(|smtp| Mailer::new(smtp, server_url));
+
+    let scheduler = SchedulerSettings {
+        enabled: server_toml.scheduler.as_ref().and_then(|scheduler| scheduler.enabled).unwrap_or(true),
+        username: server_toml.scheduler.as_ref().and_then(|scheduler| scheduler.username.clone())
+            .unwrap_or_else(|| scheduler::DEFAULT_USERNAME.to_owned()),
+        roles: server_toml.scheduler.as_ref().map(|scheduler| scheduler.roles.iter().cloned().collect()).unwrap_or_default(),
     };
 
-    let users_by_name = users.into_iter()
-        .map(|t| (t.username.clone(), t))
-        .collect::<HashMap<String, UserDef>>();
+    let definitions = load_definitions(&config, server_toml)?;
+
+    let slots = Arc::new(Slots::new(exec.max_concurrent_runs));
 
     let shared = Shared {
-        // config,
-        tasks: RwLock::new(tasks_by_name),
-        users: RwLock::new(users_by_name),
+        config,
+        auth,
+        exec,
+        task_dir: RwLock::new(definitions.task_dir),
+        tasks: RwLock::new(definitions.tasks),
+        users: RwLock::new(definitions.users),
+        tokens: RwLock::new(definitions.tokens),
+        secrets: RwLock::new(definitions.secrets),
         sessions: RwLock::new(HashMap::new()),
+        login_sessions: RwLock::new(HashMap::new()),
+        history,
+        runs: RwLock::new(HashMap::new()),
+        slots,
+        scheduler: Scheduler::new(scheduler, Clock::new()),
+        notifier: Notifier::new(notify, mailer),
     };
 
     let shared = Arc::new(shared);
 
+    // reload on SIGHUP (and changes to configuration files, if watching), and start scheduled runs
+    // (if enabled) until the server stops. Spawned rather than polled along with the server, so
+    // that a panic in one of them doesn't take the server down.
+    let background = [
+        tokio::spawn(reload::reload_on_signal(shared.clone())),
+        tokio::spawn(reload::watch_if(watch, shared.clone())),
+        tokio::spawn(scheduler::run(shared.clone())),
+    ];
+
     let make_svc = make_service_fn(move |_conn| {
         let shared = shared.clone();
         async {
@@ -232,5 +566,11 @@ pub async fn run_server<F, SF>(config: ServerConfig, on_bind: F, shutdown_fut: S
 
     let graceful_server = server.with_graceful_shutdown(shutdown_combined);
 
-    Ok(graceful_server.map_err(box_error).await?)
+    let result = graceful_server.await.map_err(box_error);
+
+    for handle in &background {
+        handle.abort();
+    }
+
+    result
 }
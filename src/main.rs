@@ -3,6 +3,7 @@ extern crate log;
 extern crate henchman;
 
 use henchman::{ServerConfig, run_server};
+use henchman::check::CheckReport;
 
 use tokio::runtime::Runtime;
 
@@ -15,7 +16,8 @@ use futures::FutureExt;
 
 #[derive(Debug)]
 struct ParsedArgs {
-    config: String
+    config: String,
+    check: bool,
 }
 
 fn print_usage(program: &str, opts: Options) {
@@ -29,6 +31,7 @@ fn parse_args() -> Option<ParsedArgs> {
 
     let mut opts = Options::new();
     opts.reqopt("c", "config", "Server configuration file", "CONFIG");
+    opts.optflag("", "check", "Check the configuration and task files, then exit (non-zero exit code if there are errors)");
 
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
@@ -41,7 +44,8 @@ fn parse_args() -> Option<ParsedArgs> {
 
     let parsed = ParsedArgs {
         config: matches.opt_str("config").unwrap_or_else(||
-            panic!("Required field not provided")) // should have already been validated
+            panic!("Required field not provided")), // should have already been validated
+        check: matches.opt_present("check"),
     };
 
     Some(parsed)
@@ -63,6 +67,10 @@ pub fn main() -> Result<(), Box<dyn std::error::Error>> {
         config: PathBuf::from(args.config)
     };
 
+    if args.check {
+        check_config(&config);
+    }
+
     let runtime = Runtime::new()?;
 
     let (_shutdown_signal_tx, shutdown_signal_rx) = tokio::sync::oneshot::channel::<()>();
@@ -78,3 +86,20 @@ pub fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+/// Report problems with the configuration and exit
+fn check_config(config: &ServerConfig) -> ! {
+    let report: CheckReport = henchman::check::check_config(config);
+
+    for problem in &report.problems {
+        eprintln!("{}", problem);
+    }
+
+    if report.has_errors() {
+        eprintln!("Configuration has errors: {}", config.config.to_string_lossy());
+        std::process::exit(1)
+    }
+
+    println!("Configuration OK: {} ({} tasks)", config.config.to_string_lossy(), report.tasks);
+    std::process::exit(0)
+}
@@ -0,0 +1,71 @@
+use std::convert::Infallible;
+
+use futures::stream::{Stream, StreamExt};
+
+use http::{HeaderMap, HeaderValue};
+use hyper::Body;
+use hyper::body::Bytes;
+
+use crate::history::RunRecord;
+use crate::json_conv;
+use crate::run::RunEvent;
+
+pub const APPLICATION_NDJSON: &str = "application/x-ndjson";
+
+/// Sent after the output when the client asks for trailers ('TE: trailers')
+pub const TRAILER_NAMES: &str = "X-Run-Status, X-Exit-Code";
+
+/// One JSON object per line: 'queued', 'started', one 'output' per line of output, and finally 'exit'
+pub fn lines<S>(events: S) -> impl Stream<Item=Result<String, Infallible>>
+    where S: Stream<Item=RunEvent> {
+    events.map(|event| Ok(to_line(&event)))
+}
+
+/// Lines followed by trailer fields saying whether the run succeeded, so clients don't need to
+/// parse the output to find out (only sent on HTTP/2 connections, HTTP/1.1 trailers aren't supported)
+pub fn body_with_trailers<S>(events: S) -> Body
+    where S: Stream<Item=RunEvent> + Send + 'static {
+    let (mut sender, body) = Body::channel();
+
+    tokio::spawn(async move {
+        futures::pin_mut!(events);
+
+        let mut finished = None;
+
+        while let Some(event) = events.next().await {
+            if let RunEvent::Finished(record) = &event {
+                finished = Some(record.clone());
+            }
+
+            if sender.send_data(Bytes::from(to_line(&event))).await.is_err() {
+                return; // client has gone (events are dropped, as they would be with any other body)
+            }
+        }
+
+        if let Some(record) = finished {
+            let _ = sender.send_trailers(trailers(&record)).await;
+        }
+    });
+
+    body
+}
+
+fn to_line(event: &RunEvent) -> String {
+    let mut line = serde_json::to_string(&json_conv::to_run_event_json(event)).unwrap(); // TODO: handle error
+    line.push('\n');
+    line
+}
+
+fn trailers(record: &RunRecord) -> HeaderMap {
+    let mut trailers = HeaderMap::new();
+
+    let status = if record.succeeded() { "success" } else { "failure" };
+
+    trailers.insert("X-Run-Status", HeaderValue::from_static(status));
+
+    if let Some(exit_code) = record.exit_code {
+        trailers.insert("X-Exit-Code", HeaderValue::from(exit_code));
+    }
+
+    trailers
+}
@@ -0,0 +1,315 @@
+use std::time::Duration;
+
+use http::{header, Method, Request, StatusCode};
+
+use hyper::{Body, Client};
+use hyper::client::HttpConnector;
+
+use hyper_rustls::HttpsConnector;
+
+use serde_json::Value;
+
+use crate::email::Mailer;
+use crate::history::RunRecord;
+use crate::task::{EmailDef, NotifyDef};
+
+pub const DEFAULT_RETRIES: u32 = 3;
+
+/// Wait before the first retry (doubled for each one after that)
+pub const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Longest wait for a response to each attempt (a server that doesn't answer is tried again)
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Lines of output included in notifications
+pub const OUTPUT_TAIL_LINES: usize = 20;
+
+/// Fields of the run that can be used in body templates (as well as 'params.<name>')
+const PLACEHOLDERS: [&str; 10] = [
+    "task", "run_id", "user", "status", "exit_code", "signal", "duration_secs", "started_at", "finished_at", "output_tail",
+];
+
+/// Sends word of finished runs to '[[notify]]' URLs (and '[[email]]' recipients)
+pub struct Notifier {
+    client: Client<HttpsConnector<HttpConnector>>,
+    /// For runs of every task
+    notify: Vec<NotifyDef>,
+    /// Not set without an SMTP server
+    mailer: Option<Mailer>,
+}
+
+impl Notifier {
+    pub fn new(notify: Vec<NotifyDef>, mailer: Option<Mailer>) -> Notifier {
+        let connector = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+
+        Notifier { client: Client::builder().build(connector), notify, mailer }
+    }
+
+    /// Send details of the run to the server's URLs and the task's, and email the task's
+    /// recipients (in the background)
+    pub fn run_finished(&self, record: &RunRecord, output_tail: String, task_notify: &[NotifyDef], task_email: &[EmailDef]) {
+        match &self.mailer {
+            Some(mailer) => mailer.run_finished(record, &output_tail, task_email),
+            None if !task_email.is_empty() => warn!("Not emailing about run of task {}, no SMTP server configured", record.task),
+            None => {}
+        }
+
+        let notify: Vec<&NotifyDef> = self.notify.iter()
+            .chain(task_notify)
+            .filter(|notify| notify.on.matches(record.succeeded()))
+            .collect();
+
+        if notify.is_empty() {
+            return;
+        }
+
+        let payload = serde_json::to_value(crate::json_conv::to_run_notification_json(record, output_tail)).unwrap(); // TODO: handle error
+
+        for notify in notify {
+            let body = match &notify.body {
+                Some(template) => render(template, &payload),
+                None => payload.to_string(),
+            };
+
+            tokio::spawn(send(self.client.clone(), notify.clone(), body));
+        }
+    }
+}
+
+/// Post the body, trying again (after a while) if the server can't take it right now
+async fn send(client: Client<HttpsConnector<HttpConnector>>, notify: NotifyDef, body: String) -> bool {
+    // (whole URL isn't logged, as they often include a secret)
+    let host = notify.url.host().unwrap_or_default();
+
+    let mut backoff = INITIAL_BACKOFF;
+
+    for attempt in 0..=notify.retries {
+        if attempt > 0 {
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(notify.url.clone())
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.clone()))
+            .unwrap(); // TODO: handle error
+
+        let res = match tokio::time::timeout(REQUEST_TIMEOUT, client.request(req)).await {
+            Ok(res) => res,
+            Err(_) => {
+                warn!("Timed out sending notification to {}", host);
+                continue;
+            }
+        };
+
+        match res {
+            Ok(res) if res.status().is_success() => {
+                info!("Sent notification to {}", host);
+                return true;
+            }
+            Ok(res) if res.status().is_server_error() || res.status() == StatusCode::TOO_MANY_REQUESTS => {
+                warn!("Error sending notification to {}: {}", host, res.status());
+            }
+            Ok(res) => {
+                error!("Notification rejected by {}: {}", host, res.status());
+                return false; // no point trying again
+            }
+            Err(err) => {
+                warn!("Error sending notification to {}: {}", host, err);
+            }
+        }
+    }
+
+    error!("Gave up sending notification to {} after {} attempt(s)", host, notify.retries + 1);
+    false
+}
+
+/// Names of the '{{...}}' placeholders in the template (in order)
+fn placeholders(template: &str) -> Result<Vec<(usize, usize, &str)>, String> {
+    let mut result = vec![];
+    let mut offset = 0;
+
+    while let Some(start) = template[offset..].find("{{").map(|start| offset + start) {
+        let end = template[start..].find("}}")
+            .map(|end| start + end + 2)
+            .ok_or_else(|| format!("unclosed placeholder at {}", start))?;
+
+        result.push((start, end, template[start + 2..end - 2].trim()));
+        offset = end;
+    }
+
+    Ok(result)
+}
+
+pub fn check_template(template: &str) -> Result<(), String> {
+    for (_, _, name) in placeholders(template)? {
+        if !PLACEHOLDERS.contains(&name) && !name.starts_with("params.") {
+            return Err(format!("unknown placeholder: {}", name));
+        }
+    }
+    Ok(())
+}
+
+/// Fill in the template's placeholders from the payload. Values are escaped for use inside JSON
+/// strings (templates are expected to be JSON for chat services), with missing values left empty.
+fn render(template: &str, payload: &Value) -> String {
+    let mut result = String::with_capacity(template.len());
+    let mut offset = 0;
+
+    for (start, end, name) in placeholders(template).unwrap_or_default() { // (checked when loaded)
+        result.push_str(&template[offset..start]);
+
+        let value = match name.strip_prefix("params.") {
+            Some(param) => payload.get("params").and_then(|params| params.get(param)),
+            None => payload.get(name),
+        };
+
+        match value {
+            Some(Value::String(value)) => {
+                let quoted = Value::String(value.clone()).to_string();
+                result.push_str(&quoted[1..quoted.len() - 1]);
+            }
+            Some(Value::Null) | None => {}
+            Some(value) => result.push_str(&value.to_string()),
+        }
+
+        offset = end;
+    }
+
+    result.push_str(&template[offset..]);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+
+    use hyper::Response;
+
+    use crate::task::NotifyOn;
+    use hyper::service::{make_service_fn, service_fn};
+
+    use serde_json::json;
+
+    #[test]
+    fn test_check_template() {
+        assert_eq!(check_template(r#"{"text": "{{task}} {{ status }} {{params.branch}}"}"#), Ok(()));
+        assert_eq!(check_template("no placeholders"), Ok(()));
+        assert!(check_template("{{password}}").is_err());
+        assert!(check_template("{{task").is_err());
+    }
+
+    #[test]
+    fn test_render() {
+        let payload = json!({
+            "task": "deploy",
+            "exit_code": 1,
+            "signal": null,
+            "output_tail": "Error: \"oops\"\n",
+            "params": {"branch": "main"},
+        });
+
+        assert_eq!(render(r#"{"text": "{{task}} ({{params.branch}}) exited with {{exit_code}}{{signal}}: {{output_tail}}"}"#, &payload),
+                   r#"{"text": "deploy (main) exited with 1: Error: \"oops\"\n"}"#);
+    }
+
+    /// Local stand-in for a webhook receiver, failing with the given statuses before succeeding
+    async fn receiver(failures: Vec<StatusCode>) -> (SocketAddr, Arc<Mutex<Vec<String>>>) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let failures = Arc::new(Mutex::new(failures));
+
+        let make_svc = make_service_fn({
+            let received = received.clone();
+            move |_conn| {
+                let received = received.clone();
+                let failures = failures.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                        let received = received.clone();
+                        let failures = failures.clone();
+                        async move {
+                            let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                            received.lock().unwrap().push(String::from_utf8(body.to_vec()).unwrap());
+
+                            let mut failures = failures.lock().unwrap();
+                            let status = if failures.is_empty() { StatusCode::OK } else { failures.remove(0) };
+
+                            Ok::<_, Infallible>(Response::builder().status(status).body(Body::empty()).unwrap())
+                        }
+                    }))
+                }
+            }
+        });
+
+        let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_svc);
+        let local_addr = server.local_addr();
+        tokio::spawn(server);
+
+        (local_addr, received)
+    }
+
+    fn notify(local_addr: SocketAddr, retries: u32) -> NotifyDef {
+        NotifyDef {
+            url: format!("http://{}/hook", local_addr).parse().unwrap(),
+            on: NotifyOn::Always,
+            body: None,
+            retries,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_send_retries() {
+        let (local_addr, received) = receiver(vec![StatusCode::INTERNAL_SERVER_ERROR, StatusCode::SERVICE_UNAVAILABLE]).await;
+
+        let notifier = Notifier::new(vec![], None);
+
+        assert!(send(notifier.client.clone(), notify(local_addr, 2), "{}".to_owned()).await);
+        assert_eq!(received.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_send_gives_up() {
+        let (local_addr, received) = receiver(vec![StatusCode::BAD_GATEWAY; 5]).await;
+
+        let notifier = Notifier::new(vec![], None);
+
+        assert!(!send(notifier.client.clone(), notify(local_addr, 1), "{}".to_owned()).await);
+        assert_eq!(received.lock().unwrap().len(), 2);
+
+        // not retrying requests that won't ever be accepted
+        let (local_addr, received) = receiver(vec![StatusCode::NOT_FOUND]).await;
+
+        assert!(!send(notifier.client.clone(), notify(local_addr, 3), "{}".to_owned()).await);
+        assert_eq!(received.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_send_times_out() {
+        // (stand-in for a receiver that accepts connections but never responds)
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut connections = vec![];
+            while let Ok((stream, _)) = listener.accept().await {
+                connections.push(stream);
+            }
+        });
+
+        let notifier = Notifier::new(vec![], None);
+        let started = tokio::time::Instant::now();
+
+        assert!(!send(notifier.client.clone(), notify(local_addr, 1), "{}".to_owned()).await);
+        assert!(started.elapsed() >= REQUEST_TIMEOUT * 2 + INITIAL_BACKOFF);
+    }
+}
@@ -0,0 +1,148 @@
+use std::convert::Infallible;
+
+use futures::stream::{Stream, StreamExt};
+
+use http::{header, response};
+use hyper::{Body, Request, Response};
+
+use crate::ansi::{escape_html, AnsiToHtml};
+use crate::history::OutputStream;
+use crate::run::RunEvent;
+
+const TEXT_HTML: &str = "text/html";
+
+/// Start of an HTML page showing output (the rest is streamed as the run goes)
+const HTML_HEADER: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="UTF-8"/>
+<meta name="viewport" content="width=device-width">
+<title>Run output</title>
+<style>
+body { margin: 0; background: #1e1e1e; color: #e5e5e5; }
+pre { margin: 0; padding: 8px; white-space: pre-wrap; }
+.stderr { background: #3c1f1f; color: #f5a5a5; }
+.status { font-weight: bold; }
+</style>
+</head>
+<body>
+<pre>"#;
+
+const HTML_FOOTER: &str = "</pre>\n</body>\n</html>\n";
+
+/// How the output of a run is sent to clients (chosen by the 'Accept' header)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Output as it would appear in a terminal, followed by a status line
+    Text,
+    /// Page showing output with colours (from ANSI escape sequences), marking error output
+    Html,
+    /// Server-Sent Events (see `sse`)
+    EventStream,
+    /// JSON object per line (see `ndjson`), optionally with trailer fields saying how the run ended
+    NdJson { trailers: bool },
+}
+
+impl OutputFormat {
+    pub fn from_request<T>(req: &Request<T>) -> OutputFormat {
+        let has_value = |name: header::HeaderName, expected: &str| {
+            req.headers().get_all(name)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .filter_map(|value| value.split(';').next()) // ignoring parameters (eg, quality)
+                .any(|value| value.trim().eq_ignore_ascii_case(expected))
+        };
+
+        if has_value(header::ACCEPT, crate::sse::TEXT_EVENT_STREAM) {
+            OutputFormat::EventStream
+        } else if has_value(header::ACCEPT, crate::ndjson::APPLICATION_NDJSON) {
+            OutputFormat::NdJson { trailers: has_value(header::TE, "trailers") }
+        } else if has_value(header::ACCEPT, TEXT_HTML) {
+            OutputFormat::Html // eg, submitting the form on a task's page
+        } else {
+            OutputFormat::Text
+        }
+    }
+
+    /// Response headers for the format (body is added by `body`)
+    pub fn response(&self) -> response::Builder {
+        let content_type = match self {
+            OutputFormat::Text => "text/plain; charset=utf-8",
+            OutputFormat::Html => "text/html; charset=utf-8",
+            OutputFormat::EventStream => crate::sse::TEXT_EVENT_STREAM,
+            OutputFormat::NdJson { .. } => crate::ndjson::APPLICATION_NDJSON,
+        };
+
+        // being very explicit about content type to prevent browser from buffering (so every line is printed as it executes)
+        let builder = Response::builder()
+            .header("Content-Type", content_type)
+            .header("Cache-Control", "no-store")
+            .header("X-Content-Type-Options", "nosniff");
+
+        match self {
+            OutputFormat::NdJson { trailers: true } => builder.header("Trailer", crate::ndjson::TRAILER_NAMES),
+            _ => builder
+        }
+    }
+
+    /// Response body following the run's events
+    pub fn body<S>(&self, events: S) -> Body
+        where S: Stream<Item=RunEvent> + Send + 'static {
+        match self {
+            OutputFormat::Text => Body::wrap_stream(text(events)),
+            OutputFormat::Html => Body::wrap_stream(html(events)),
+            OutputFormat::EventStream => Body::wrap_stream(crate::sse::event_stream(events)),
+            OutputFormat::NdJson { trailers: false } => Body::wrap_stream(crate::ndjson::lines(events)),
+            OutputFormat::NdJson { trailers: true } => crate::ndjson::body_with_trailers(events),
+        }
+    }
+}
+
+/// Output lines as they are, followed by the exit code (or timeout)
+fn text<S>(events: S) -> impl Stream<Item=Result<String, Infallible>>
+    where S: Stream<Item=RunEvent> {
+    events.filter_map(|event| async move {
+        match event {
+            RunEvent::Queued(position) => Some(Ok(crate::run::queue_status(position))),
+            RunEvent::Started { .. } => None,
+            RunEvent::Output(line) => Some(Ok(line.text)),
+            RunEvent::Finished(record) => Some(Ok(crate::run::trailer(&record))),
+        }
+    })
+}
+
+/// Each line of output is converted to HTML as soon as it arrives (keeping track of the style set
+/// by escape sequences separately for each stream)
+fn html<S>(events: S) -> impl Stream<Item=Result<String, Infallible>>
+    where S: Stream<Item=RunEvent> {
+    let mut stdout = AnsiToHtml::new();
+    let mut stderr = AnsiToHtml::new();
+
+    let lines = events.filter_map(move |event| {
+        let line = match event {
+            RunEvent::Queued(position) => {
+                Some(format!("<span class=\"status\">{}</span>", escape_html(&crate::run::queue_status(position))))
+            }
+            RunEvent::Started { .. } => None,
+            RunEvent::Output(line) => {
+                let (text, newline) = match line.text.strip_suffix('\n') {
+                    Some(text) => (text, "\n"),
+                    None => (line.text.as_str(), "")
+                };
+                match line.stream {
+                    OutputStream::Stdout => Some(format!("{}{}", stdout.convert(text), newline)),
+                    OutputStream::Stderr => Some(format!("<span class=\"stderr\">{}</span>{}", stderr.convert(text), newline)),
+                }
+            }
+            RunEvent::Finished(record) => {
+                Some(format!("<span class=\"status\">{}</span>\n", escape_html(&crate::run::trailer(&record))))
+            }
+        };
+        futures::future::ready(line.map(Ok))
+    });
+
+    futures::stream::once(futures::future::ready(Ok(HTML_HEADER.to_owned())))
+        .chain(lines)
+        .chain(futures::stream::once(futures::future::ready(Ok(HTML_FOOTER.to_owned()))))
+}
@@ -10,6 +10,10 @@ use ring::rand::SecureRandom;
 
 lazy_static! {
     static ref RNG: ring::rand::SystemRandom = ring::rand::SystemRandom::new();
+
+    /// Verified against for unknown users, so that they take as long as known ones
+    pub static ref DUMMY_PASSWORD: PasswordParts = parse_password(&hash_password(&generate_secret()))
+        .expect("Error parsing dummy password");
 }
 
 struct PasswordFormatV1;
@@ -24,6 +28,7 @@ static PBKDF2_ALG: pbkdf2::Algorithm = ring::pbkdf2::PBKDF2_HMAC_SHA256;
 
 const DEFAULT_ITERATIONS: u32 = 10_000;
 
+#[derive(Clone)]
 pub struct PasswordParts {
     // if we change structure/algorithm in the future
     version: u8,
@@ -53,6 +58,33 @@ pub fn hash_password(password: &str) -> String {
     hex_encoded
 }
 
+const TOKEN_SECRET_LEN: usize = 32;
+
+/// Random value (hex encoded) for use as a token or session ID
+pub fn generate_secret() -> String {
+    let mut secret = [0u8; TOKEN_SECRET_LEN];
+    RNG.fill(&mut secret).expect("Error generating random number");
+
+    hex::encode(secret)
+}
+
+/// API tokens are '<name>.<secret>' (name is used to find which hash to verify the secret against)
+pub fn generate_token(name: &str) -> (String, String) {
+    let secret_hex = generate_secret();
+
+    (format!("{}.{}", name, secret_hex), hash_password(&secret_hex))
+}
+
+pub fn is_valid_token_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Split a token into its name and secret parts
+pub fn split_token(token: &str) -> Option<(&str, &str)> {
+    token.split_once('.')
+        .filter(|(name, secret)| is_valid_token_name(name) && !secret.is_empty())
+}
+
 #[derive(Debug)]
 pub enum VerifyError {
     MalformedInput,
@@ -129,4 +161,32 @@ mod tests {
         assert_eq!(verify_password("secret", &hashed).ok(), Some(true));
         assert_eq!(verify_password("wrong", &hashed).ok(), Some(false));
     }
+
+    #[test]
+    fn test_dummy_password() {
+        assert_eq!(verify_password_parts("", &DUMMY_PASSWORD).ok(), Some(false));
+        assert_eq!(verify_password_parts("secret", &DUMMY_PASSWORD).ok(), Some(false));
+    }
+
+    #[test]
+    fn test_generate_and_verify_token() {
+        let (token, hashed) = generate_token("ci");
+
+        let (name, secret) = split_token(&token).unwrap();
+
+        assert_eq!(name, "ci");
+        assert_eq!(secret.len(), TOKEN_SECRET_LEN * 2);
+
+        assert_eq!(verify_password(secret, &hashed).ok(), Some(true));
+        assert_eq!(verify_password(&token, &hashed).ok(), Some(false));
+    }
+
+    #[test]
+    fn test_split_token() {
+        assert_eq!(split_token("ci.abc"), Some(("ci", "abc")));
+        assert_eq!(split_token("ci"), None);
+        assert_eq!(split_token("ci."), None);
+        assert_eq!(split_token(".abc"), None);
+        assert_eq!(split_token("c/i.abc"), None);
+    }
 }
\ No newline at end of file
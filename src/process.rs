@@ -0,0 +1,117 @@
+use std::io::Error as IoError;
+use std::process::ExitStatus;
+
+use tokio::process::{Child, Command};
+
+/// Process of a run, along with anything it starts. On Unix each run gets its own session (and so
+/// its own process group), which lets us signal scripts and their background jobs all together.
+pub struct RunProcess {
+    child: Child,
+    /// Same as the process ID of the child (cleared once nothing can be left running in the group)
+    group_id: Option<u32>,
+}
+
+/// Start the process in a new session (and process group) rather than the server's
+#[cfg(unix)]
+pub fn configure(command: &mut Command) {
+    unsafe {
+        // only calls an async-signal-safe function between fork and exec
+        command.pre_exec(|| {
+            if libc::setsid() == -1 {
+                return Err(IoError::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(not(unix))]
+pub fn configure(_command: &mut Command) {}
+
+impl RunProcess {
+    pub fn new(child: Child) -> RunProcess {
+        let group_id = child.id();
+        RunProcess { child, group_id }
+    }
+
+    pub fn pid(&self) -> Option<u32> {
+        self.child.id()
+    }
+
+    /// Ask all processes to stop (giving them a chance to clean up, unlike `kill`)
+    pub fn terminate(&mut self) {
+        self.signal(Signal::Terminate);
+    }
+
+    pub fn kill(&mut self) {
+        self.signal(Signal::Kill);
+    }
+
+    /// Wait for the child to exit (cancel safe, so fine to use in a `select!`)
+    pub async fn wait(&mut self) -> Result<ExitStatus, IoError> {
+        self.child.wait().await
+    }
+
+    /// Once the child has exited, kill anything it left running in its group (background jobs
+    /// would otherwise be left behind as orphans, and keep the output streams open)
+    pub fn kill_remaining(&mut self) {
+        self.kill();
+        // group ID could eventually be reused once everything in it has gone
+        self.group_id = None;
+    }
+
+    #[cfg(unix)]
+    fn signal(&mut self, signal: Signal) {
+        let signal = match signal {
+            Signal::Terminate => libc::SIGTERM,
+            Signal::Kill => libc::SIGKILL,
+        };
+
+        if let Some(group_id) = self.group_id {
+            // safe to call with any ID (worst case is an error result)
+            if unsafe { libc::killpg(group_id as libc::pid_t, signal) } != 0 {
+                let err = IoError::last_os_error();
+                if err.raw_os_error() != Some(libc::ESRCH) { // nothing left to signal
+                    error!("Error signalling process group {}: {}", group_id, err);
+                }
+            }
+        }
+    }
+
+    /// No process groups or signals, so just the child is killed either way
+    #[cfg(not(unix))]
+    fn signal(&mut self, _signal: Signal) {
+        if self.group_id.is_some() {
+            if let Err(err) = self.child.start_kill() {
+                error!("Error killing process: {}", err);
+            }
+        }
+    }
+}
+
+/// Signal that ended the process, if any
+#[cfg(unix)]
+pub fn exit_signal(status: &ExitStatus) -> Option<i32> {
+    use std::os::unix::process::ExitStatusExt;
+
+    status.signal()
+}
+
+#[cfg(not(unix))]
+pub fn exit_signal(_status: &ExitStatus) -> Option<i32> {
+    None
+}
+
+enum Signal {
+    Terminate,
+    Kill,
+}
+
+/// Driver of a run was dropped before the process finished (eg, on shutdown)
+impl Drop for RunProcess {
+    fn drop(&mut self) {
+        if self.group_id.is_some() {
+            self.kill();
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use tokio::time::MissedTickBehavior;
+
+use crate::{box_error, GenericError, Shared};
+use crate::server_file::{self, ServerToml};
+use crate::task_file::find_task_files;
+
+/// How often to check configuration files for changes (when watching)
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Number of definitions in effect
+pub struct ReloadSummary {
+    pub tasks: usize,
+    pub users: usize,
+    pub tokens: usize,
+    pub secrets: usize,
+}
+
+pub fn summary(shared: &Shared) -> ReloadSummary {
+    ReloadSummary {
+        tasks: shared.tasks.read().unwrap().len(),
+        users: shared.users.read().unwrap().len(),
+        tokens: shared.tokens.read().unwrap().len(),
+        secrets: shared.secrets.read().unwrap().len(),
+    }
+}
+
+/// Reload task definitions, users, tokens and secrets. Nothing is replaced unless everything loads, so a
+/// broken file leaves the previous definitions in place. Runs already executing are not affected.
+///
+/// Other settings (listen address, history, auth modes) are only read at startup.
+pub async fn reload(shared: &Arc<Shared>) -> Result<ReloadSummary, GenericError> {
+    let shared = shared.clone();
+
+    // (reading and parsing files blocks, which would hold up other requests)
+    tokio::task::spawn_blocking(move || reload_blocking(&shared)).await.map_err(box_error)?
+}
+
+fn reload_blocking(shared: &Shared) -> Result<ReloadSummary, GenericError> {
+    let server_toml = server_file::load_toml::<ServerToml>(&shared.config.config).map_err(box_error)?;
+
+    let definitions = crate::load_definitions(&shared.config, server_toml)?;
+
+    *shared.task_dir.write().unwrap() = definitions.task_dir;
+    *shared.tasks.write().unwrap() = definitions.tasks;
+    *shared.users.write().unwrap() = definitions.users;
+    *shared.tokens.write().unwrap() = definitions.tokens;
+    *shared.secrets.write().unwrap() = definitions.secrets;
+
+    // passwords may have changed (login sessions are fine, they look up their user every request)
+    shared.sessions.write().unwrap().clear();
+
+    let summary = summary(shared);
+
+    info!("Reloaded configuration: {} tasks, {} users, {} tokens, {} secrets", summary.tasks, summary.users, summary.tokens, summary.secrets);
+
+    Ok(summary)
+}
+
+/// For reloads that nobody is waiting on (errors only end up in the log)
+async fn reload_and_log(shared: &Arc<Shared>) {
+    if let Err(err) = reload(shared).await {
+        error!("Error reloading configuration (keeping previous definitions): {}", err);
+    }
+}
+
+/// Reload whenever the process receives SIGHUP (never completes)
+#[cfg(unix)]
+pub async fn reload_on_signal(shared: Arc<Shared>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    match signal(SignalKind::hangup()) {
+        Ok(mut hangup) => {
+            while hangup.recv().await.is_some() {
+                info!("Received SIGHUP, reloading configuration");
+                reload_and_log(&shared).await;
+            }
+        }
+        Err(err) => {
+            error!("Error installing SIGHUP handler (reload on signal disabled): {}", err);
+        }
+    }
+
+    futures::future::pending::<()>().await
+}
+
+#[cfg(not(unix))]
+pub async fn reload_on_signal(_shared: Arc<Shared>) {
+    futures::future::pending::<()>().await
+}
+
+/// Reload when the server configuration, any task file or secret is added, removed or modified (never completes)
+pub async fn watch_if(enabled: bool, shared: Arc<Shared>) {
+    if enabled {
+        info!("Watching configuration files for changes");
+
+        let mut last = fingerprint(&shared).await;
+
+        let mut interval = tokio::time::interval(WATCH_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+
+            let current = fingerprint(&shared).await;
+
+            // only try again once something changes (a broken file would otherwise be reported every interval)
+            if current != last {
+                info!("Configuration files changed, reloading");
+                reload_and_log(&shared).await;
+                last = current;
+            }
+        }
+    }
+
+    futures::future::pending::<()>().await
+}
+
+/// Modification times of all configuration files (polling rather than relying on platform notifications)
+async fn fingerprint(shared: &Arc<Shared>) -> Vec<(PathBuf, Option<SystemTime>)> {
+    let shared = shared.clone();
+
+    tokio::task::spawn_blocking(move || fingerprint_blocking(&shared)).await.unwrap_or_else(|err| {
+        error!("Error checking configuration files: {}", err);
+        vec![]
+    })
+}
+
+fn fingerprint_blocking(shared: &Shared) -> Vec<(PathBuf, Option<SystemTime>)> {
+    let task_dir = shared.task_dir.read().unwrap().clone();
+
+    let mut paths = vec![shared.config.config.clone()];
+
+    match find_task_files(&task_dir) {
+        Ok(task_files) => paths.extend(task_files),
+        Err(err) => {
+            debug!("Error finding task files: {}", err); // reported when reloading
+        }
+    }
+
+    paths.extend(shared.secrets.read().unwrap().paths());
+
+    paths.sort();
+
+    paths.into_iter()
+        .map(|path| {
+            let modified = std::fs::metadata(&path).and_then(|metadata| metadata.modified()).ok();
+            (path, modified)
+        })
+        .collect()
+}
@@ -22,6 +22,99 @@ export function getTask(name) {
     }).then(handleJsonResponse);
 }
 
+/**
+ * @returns {Promise<any>}
+ */
+export function getSession() {
+    return fetch('/api/session', {
+        method: 'GET',
+        headers: {
+            'Accept': 'application/json'
+        }
+    }).then(handleJsonResponse);
+}
+
+/**
+ * @returns {Promise<any>}
+ */
+export function getRuns() {
+    return fetch('/api/runs', {
+        method: 'GET',
+        headers: {
+            'Accept': 'application/json'
+        }
+    }).then(handleJsonResponse);
+}
+
+/**
+ * @param query {URLSearchParams} filters ('task', 'user' and 'outcome') and page ('page' and 'per_page')
+ * @returns {Promise<any>} page of runs, along with the total matching
+ */
+export function getHistory(query) {
+    return fetch(`/api/history?${query}`, {
+        method: 'GET',
+        headers: {
+            'Accept': 'application/json'
+        }
+    }).then(handleJsonResponse);
+}
+
+/**
+ * @returns {Promise<any>}
+ */
+export function getRun(id) {
+    return fetch(`/api/runs/${id}`, {
+        method: 'GET',
+        headers: {
+            'Accept': 'application/json'
+        }
+    }).then(handleJsonResponse);
+}
+
+/**
+ * Start a run that carries on without anyone watching its output (see the run page for that)
+ *
+ * @param params {URLSearchParams}
+ * @returns {Promise<any>} run just started (or queued)
+ */
+export function startRun(name, method, params, session) {
+    let headers = {
+        'Accept': 'application/json',
+        'Prefer': 'respond-async',
+        ...(session.csrf_token
+            ? {'X-CSRF-Token': session.csrf_token}
+            : {})
+    };
+
+    if (method === 'POST') {
+        return fetch(`/api/tasks/${name}/run`, {
+            method,
+            headers: {...headers, 'Content-Type': 'application/x-www-form-urlencoded'},
+            body: params
+        }).then(handleJsonResponse);
+    } else {
+        return fetch(`/api/tasks/${name}/run?${params}`, {
+            method,
+            headers
+        }).then(handleJsonResponse);
+    }
+}
+
+/**
+ * @returns {Promise<any>} final status of the run
+ */
+export function cancelRun(id, session) {
+    return fetch(`/api/runs/${id}/cancel`, {
+        method: 'POST',
+        headers: {
+            'Accept': 'application/json',
+            ...(session.csrf_token
+                ? {'X-CSRF-Token': session.csrf_token}
+                : {})
+        }
+    }).then(handleJsonResponse);
+}
+
 /**
  * @param response {Response}
  * @returns {Promise<any>}
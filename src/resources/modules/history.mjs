@@ -0,0 +1,96 @@
+import {fatalError, getElapsed, getRunStatus, registerOnLoad, throwError} from "./utils";
+import {getHistory, getTasks} from "./api";
+import * as html from "./html";
+import {showSession} from "./session";
+
+const FILTERS = ['task', 'user', 'outcome'];
+
+function renderParams(params) {
+    return Object.keys(params)
+        .sort()
+        .map(name => `${name}=${params[name]}`)
+        .join(', ');
+}
+
+function renderRun(run) {
+    let status = getRunStatus(run);
+
+    return html.tr([
+        html.td(html.a(new Date(run.started_at).toLocaleString(), {href: `/web/runs/${run.id}`})),
+        html.td(html.code(html.a(run.task, {href: `/web/tasks/${run.task}`}))),
+        html.td(html.span(status.text, {'class': `badge ${status.className}`})),
+        html.td(run.user),
+        html.td(run.queue_position ? '' : getElapsed(run)),
+        html.td(html.code(renderParams(run.params)))
+    ]);
+}
+
+/**
+ * Links to the previous and next pages, keeping the same filters
+ */
+function renderPages(history, query) {
+    let pages = Math.max(1, Math.ceil(history.total / history.per_page));
+
+    function pageLink(text, page) {
+        let pageQuery = new URLSearchParams(query);
+        pageQuery.set('page', `${page}`);
+        return html.a(text, {href: `/web/history?${pageQuery}`});
+    }
+
+    let children = [];
+    if (history.page > 1) {
+        children.push(pageLink('Previous', history.page - 1), ' ');
+    }
+    children.push(`Page ${history.page} of ${pages} (${history.total} runs)`);
+    if (history.page < pages) {
+        children.push(' ', pageLink('Next', history.page + 1));
+    }
+    return children;
+}
+
+async function onLoad() {
+    try {
+        let query = new URLSearchParams(window.location.search);
+
+        await showSession();
+
+        let selectTask = document.getElementById('filter-task') || throwError(`Element not found`);
+        (await getTasks()).forEach(task => {
+            selectTask.appendChild(html.option(task.name, {value: task.name}));
+        });
+
+        // show the filters being used
+        FILTERS.forEach(name => {
+            let input = document.getElementById(`filter-${name}`) || throwError(`Element not found`);
+            input.value = query.get(name) || '';
+        });
+
+        if (query.get('task')) {
+            document.title = `/history/${query.get('task')}`;
+        }
+
+        let history = await getHistory(query);
+
+        let tableRuns = document.getElementById('history-runs') || throwError(`Element not found`);
+
+        if (history.runs.length) {
+            tableRuns.appendChild(html.tr(
+                ['Started', 'Task', 'Status', 'User', 'Duration', 'Parameters'].map(heading => html.th(heading))));
+            history.runs.forEach(run => {
+                tableRuns.appendChild(renderRun(run));
+            });
+        } else {
+            tableRuns.appendChild(html.tr(html.td(html.i('No runs found'))));
+        }
+
+        let pPages = document.getElementById('history-pages') || throwError(`Element not found`);
+        renderPages(history, query).forEach(child => {
+            pPages.appendChild(typeof child === 'string' ? html.text(child) : child);
+        });
+
+    } catch (err) {
+        fatalError(err);
+    }
+}
+
+registerOnLoad(onLoad);
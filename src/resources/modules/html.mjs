@@ -74,6 +74,10 @@ export function td(children, attributes) {
     return element('td', children, attributes);
 }
 
+export function th(children, attributes) {
+    return element('th', children, attributes);
+}
+
 export function label(children, attributes) {
     return element('label', children, attributes);
 }
@@ -89,3 +93,15 @@ export function select(children, attributes) {
 export function option(children, attributes) {
     return element('option', children, attributes);
 }
+
+export function form(children, attributes) {
+    return element('form', children, attributes);
+}
+
+export function button(children, attributes) {
+    return element('button', children, attributes);
+}
+
+export function span(children, attributes) {
+    return element('span', children, attributes);
+}
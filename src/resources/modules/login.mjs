@@ -0,0 +1,16 @@
+import {registerOnLoad, throwError} from "./utils";
+
+function onLoad() {
+    let params = new URLSearchParams(window.location.search);
+
+    let inputRedirect = document.getElementById('redirect') || throwError(`Element not found`);
+
+    inputRedirect.value = params.get('redirect') || '';
+
+    if (params.has('error')) {
+        let pError = document.getElementById('login-error') || throwError(`Element not found`);
+        pError.hidden = false;
+    }
+}
+
+registerOnLoad(onLoad);
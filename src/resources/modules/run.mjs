@@ -0,0 +1,149 @@
+import {fatalError, getElapsed, getRunStatus, registerOnLoad, throwError} from "./utils";
+import {cancelRun, getRun} from "./api";
+import * as html from "./html";
+import {showSession} from "./session";
+
+/** How often to check on a run that hasn't finished */
+const REFRESH_INTERVAL_MS = 1000;
+
+/** How close to the end of the output counts as being at the end (to keep following it) */
+const SCROLL_SLACK_PX = 24;
+
+const URL_PATTERN = new RegExp('^/web/runs/([^/]+)$');
+
+function getRunId(location) {
+    let match = URL_PATTERN.exec(location.pathname);
+    if (!match) {
+        throw new Error(`Unexpected URL path: ${location.pathname}`);
+    }
+    return match[1];
+}
+
+function renderRun(run) {
+    let status = getRunStatus(run);
+
+    let spanStatus = document.getElementById('run-status') || throwError(`Element not found`);
+    spanStatus.innerText = status.text;
+    spanStatus.className = `badge ${status.className}`;
+
+    let tdStarted = document.getElementById('run-started') || throwError(`Element not found`);
+    tdStarted.innerText = run.queue_position ? '' : new Date(run.started_at).toLocaleString();
+
+    let tdElapsed = document.getElementById('run-elapsed') || throwError(`Element not found`);
+    tdElapsed.innerText = run.queue_position ? '' : getElapsed(run);
+
+    let buttonCancel = document.getElementById('run-cancel') || throwError(`Element not found`);
+    buttonCancel.hidden = !!run.finished_at;
+}
+
+function renderParams(run) {
+    let tableParams = document.getElementById('run-params') || throwError(`Element not found`);
+
+    let names = Object.keys(run.params).sort();
+
+    if (names.length) {
+        names.forEach(name => {
+            tableParams.appendChild(
+                html.tr([
+                    html.td(html.code(name)),
+                    html.td(html.code(run.params[name]))
+                ]));
+        });
+    } else {
+        tableParams.appendChild(html.tr(html.td(html.i('None'))));
+    }
+}
+
+/** Query for running it again, leaving out secrets (only their redacted values are known) */
+function againParams(run) {
+    let params = new URLSearchParams(run.params);
+    (run.secret_params || []).forEach(name => params.delete(name));
+    return params;
+}
+
+/**
+ * Keep the end of the output in view while it's being written (unless the user has scrolled up
+ * to read something)
+ */
+function followOutput(iframe) {
+    let lastHeight = 0;
+
+    return () => {
+        let scrolling = iframe.contentDocument?.scrollingElement;
+        if (!scrolling) {
+            return;
+        }
+        let wasAtEnd = scrolling.scrollTop + scrolling.clientHeight >= lastHeight - SCROLL_SLACK_PX;
+        if (wasAtEnd) {
+            scrolling.scrollTop = scrolling.scrollHeight;
+        }
+        lastHeight = scrolling.scrollHeight;
+    };
+}
+
+async function onLoad() {
+    try {
+        let id = getRunId(window.location);
+
+        let session = await showSession();
+
+        let run = await getRun(id);
+
+        document.title = `/runs/${run.task}`;
+
+        let codeTask = document.getElementById('run-task') || throwError(`Element not found`);
+        codeTask.appendChild(html.a(run.task, {href: `/web/tasks/${run.task}`}));
+
+        let tdUser = document.getElementById('run-user') || throwError(`Element not found`);
+        tdUser.innerText = run.user;
+
+        let aAgain = document.getElementById('run-again') || throwError(`Element not found`);
+        aAgain.href = `/web/tasks/${run.task}?${againParams(run)}`;
+
+        renderParams(run);
+        renderRun(run);
+
+        let buttonCancel = document.getElementById('run-cancel') || throwError(`Element not found`);
+        buttonCancel.addEventListener('click', async () => {
+            if (!confirm(`Cancel run of ${run.task}?`)) {
+                return;
+            }
+            buttonCancel.disabled = true;
+            try {
+                renderRun(await cancelRun(id, session));
+            } catch (err) {
+                fatalError(err);
+            }
+        });
+
+        // output is rendered by the server (as HTML, with colours), following the run until it ends
+        let iframe = document.getElementById('run-output') || throwError(`Element not found`);
+        iframe.src = `/api/runs/${id}/attach`;
+
+        if (run.finished_at) {
+            return;
+        }
+
+        let follow = followOutput(iframe);
+
+        let timer = setInterval(async () => {
+            follow();
+            try {
+                run = await getRun(id);
+                renderRun(run);
+                if (run.finished_at) {
+                    clearInterval(timer);
+                    setTimeout(follow, REFRESH_INTERVAL_MS); // (last of the output)
+                }
+            } catch (err) {
+                clearInterval(timer);
+                fatalError(err);
+            }
+        }, REFRESH_INTERVAL_MS);
+
+    } catch (err) {
+        fatalError(err);
+    }
+}
+
+registerOnLoad(onLoad);
@@ -0,0 +1,52 @@
+import {getSession} from "./api";
+import * as html from "./html";
+
+/**
+ * Hidden form field required when POSTing forms as a user logged in via the login page
+ *
+ * @returns {HTMLElement|null}
+ */
+export function csrfInput(session) {
+    if (session.csrf_token) {
+        return html.input([], {type: 'hidden', name: '_csrf', value: session.csrf_token});
+    } else {
+        return null; // not logged in with a session cookie (nothing to protect)
+    }
+}
+
+function renderSession(session) {
+    if (session.guest) {
+        let href = `/web/login?redirect=${encodeURIComponent(window.location.pathname)}`;
+        return html.p(['Browsing as guest ', html.a('Log in', {href})]);
+    }
+
+    let children = [
+        'Logged in as ',
+        html.b(session.username)
+    ];
+
+    let input = csrfInput(session);
+    if (input) {
+        children.push(
+            html.form([
+                input,
+                html.button('Log out', {type: 'submit'})
+            ], {method: 'POST', action: '/api/logout'}));
+    }
+
+    return html.p(children);
+}
+
+/**
+ * @returns {Promise<any>} current session (to save fetching it again)
+ */
+export async function showSession() {
+    let session = await getSession();
+
+    let navSession = document.getElementById('session');
+    if (navSession) {
+        navSession.appendChild(renderSession(session));
+    }
+
+    return session;
+}
@@ -1,12 +1,14 @@
 import {registerOnLoad, throwError, UnhandledCaseError} from "./utils";
-import {getTask} from "./api";
+import {getTask, startRun} from "./api";
 import * as html from "./html";
 import {fatalError} from "./utils";
+import {csrfInput, showSession} from "./session";
 
 /**
+ * @param value {string|null} from a previous run (see 'Run again' on the run page), if any
  * @returns {HTMLElement}
  */
-function renderInput(parameter) {
+function renderInput(parameter, value) {
     let inputId = `parameter_${parameter.name}`;
 
     let attributes = {
@@ -15,11 +17,25 @@ function renderInput(parameter) {
         ...(parameter.required === true
             ? {required: true}
             : {}),
-        value: parameter.default || ''
+        value: value ?? (parameter.default || '')
+    }
+
+    if (parameter.type === 'boolean' && value === 'true') {
+        attributes.checked = true;
+    }
+
+    if (parameter.type === 'secret') {
+        // never filled in (previous runs only have '***'), and left out when empty so that any
+        // default (which isn't sent to browsers) applies
+        attributes.value = '';
+        delete attributes.required;
+        attributes.autocomplete = 'off';
+        return html.input([], {...attributes, type: 'password'});
     }
 
     if ((parameter.enum || []).length) {
-        return html.select(parameter.enum.map(value => html.option(`${value}`)), {...attributes});
+        return html.select(parameter.enum.map(option =>
+            html.option(`${option}`, `${option}` === `${attributes.value}` ? {selected: true} : {})), {...attributes});
     }
 
     switch (parameter.type) {
@@ -34,6 +50,93 @@ function renderInput(parameter) {
     }
 }
 
+/**
+ * Parameters from the form, without secrets that weren't filled in
+ * @returns {URLSearchParams}
+ */
+function formParams(form) {
+    let params = new URLSearchParams(new FormData(form));
+    form.querySelectorAll('input[type=password]').forEach(input => {
+        if (!input.value) {
+            params.delete(input.name);
+        }
+    });
+    return params;
+}
+
+/**
+ * Run the task over a WebSocket, showing its output and sending whatever is typed as its input
+ * (only for tasks that accept input, others are run by submitting the form as usual)
+ */
+function runInTerminal(name, form, session) {
+    let section = document.getElementById('terminal') || throwError(`Element not found`);
+    let output = document.getElementById('terminal-output') || throwError(`Element not found`);
+    let terminalForm = document.getElementById('terminal-form') || throwError(`Element not found`);
+    let input = document.getElementById('terminal-input') || throwError(`Element not found`);
+    let send = document.getElementById('terminal-send') || throwError(`Element not found`);
+    let eof = document.getElementById('terminal-eof') || throwError(`Element not found`);
+
+    let query = formParams(form);
+    if (session.csrf_token) {
+        query.set('_csrf', session.csrf_token); // can't send headers with a WebSocket
+    }
+
+    let scheme = window.location.protocol === 'https:' ? 'wss:' : 'ws:';
+    let socket = new WebSocket(`${scheme}//${window.location.host}/api/tasks/${encodeURIComponent(name)}/ws?${query}`);
+
+    output.replaceChildren();
+    section.hidden = false;
+
+    function print(line, className) {
+        output.appendChild(html.span(`${line}\n`, className ? {'class': className} : {}));
+        output.scrollTop = output.scrollHeight;
+    }
+
+    function setInputEnabled(enabled) {
+        [input, send, eof].forEach(el => el.disabled = !enabled);
+    }
+
+    socket.onopen = () => {
+        setInputEnabled(true);
+        input.focus();
+    };
+
+    socket.onmessage = message => {
+        let event = JSON.parse(message.data);
+        switch (event.type) {
+            case 'queued':
+                print(`[Queued: position ${event.position}]`);
+                break;
+            case 'started':
+                break;
+            case 'output':
+                print(event.line, event.stream);
+                break;
+            case 'exit':
+                print(event.exit_code !== null ? `[Exit code: ${event.exit_code}]` : '[Exited]');
+                break;
+            default:
+                throw new UnhandledCaseError(event.type);
+        }
+    };
+
+    socket.onclose = () => {
+        setInputEnabled(false);
+    };
+
+    terminalForm.onsubmit = event => {
+        event.preventDefault();
+        socket.send(JSON.stringify({type: 'input', data: `${input.value}\n`}));
+        print(input.value, 'input');
+        input.value = '';
+    };
+
+    eof.onclick = () => {
+        socket.send(JSON.stringify({type: 'eof'}));
+        setInputEnabled(false);
+    };
+}
+
 const URL_PATTERN = new RegExp('^/web/tasks/([^/]+)$');
 
 function getTaskName(location) {
@@ -44,10 +147,34 @@ function getTaskName(location) {
     return match[1];
 }
 
+/**
+ * Start the run in the background and follow it on its own page (submitting the form without
+ * scripts still works, showing the output directly)
+ */
+async function submitRun(name, form, session) {
+    let params = formParams(form);
+    params.delete('_csrf'); // sent as a header
+
+    try {
+        let run = await startRun(name, form.method.toUpperCase(), params, session);
+        window.location.href = `/web/runs/${run.id}`;
+    } catch (err) {
+        if (err.status === 409) {
+            alert('Task cannot run right now (it may already be running, or waiting on a lock held by another run)');
+        } else {
+            fatalError(err);
+        }
+    }
+}
+
 async function onLoad() {
     try {
         let name = getTaskName(window.location);
 
+        let previousParams = new URLSearchParams(window.location.search);
+
+        let session = await showSession();
+
         let taskJson = await getTask(name);
 
         let tableParameters = document.getElementById('parameters-table') || throwError(`Element not found`);
@@ -60,7 +187,7 @@ async function onLoad() {
                         html.td(
                             html.label(parameter.name),
                             {'for': inputId}),
-                        html.td(renderInput(parameter))
+                        html.td(renderInput(parameter, previousParams.get(parameter.name)))
                     ]));
             });
         } else {
@@ -81,10 +208,29 @@ async function onLoad() {
 
         formParameters.action = `/api/tasks/${name}/run`;
 
+        if (formParameters.method.toUpperCase() === 'POST') {
+            let input = csrfInput(session);
+            if (input) {
+                formParameters.appendChild(input);
+            }
+        }
+
+        formParameters.onsubmit = event => {
+            event.preventDefault();
+            if (taskJson.stdin) {
+                runInTerminal(name, formParameters, session);
+            } else {
+                submitRun(name, formParameters, session);
+            }
+        };
+
         let codeTaskName = document.getElementById('task-name') || throwError(`Element not found`);
 
         codeTaskName.innerText = name;
 
+        let aHistory = document.getElementById('task-history') || throwError(`Element not found`);
+        aHistory.href = `/web/history?${new URLSearchParams({task: name})}`;
+
     } catch (err) {
         fatalError(err);
     }
@@ -1,6 +1,7 @@
-import {registerOnLoad, throwError} from "./utils";
-import {getTasks} from "./api";
+import {fatalError, registerOnLoad, throwError} from "./utils";
+import {cancelRun, getRuns, getTasks} from "./api";
 import * as html from "./html";
+import {showSession} from "./session";
 
 function renderTask(task) {
     let href = `/web/tasks/${task.name}`;
@@ -12,9 +13,55 @@ function renderTask(task) {
     ]);
 }
 
+function renderRun(run, session) {
+    let buttonCancel = html.button('Cancel', {type: 'button'});
+
+    buttonCancel.addEventListener('click', async () => {
+        if (!confirm(`Cancel run of ${run.task} started by ${run.user}?`)) {
+            return;
+        }
+        buttonCancel.disabled = true;
+        try {
+            await cancelRun(run.id, session);
+            await showRuns(session);
+        } catch (err) {
+            fatalError(err);
+        }
+    });
+
+    let status = run.queue_position
+        ? ` queued by ${run.user} (position ${run.queue_position}) `
+        : ` started by ${run.user} at ${new Date(run.started_at).toLocaleString()} `;
+
+    return html.li([
+        html.code(html.a(run.task, {href: `/web/runs/${run.id}`})),
+        status,
+        buttonCancel
+    ]);
+}
+
+/**
+ * Runs that haven't finished yet (so they can be cancelled)
+ */
+async function showRuns(session) {
+    let sectionRunning = document.getElementById('running') || throwError(`Element not found`);
+    let ulRuns = document.getElementById('runs') || throwError(`Element not found`);
+
+    let runsJson = (await getRuns()).filter(run => !run.finished_at);
+
+    ulRuns.innerText = '';
+    runsJson.forEach(run => {
+        ulRuns.appendChild(renderRun(run, session));
+    });
+
+    sectionRunning.hidden = !runsJson.length;
+}
+
 async function onLoad() {
     let ulTasks = document.getElementById('tasks') || throwError(`Element not found`);
 
+    let session = await showSession();
+
     let tasksJson = await getTasks();
 
     if (tasksJson.length) {
@@ -24,6 +71,8 @@ async function onLoad() {
     } else {
         ulTasks.appendChild(html.li([html.i("No tasks found")]));
     }
+
+    await showRuns(session);
 }
 
 registerOnLoad(onLoad);
@@ -44,6 +44,56 @@ export function fatalError(err) {
     document.body.appendChild(html.h1(getMessage()));
 }
 
+/**
+ * @param ms {number}
+ * @returns {string} eg, '1h 2m 3s'
+ */
+export function formatDuration(ms) {
+    let secs = Math.max(0, Math.floor(ms / 1000));
+    let parts = [];
+    if (secs >= 3600) {
+        parts.push(`${Math.floor(secs / 3600)}h`);
+    }
+    if (secs >= 60) {
+        parts.push(`${Math.floor(secs / 60) % 60}m`);
+    }
+    parts.push(`${secs % 60}s`);
+    return parts.join(' ');
+}
+
+/**
+ * Short description of how a run is going (or how it ended), with a class for its badge
+ *
+ * @returns {{text: string, className: string}}
+ */
+export function getRunStatus(run) {
+    if (run.queue_position) {
+        return {text: `Queued (position ${run.queue_position})`, className: 'queued'};
+    } else if (!run.finished_at) {
+        return {text: 'Running', className: 'running'};
+    } else if (run.cancelled_by) {
+        return {text: `Cancelled by ${run.cancelled_by}`, className: 'failure'};
+    } else if (run.timed_out_after_secs) {
+        return {text: `Timed out after ${formatDuration(run.timed_out_after_secs * 1000)}`, className: 'failure'};
+    } else if (run.exit_code === 0) {
+        return {text: 'Succeeded', className: 'success'};
+    } else if (run.exit_code !== undefined) {
+        return {text: `Failed (exit code ${run.exit_code})`, className: 'failure'};
+    } else if (run.signal !== undefined) {
+        return {text: `Killed (signal ${run.signal})`, className: 'failure'};
+    } else {
+        return {text: 'Unknown', className: 'failure'};
+    }
+}
+
+/**
+ * @returns {string} how long the run took (or has been going)
+ */
+export function getElapsed(run) {
+    let finished = run.finished_at ? new Date(run.finished_at) : new Date();
+    return formatDuration(finished - new Date(run.started_at));
+}
+
 export class UnhandledCaseError extends Error {
     constructor(value) {
         super(`Unhandled case: ${value}`);
@@ -0,0 +1,763 @@
+use std::collections::{HashMap, VecDeque};
+use std::ffi::OsString;
+use std::future::Future;
+use std::pin::Pin;
+use std::process::ExitStatus;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+use futures::stream::{Stream, StreamExt};
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::Command;
+use tokio::sync::{mpsc, oneshot, watch, Notify};
+use tokio_stream::wrappers::LinesStream;
+
+use crate::{Shared, TaskExec};
+use crate::history::{History, OutputStream, RunOutput, RunRecord};
+use crate::process::RunProcess;
+use crate::secret::Secrets;
+use crate::server::{ServerError, UserPrincipal};
+use crate::slots::{Admission, BlockReason, Blocker, Progress, Requirements, SlotGuard, Ticket};
+
+/// Lines of output kept in memory for anyone attaching to a run late (the rest is only in the
+/// history, if there is one)
+const MAX_BUFFERED_LINES: usize = 10_000;
+
+/// Lines of process output tagged with which stream they came from
+type ProcessLine = (OutputStream, Result<String, std::io::Error>);
+
+/// Data for the process's standard input (closing the channel closes the input). Only used by
+/// tasks that accept input, which otherwise get none at all.
+pub type Input = mpsc::Receiver<Vec<u8>>;
+
+/// A run whose process is still executing (runs are removed from `Shared.runs` once finished)
+pub struct ActiveRun {
+    state: Mutex<RunState>,
+    updated: watch::Sender<()>,
+    cancelled: Notify,
+}
+
+struct RunState {
+    record: RunRecord,
+    /// Set once the process has been spawned
+    pid: Option<u32>,
+    /// The most recent lines (up to 'MAX_BUFFERED_LINES')
+    output: VecDeque<OutputLine>,
+    /// Lines dropped from the start of 'output'
+    dropped: usize,
+    finished: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct OutputLine {
+    /// Position in the output, starting from zero (the same as the offset when attaching)
+    pub seq: usize,
+    pub stream: OutputStream,
+    /// When the line was read (not known for runs replayed from history)
+    pub time: Option<DateTime<Utc>>,
+    /// Including the line ending
+    pub text: String,
+}
+
+/// Something that happened during a run, for anyone following it
+#[derive(Debug, Clone)]
+pub enum RunEvent {
+    /// Waiting to start (at the given place in the queue)
+    Queued(usize),
+    Started { run_id: String, pid: Option<u32> },
+    Output(OutputLine),
+    Finished(RunRecord),
+}
+
+/// Kills the process of an attached run when dropped (ie, when the client that started it goes away)
+pub struct AttachGuard {
+    _kill: oneshot::Sender<()>,
+}
+
+impl ActiveRun {
+    pub fn record(&self) -> RunRecord {
+        self.state.lock().unwrap().record.clone()
+    }
+
+    fn set_queue_position(&self, position: usize) {
+        self.state.lock().unwrap().record.queue_position = Some(position);
+        self.updated.send_replace(());
+    }
+
+    /// Left the queue, and the process is about to be spawned
+    fn set_started(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.record.started_at = Utc::now();
+        state.record.queue_position = None;
+    }
+
+    fn set_pid(&self, pid: Option<u32>) {
+        self.state.lock().unwrap().pid = pid;
+        self.updated.send_replace(());
+    }
+
+    fn push_output(&self, stream: OutputStream, text: String) {
+        let mut state = self.state.lock().unwrap();
+        let seq = state.dropped + state.output.len();
+        state.output.push_back(OutputLine { seq, stream, time: Some(Utc::now()), text });
+        if state.output.len() > MAX_BUFFERED_LINES {
+            state.output.pop_front();
+            state.dropped += 1;
+        }
+        drop(state);
+        self.updated.send_replace(());
+    }
+
+    fn complete(&self, exit_status: Option<ExitStatus>, timed_out_after: Option<Duration>) -> RunRecord {
+        let mut state = self.state.lock().unwrap();
+        state.record.finished_at = Some(Utc::now());
+        state.record.exit_code = exit_status.and_then(|status| status.code());
+        state.record.signal = exit_status.as_ref().and_then(crate::process::exit_signal);
+        state.record.timed_out_after_secs = timed_out_after.map(|x| x.as_secs());
+        state.record.queue_position = None;
+        state.record.clone()
+    }
+
+    /// Last lines of output (from both streams)
+    fn output_tail(&self, lines: usize) -> String {
+        let state = self.state.lock().unwrap();
+        let start = state.output.len().saturating_sub(lines);
+        state.output.iter().skip(start).map(|line| line.text.as_str()).collect()
+    }
+
+    /// Ask the driver to stop the process (returns false if the run has already finished or been cancelled)
+    pub fn cancel(&self, username: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        if state.finished || state.record.cancelled_by.is_some() {
+            return false;
+        }
+
+        state.record.cancelled_by = Some(username.to_owned());
+        self.cancelled.notify_one(); // remembered if the driver isn't waiting right now
+
+        true
+    }
+
+    /// Wait for the process to exit, returning the final record
+    pub async fn finished(&self) -> RunRecord {
+        let mut updated = self.updated.subscribe(); // subscribe before checking so no updates are missed
+
+        loop {
+            {
+                let state = self.state.lock().unwrap();
+                if state.finished {
+                    return state.record.clone();
+                }
+            }
+
+            if updated.changed().await.is_err() {
+                return self.record(); // not expected (we're holding a reference to the sender)
+            }
+        }
+    }
+
+    /// Let anyone attached know there's no more output coming
+    fn mark_finished(&self) {
+        self.state.lock().unwrap().finished = true;
+        self.updated.send_replace(());
+    }
+}
+
+pub fn exit_trailer(exit_code: Option<i32>) -> String {
+    format!("[Exit code: {}]",
+            exit_code
+                .map(|x| x.to_string())
+                .unwrap_or("<unknown>".to_owned()))
+}
+
+/// Status line while a run is waiting to start
+pub fn queue_status(position: usize) -> String {
+    format!("[Queued: position {}]\n", position)
+}
+
+/// Last line of output for a finished run
+pub fn trailer(record: &RunRecord) -> String {
+    if let Some(username) = &record.cancelled_by {
+        format!("[Cancelled by {}]", username)
+    } else if let Some(secs) = record.timed_out_after_secs {
+        format!("[Timed out after {}]", crate::utils::format_duration(Duration::from_secs(secs)))
+    } else {
+        exit_trailer(record.exit_code)
+    }
+}
+
+/// Start executing a task (or queue it, if it has to wait for other runs). Unless `detach` is set,
+/// the returned guard must be kept for as long as somebody is watching the output (if nobody is
+/// around to see output process shouldn't keep running).
+pub async fn start(shared: Arc<Shared>,
+                   task: TaskExec,
+                   user: &UserPrincipal,
+                   detach: bool,
+                   input: Option<Input>) -> Result<(Arc<ActiveRun>, Option<AttachGuard>), ServerError> {
+    info!("Executing: {:?}", task); // (with secrets redacted)
+
+    let run_id = uuid::Uuid::new_v4().to_string();
+
+    let requirements = Requirements {
+        task: task.name.clone(),
+        concurrency: task.concurrency,
+        lock: task.lock.clone(),
+    };
+
+    let admission = match shared.slots.admit(&run_id, requirements, task.queue) {
+        Admission::Conflict(blocker) => {
+            return Err(ServerError::Conflict(describe_blocker(&shared, &blocker)));
+        }
+        admission => admission
+    };
+
+    let mut secret_params: Vec<String> = task.secret_params.iter().cloned().collect();
+    secret_params.sort();
+
+    let mut record = RunRecord {
+        id: run_id.clone(),
+        task: task.name.clone(),
+        username: user.username.clone(),
+        params: crate::secret::redact_params(&task.params, &task.secret_params),
+        secret_params,
+        started_at: Utc::now(),
+        finished_at: None,
+        exit_code: None,
+        signal: None,
+        timed_out_after_secs: None,
+        cancelled_by: None,
+        queue_position: None,
+    };
+
+    // create history entry before starting process (don't want to run anything we can't record)
+    let recorder = match &shared.history {
+        Some(history) => {
+            let output = history.create(&record).await.map_err(|err| {
+                error!("Error creating run history: {}", err);
+                ServerError::InternalServerError
+            })?;
+            Some((history.clone(), output))
+        }
+        None => None
+    };
+
+    if let Admission::Queued(ticket) = &admission {
+        record.queue_position = Some(ticket.position());
+    }
+
+    let run = Arc::new(ActiveRun {
+        state: Mutex::new(RunState {
+            record,
+            pid: None,
+            output: VecDeque::new(),
+            dropped: 0,
+            finished: false,
+        }),
+        updated: watch::channel(()).0,
+        cancelled: Notify::new(),
+    });
+
+    let launch = match admission {
+        Admission::Started(slot) => {
+            let spawned = spawn(&task, &shared.secrets.read().unwrap(), input);
+
+            match spawned {
+                Ok((process, output)) => Launch::Spawned(slot, process, output),
+                Err(err) => {
+                    error!("Error executing command: {:?}", err);
+                    drop(slot);
+                    finish_failed_start(&shared, &run, &task, recorder).await;
+                    return Err(ServerError::InternalServerError);
+                }
+            }
+        }
+        Admission::Queued(ticket) => {
+            info!("Run is queued at position {}: {}", ticket.position(), run_id);
+            Launch::Queued(ticket, input)
+        }
+        Admission::Conflict(_) => unreachable!() // returned above
+    };
+
+    let (guard, kill_rx) = if detach {
+        (None, None)
+    } else {
+        let (kill_tx, kill_rx) = oneshot::channel::<()>();
+        (Some(AttachGuard { _kill: kill_tx }), Some(kill_rx))
+    };
+
+    shared.runs.write().unwrap().insert(run_id, run.clone());
+
+    tokio::spawn(execute(shared, run.clone(), task, launch, kill_rx, recorder));
+
+    Ok((run, guard))
+}
+
+/// Finish a run whose process couldn't be spawned like any other failed run (recorded, with
+/// anyone attached and notifications told), whether or not it was queued first
+async fn finish_failed_start(shared: &Shared, run: &ActiveRun, task: &TaskExec, mut recorder: Option<(History, RunOutput)>) {
+    record_output(run, &mut recorder, OutputStream::Stderr, "Error executing command\n".to_owned()).await;
+    finish(shared, run, task, None, None, recorder).await;
+}
+
+/// Message for a client whose run can't start because of another run
+fn describe_blocker(shared: &Shared, blocker: &Blocker) -> String {
+    let holder = match shared.runs.read().unwrap().get(&blocker.run_id) {
+        Some(run) => {
+            let record = run.record();
+            format!("run {} of task {} (started by {} at {})",
+                    record.id, record.task, record.username, record.started_at.to_rfc3339())
+        }
+        None => format!("run {}", blocker.run_id) // only just started
+    };
+
+    match &blocker.reason {
+        BlockReason::Lock { lock } => {
+            format!("Lock {} is held by {}", lock, holder)
+        }
+        BlockReason::Concurrency { task, limit } => {
+            format!("Task {} is limited to {} run(s) at once, and is already executing {}", task, limit, holder)
+        }
+    }
+}
+
+type OutputLines = Pin<Box<dyn Stream<Item=ProcessLine> + Send>>;
+
+/// How the driver of a run gets going
+enum Launch {
+    Spawned(SlotGuard, RunProcess, OutputLines),
+    /// Process is spawned once it's the run's turn
+    Queued(Ticket, Option<Input>),
+}
+
+fn spawn(task: &TaskExec, secrets: &Secrets, input: Option<Input>) -> Result<(RunProcess, OutputLines), std::io::Error> {
+    let args: Vec<OsString> = task.args
+        .iter()
+        .map(OsString::from)
+        .collect();
+
+    // looked up only now, so that secrets aren't part of anything logged or kept (checked when
+    // tasks are loaded, only missing if a secret was removed by a reload)
+    let secret_env = task.secret_env.iter()
+        .map(|(env, name)| match secrets.get(name) {
+            Some(value) => Ok((env, value.to_owned())),
+            None => Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("Secret not found: {}", name))),
+        })
+        .collect::<Result<HashMap<_, _>, _>>()?;
+
+    let mut command = Command::new(&task.command);
+
+    // process belongs to the run's driver task rather than the connection, only killed if that task is dropped (eg, on shutdown)
+    command.current_dir(&task.dir)
+        .args(&args)
+        .envs(&task.env)
+        .envs(&secret_env)
+        .kill_on_drop(true) // along with the rest of its process group (see 'RunProcess')
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped());
+
+    let input = input.filter(|_| task.stdin);
+
+    if task.stdin {
+        command.stdin(if input.is_some() { std::process::Stdio::piped() } else { std::process::Stdio::null() });
+    }
+
+    crate::process::configure(&mut command);
+
+    let mut child = command.spawn()?;
+
+    if let (Some(mut input), Some(mut stdin)) = (input, child.stdin.take()) {
+        tokio::spawn(async move {
+            while let Some(data) = input.recv().await {
+                if let Err(err) = stdin.write_all(&data).await {
+                    warn!("Error writing to process input: {}", err); // most likely exited already
+                    break;
+                }
+            }
+            // dropping 'stdin' closes it, so the process sees the end of its input
+        });
+    }
+
+    let stdout = child.stdout.take().unwrap(); // TODO: handle not having both streams
+    let stderr = child.stderr.take().unwrap();
+
+    // not buffering so that all output (however small) is sent through to browser
+    let stdout_stream = LinesStream::new(BufReader::with_capacity(1, stdout).lines())
+        .map(|line| (OutputStream::Stdout, line.map(|l| l + "\n")));
+    let stderr_stream = LinesStream::new(BufReader::with_capacity(1, stderr).lines())
+        .map(|line| (OutputStream::Stderr, line.map(|l| l + "\n")));
+
+    let interleaved = crate::interleave::Interleave::new(
+        stdout_stream.fuse(),
+        stderr_stream.fuse());
+
+    // processes may well print what they're given, secrets shouldn't end up in the output (or history)
+    let mut secret_values = task.secret_values();
+    secret_values.extend(secret_env.into_values());
+    let masked = interleaved.map(move |(stream, line)| (stream, line.map(|l| crate::secret::mask(l, &secret_values))));
+
+    Ok((RunProcess::new(child), Box::pin(masked)))
+}
+
+/// How long a run may take
+#[derive(Clone, Copy)]
+struct Limits {
+    timeout: Option<Duration>,
+    grace_period: Duration,
+}
+
+/// Wait for the run's turn (if queued), then drive the process until it exits
+async fn execute(shared: Arc<Shared>,
+                 run: Arc<ActiveRun>,
+                 task: TaskExec,
+                 launch: Launch,
+                 kill_rx: Option<oneshot::Receiver<()>>,
+                 mut recorder: Option<(History, RunOutput)>) {
+    // detached runs are never killed by a client going away
+    let kill = async move {
+        match kill_rx {
+            Some(rx) => {
+                let _ = rx.await; // only ever 'fails' (the sender is never used, just dropped)
+            }
+            None => futures::future::pending::<()>().await
+        }
+    };
+    futures::pin_mut!(kill);
+
+    let (slot, process, output) = match launch {
+        Launch::Spawned(slot, process, output) => (slot, process, output),
+        Launch::Queued(mut ticket, input) => {
+            let slot = loop {
+                tokio::select! {
+                    progress = ticket.wait() => match progress {
+                        Progress::Started(slot) => break Some(slot),
+                        Progress::Moved(position) => run.set_queue_position(position),
+                    },
+                    _ = &mut kill => {
+                        info!("Connection closed, removing run from queue: {}", run.record().id);
+                        break None;
+                    }
+                    _ = run.cancelled.notified() => {
+                        info!("Run cancelled, removing run from queue: {}", run.record().id);
+                        break None;
+                    }
+                }
+            };
+            drop(ticket); // leaves the queue
+
+            let slot = match slot {
+                Some(slot) => slot,
+                None => {
+                    finish(&shared, &run, &task, None, None, recorder).await;
+                    return;
+                }
+            };
+
+            run.set_started();
+
+            let spawned = spawn(&task, &shared.secrets.read().unwrap(), input);
+
+            match spawned {
+                Ok((process, output)) => (slot, process, output),
+                Err(err) => {
+                    error!("Error executing command: {:?}", err);
+                    drop(slot);
+                    finish_failed_start(&shared, &run, &task, recorder).await;
+                    return;
+                }
+            }
+        }
+    };
+
+    let limits = Limits { timeout: task.timeout, grace_period: task.grace_period };
+
+    run.set_pid(process.pid());
+
+    let (exit_status, timed_out) = drive(&run, process, output, kill, limits, &mut recorder).await;
+
+    drop(slot); // let the next run start as soon as possible
+
+    finish(&shared, &run, &task, exit_status, if timed_out { limits.timeout } else { None }, recorder).await;
+}
+
+async fn record_output(run: &ActiveRun, recorder: &mut Option<(History, RunOutput)>, stream: OutputStream, line: String) {
+    if let Some((_, run_output)) = recorder.as_mut() {
+        if let Err(err) = run_output.write(stream, &line).await {
+            error!("Error writing run output: {}", err);
+        }
+    }
+    run.push_output(stream, line);
+}
+
+/// Drive the process until it has exited (returning its exit status, and whether it timed out)
+async fn drive<K>(run: &ActiveRun,
+                  mut process: RunProcess,
+                  mut output: OutputLines,
+                  mut kill: Pin<&mut K>,
+                  limits: Limits,
+                  recorder: &mut Option<(History, RunOutput)>) -> (Option<ExitStatus>, bool)
+    where K: Future<Output=()> {
+    let timeout = async move {
+        match limits.timeout {
+            Some(timeout) => tokio::time::sleep(timeout).await,
+            None => futures::future::pending::<()>().await
+        }
+    };
+    futures::pin_mut!(timeout);
+
+    // only started once timed out
+    let grace_period = tokio::time::sleep(Duration::ZERO);
+    futures::pin_mut!(grace_period);
+
+    let mut exit_status = None;
+    let mut output_closed = false;
+    let mut killed = false;
+    let mut timed_out = false;
+    let mut stopping = false; // asked to stop, and waiting for the grace period
+
+    // finished once the process has exited and the output is closed (output can be held open by
+    // other processes in the group, or be closed by the process before it exits)
+    let exit_status = loop {
+        if output_closed {
+            if let Some(exit_status) = exit_status.take() {
+                break exit_status;
+            }
+        }
+
+        tokio::select! {
+            line = output.next(), if !output_closed => match line {
+                Some((stream, Ok(line))) => {
+                    record_output(run, recorder, stream, line).await;
+                }
+                Some((_, Err(err))) => {
+                    error!("Error reading process output: {}", err);
+                }
+                None => {
+                    output_closed = true; // both streams closed
+                }
+            },
+            status = process.wait(), if exit_status.is_none() => {
+                process.kill_remaining();
+                exit_status = Some(status);
+            }
+            _ = &mut kill, if !killed => {
+                info!("Connection closed, killing process: {}", run.record().id);
+                killed = true;
+                process.kill();
+            }
+            _ = &mut timeout, if !timed_out && !stopping => {
+                info!("Process timed out, asking it to stop: {}", run.record().id);
+                timed_out = true;
+                stopping = true;
+                process.terminate();
+                grace_period.as_mut().reset(tokio::time::Instant::now() + limits.grace_period);
+            }
+            _ = run.cancelled.notified(), if !stopping => {
+                info!("Run cancelled, asking process to stop: {}", run.record().id);
+                stopping = true;
+                process.terminate();
+                grace_period.as_mut().reset(tokio::time::Instant::now() + limits.grace_period);
+            }
+            _ = &mut grace_period, if stopping && !killed => {
+                info!("Process did not stop within grace period, killing process: {}", run.record().id);
+                killed = true;
+                process.kill();
+            }
+        }
+    };
+
+    let exit_status = match exit_status {
+        Ok(status) => Some(status),
+        Err(err) => {
+            error!("Error waiting for process: {}", err);
+            None
+        }
+    };
+
+    info!("Process exited with code: {}",
+        exit_status
+            .and_then(|x| x.code())
+            .map(|x| x.to_string())
+            .unwrap_or("<none>".to_owned()));
+
+    (exit_status, timed_out)
+}
+
+/// Record how the run ended, and let anyone attached (or to be notified) know
+async fn finish(shared: &Shared,
+                run: &ActiveRun,
+                task: &TaskExec,
+                exit_status: Option<ExitStatus>,
+                timed_out_after: Option<Duration>,
+                recorder: Option<(History, RunOutput)>) {
+    let record = run.complete(exit_status, timed_out_after);
+
+    // save record before telling anyone attached, so anyone who saw the exit code can also find the record
+    if let Some((history, mut run_output)) = recorder {
+        if let Err(err) = run_output.flush().await {
+            error!("Error writing run output: {}", err);
+        }
+        if let Err(err) = history.save(&record).await {
+            error!("Error saving run record: {}", err);
+        }
+    }
+
+    shared.runs.write().unwrap().remove(&record.id);
+
+    run.mark_finished();
+
+    shared.notifier.run_finished(&record, run.output_tail(crate::notify::OUTPUT_TAIL_LINES), &task.notify, &task.email);
+}
+
+/// Follow an active run from the given line offset until it finishes. Runs waiting to start
+/// report their place in the queue (whenever it changes).
+pub fn events(run: Arc<ActiveRun>, offset: usize) -> impl Stream<Item=RunEvent> {
+    let updated = run.updated.subscribe(); // subscribe before first read so no updates are missed
+
+    let follow = Follow {
+        run,
+        updated,
+        offset,
+        announced_position: None,
+        announced_start: false,
+        finished: false,
+        pending: VecDeque::new(),
+    };
+
+    futures::stream::unfold(follow, |mut follow| async move {
+        loop {
+            if let Some(event) = follow.pending.pop_front() {
+                return Some((event, follow));
+            }
+
+            if follow.finished {
+                return None;
+            }
+
+            follow.catch_up();
+
+            if follow.pending.is_empty() && follow.updated.changed().await.is_err() {
+                return None; // not expected (we're holding a reference to the sender)
+            }
+        }
+    })
+}
+
+struct Follow {
+    run: Arc<ActiveRun>,
+    updated: watch::Receiver<()>,
+    offset: usize,
+    announced_position: Option<usize>,
+    announced_start: bool,
+    finished: bool,
+    pending: VecDeque<RunEvent>,
+}
+
+impl Follow {
+    /// Queue up events for whatever has happened since last time
+    fn catch_up(&mut self) {
+        let state = self.run.state.lock().unwrap();
+
+        if !self.announced_start && state.pid.is_some() {
+            self.announced_start = true;
+            self.pending.push_back(RunEvent::Started { run_id: state.record.id.clone(), pid: state.pid });
+        }
+
+        // (too far behind to catch up on everything, the marker stands in for the lines that are gone)
+        if self.offset < state.dropped {
+            self.pending.push_back(RunEvent::Output(OutputLine {
+                seq: state.dropped - 1,
+                stream: OutputStream::Stderr,
+                time: None,
+                text: format!("[{} earlier line(s) not shown]\n", state.dropped - self.offset),
+            }));
+            self.offset = state.dropped;
+        }
+
+        for line in state.output.iter().skip(self.offset - state.dropped) {
+            self.pending.push_back(RunEvent::Output(line.clone()));
+            self.offset += 1;
+        }
+
+        if state.finished {
+            self.finished = true;
+            self.pending.push_back(RunEvent::Finished(state.record.clone()));
+        } else if let Some(position) = state.record.queue_position.filter(|&position| self.announced_position != Some(position)) {
+            self.announced_position = Some(position);
+            self.pending.push_back(RunEvent::Queued(position));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn active_run() -> ActiveRun {
+        ActiveRun {
+            state: Mutex::new(RunState {
+                record: RunRecord {
+                    id: "1234".to_owned(),
+                    task: "chatty".to_owned(),
+                    username: "admin".to_owned(),
+                    params: HashMap::new(),
+                    secret_params: vec![],
+                    started_at: Utc::now(),
+                    finished_at: None,
+                    exit_code: None,
+                    signal: None,
+                    timed_out_after_secs: None,
+                    cancelled_by: None,
+                    queue_position: None,
+                },
+                pid: Some(1),
+                output: VecDeque::new(),
+                dropped: 0,
+                finished: false,
+            }),
+            updated: watch::channel(()).0,
+            cancelled: Notify::new(),
+        }
+    }
+
+    fn output(events: &[RunEvent]) -> Vec<(usize, String)> {
+        events.iter()
+            .filter_map(|event| match event {
+                RunEvent::Output(line) => Some((line.seq, line.text.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_events_after_output_dropped() {
+        let run = Arc::new(active_run());
+
+        for i in 0..MAX_BUFFERED_LINES + 5 {
+            run.push_output(OutputStream::Stdout, format!("{}\n", i));
+        }
+        run.mark_finished();
+
+        assert_eq!(run.output_tail(2), format!("{}\n{}\n", MAX_BUFFERED_LINES + 3, MAX_BUFFERED_LINES + 4));
+
+        // when attaching from the start, the lines that are gone are counted
+        let replayed: Vec<RunEvent> = events(run.clone(), 0).collect().await;
+        let lines = output(&replayed);
+
+        assert_eq!(lines.len(), MAX_BUFFERED_LINES + 1);
+        assert_eq!(lines[0], (4, "[5 earlier line(s) not shown]\n".to_owned()));
+        assert_eq!(lines[1], (5, "5\n".to_owned()));
+        assert!(matches!(replayed.last(), Some(RunEvent::Finished(_))));
+
+        // and offsets still count every line
+        let replayed: Vec<RunEvent> = events(run.clone(), MAX_BUFFERED_LINES + 3).collect().await;
+
+        assert_eq!(output(&replayed), vec![
+            (MAX_BUFFERED_LINES + 3, format!("{}\n", MAX_BUFFERED_LINES + 3)),
+            (MAX_BUFFERED_LINES + 4, format!("{}\n", MAX_BUFFERED_LINES + 4)),
+        ]);
+    }
+}
@@ -0,0 +1,432 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Duration, Local, Utc};
+
+use tokio::time::Instant;
+
+use crate::{Shared, TaskRequest};
+use crate::cron::CronSchedule;
+use crate::server::{ServerError, UserPrincipal};
+use crate::task::{MissedPolicy, OverlapPolicy, TaskDef, TaskDefSchedule};
+
+pub const DEFAULT_USERNAME: &str = "scheduler";
+
+/// Runs that were due longer ago than this have been missed (eg, while the server was down)
+const MISSED_AFTER: Duration = Duration::minutes(1);
+
+/// Check schedules at least this often, to pick up any added by reloading
+const RECHECK_INTERVAL: Duration = Duration::minutes(1);
+
+pub struct SchedulerSettings {
+    pub enabled: bool,
+    /// Recorded as having started scheduled runs
+    pub username: String,
+    pub roles: HashSet<String>,
+}
+
+/// Time zone of cron expressions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zone {
+    /// The server's
+    Local,
+    Utc,
+}
+
+impl Zone {
+    fn next_time(self, cron: &CronSchedule, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Zone::Local => cron.next_after_in(&after.with_timezone(&Local)).map(|time| time.with_timezone(&Utc)),
+            Zone::Utc => cron.next_after_in(&after),
+        }
+    }
+}
+
+/// Time of day that follows tokio's clock rather than the system's, so tests can pause it and
+/// move it along (and don't depend on the time zone they're run in)
+#[derive(Debug, Clone, Copy)]
+pub struct Clock {
+    wall: DateTime<Utc>,
+    instant: Instant,
+    zone: Zone,
+}
+
+impl Clock {
+    pub fn new() -> Clock {
+        Clock::starting_at(Utc::now(), Zone::Local)
+    }
+
+    pub fn starting_at(wall: DateTime<Utc>, zone: Zone) -> Clock {
+        Clock { wall, instant: Instant::now(), zone }
+    }
+
+    /// Next time of the schedule (in the clock's time zone) after the given one
+    fn next_time(&self, cron: &CronSchedule, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.zone.next_time(cron, after)
+    }
+
+    pub fn now(&self) -> DateTime<Utc> {
+        self.wall + Duration::from_std(self.instant.elapsed()).unwrap_or(Duration::zero())
+    }
+
+    pub async fn sleep_until(&self, time: DateTime<Utc>) {
+        let offset = (time - self.wall).to_std().unwrap_or_default(); // (already passed if negative)
+        tokio::time::sleep_until(self.instant + offset).await
+    }
+}
+
+/// Identifies a schedule across reloads (as long as it's in the same place, with the same times)
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct ScheduleKey {
+    task: String,
+    index: usize,
+    cron: String,
+}
+
+impl ScheduleKey {
+    fn new(task: &str, index: usize, schedule: &TaskDefSchedule) -> ScheduleKey {
+        ScheduleKey { task: task.to_owned(), index, cron: schedule.cron.to_string() }
+    }
+}
+
+struct ScheduleState {
+    /// Anything due up to this time has been dealt with
+    checked_until: DateTime<Utc>,
+    last_run_id: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Due {
+    No,
+    Run,
+    /// Was due at the time (but it's too late to run now)
+    Missed(DateTime<Utc>),
+}
+
+impl ScheduleState {
+    /// Whether the schedule should run now, moving on past any times that have gone by
+    fn take_due(&mut self, schedule: &TaskDefSchedule, now: DateTime<Utc>, zone: Zone) -> Due {
+        match zone.next_time(&schedule.cron, self.checked_until) {
+            Some(due) if due <= now => {
+                let checked_until = std::mem::replace(&mut self.checked_until, now);
+
+                // only the most recent time counts if several have passed
+                let recent = zone.next_time(&schedule.cron, checked_until.max(now - MISSED_AFTER));
+
+                match (recent, schedule.missed) {
+                    (Some(recent), _) if recent <= now => Due::Run,
+                    (_, MissedPolicy::RunOnce) => Due::Run,
+                    (_, MissedPolicy::Skip) => Due::Missed(due),
+                }
+            }
+            _ => Due::No
+        }
+    }
+}
+
+/// Run for a schedule that's due
+struct Fire {
+    key: ScheduleKey,
+    params: HashMap<String, String>,
+    overlap: OverlapPolicy,
+}
+
+/// When a schedule will next run, and how it last went
+pub struct ScheduleStatus {
+    pub next_run_at: Option<DateTime<Utc>>,
+    pub last_run_id: Option<String>,
+}
+
+/// Starts runs for the '[[schedule]]' entries of all tasks
+pub struct Scheduler {
+    pub settings: SchedulerSettings,
+    clock: Clock,
+    states: Mutex<HashMap<ScheduleKey, ScheduleState>>,
+}
+
+impl Scheduler {
+    pub fn new(settings: SchedulerSettings, clock: Clock) -> Scheduler {
+        Scheduler { settings, clock, states: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn status(&self, task: &str, index: usize, schedule: &TaskDefSchedule) -> ScheduleStatus {
+        if !self.settings.enabled {
+            return ScheduleStatus { next_run_at: None, last_run_id: None };
+        }
+
+        let states = self.states.lock().unwrap();
+        let state = states.get(&ScheduleKey::new(task, index, schedule));
+
+        let checked_until = state.map(|state| state.checked_until).unwrap_or_else(|| self.clock.now());
+
+        ScheduleStatus {
+            next_run_at: self.clock.next_time(&schedule.cron, checked_until),
+            last_run_id: state.and_then(|state| state.last_run_id.clone()),
+        }
+    }
+
+    /// Runs due now, and when to look again. Schedules seen for the first time start from now,
+    /// unless they run missed runs and there's a last run to start from (`last_runs` by task).
+    fn tick(&self,
+            tasks: &HashMap<String, TaskDef>,
+            last_runs: &HashMap<String, DateTime<Utc>>,
+            now: DateTime<Utc>) -> (Vec<Fire>, DateTime<Utc>) {
+        let mut states = self.states.lock().unwrap();
+
+        let mut seen = HashSet::new();
+        let mut fires = vec![];
+        let mut wake = now + RECHECK_INTERVAL;
+
+        for task in tasks.values() {
+            for (index, schedule) in task.schedules.iter().enumerate() {
+                let key = ScheduleKey::new(&task.name, index, schedule);
+
+                let state = states.entry(key.clone()).or_insert_with(|| ScheduleState {
+                    checked_until: match schedule.missed {
+                        MissedPolicy::RunOnce => last_runs.get(&task.name).copied().unwrap_or(now),
+                        MissedPolicy::Skip => now,
+                    },
+                    last_run_id: None,
+                });
+
+                match state.take_due(schedule, now, self.clock.zone) {
+                    Due::Run => fires.push(Fire {
+                        key: key.clone(),
+                        params: schedule.params.clone(),
+                        overlap: schedule.overlap,
+                    }),
+                    Due::Missed(due) => {
+                        warn!("Missed scheduled run of task {} ({}) due at {}", task.name, schedule.cron, due.to_rfc3339());
+                    }
+                    Due::No => {}
+                }
+
+                if let Some(next) = self.clock.next_time(&schedule.cron, state.checked_until) {
+                    wake = wake.min(next);
+                }
+
+                seen.insert(key);
+            }
+        }
+
+        states.retain(|key, _| seen.contains(key)); // schedules removed by reloading
+
+        (fires, wake)
+    }
+
+    fn set_last_run(&self, key: &ScheduleKey, run_id: String) {
+        if let Some(state) = self.states.lock().unwrap().get_mut(key) {
+            state.last_run_id = Some(run_id);
+        }
+    }
+
+    fn last_run(&self, key: &ScheduleKey) -> Option<String> {
+        self.states.lock().unwrap().get(key).and_then(|state| state.last_run_id.clone())
+    }
+}
+
+/// Call `tick` whenever it says something is next due (it's given the current time)
+async fn drive<T>(clock: Clock, mut tick: T)
+    where T: FnMut(DateTime<Utc>) -> DateTime<Utc> {
+    loop {
+        let wake = tick(clock.now());
+        clock.sleep_until(wake).await;
+    }
+}
+
+/// Start scheduled runs for as long as the server is running
+pub async fn run(shared: Arc<Shared>) {
+    if !shared.scheduler.settings.enabled {
+        info!("Scheduler is disabled");
+        return;
+    }
+
+    let mut last_runs = Some(last_scheduled_runs(&shared).await);
+
+    let clock = shared.scheduler.clock;
+
+    drive(clock, |now| {
+        let (fires, wake) = {
+            let tasks = shared.tasks.read().unwrap();
+            shared.scheduler.tick(&tasks, &last_runs.take().unwrap_or_default(), now)
+        };
+
+        for fire in fires {
+            tokio::spawn(start_run(shared.clone(), fire));
+        }
+
+        wake
+    }).await
+}
+
+/// When each task was last run by the scheduler (for catching up on missed runs)
+async fn last_scheduled_runs(shared: &Shared) -> HashMap<String, DateTime<Utc>> {
+    let mut last_runs: HashMap<String, DateTime<Utc>> = HashMap::new();
+
+    if let Some(history) = &shared.history {
+        match history.list().await {
+            Ok(runs) => {
+                for run in runs.into_iter().filter(|run| run.username == shared.scheduler.settings.username) {
+                    let last = last_runs.entry(run.task).or_insert(run.started_at);
+                    *last = (*last).max(run.started_at);
+                }
+            }
+            Err(err) => {
+                error!("Error listing runs (missed scheduled runs won't be run): {}", err);
+            }
+        }
+    }
+
+    last_runs
+}
+
+async fn start_run(shared: Arc<Shared>, fire: Fire) {
+    let task_name = &fire.key.task;
+
+    let principal = UserPrincipal::system(&shared.scheduler.settings.username, &shared.scheduler.settings.roles);
+
+    if let Some(run_id) = shared.scheduler.last_run(&fire.key) {
+        if fire.overlap == OverlapPolicy::Skip && shared.runs.read().unwrap().contains_key(&run_id) {
+            info!("Skipping scheduled run of task {}, last run is still going: {}", task_name, run_id);
+            return;
+        }
+    }
+
+    let task_req = TaskRequest {
+        name: task_name.clone(),
+        method: None,
+        params: fire.params,
+    };
+
+    let mut task = match crate::server::validate_task_req(shared.clone(), task_req, &principal) {
+        Ok(task) => task,
+        Err(err) => {
+            error!("Unable to run scheduled task {}: {:?}", task_name, err);
+            return;
+        }
+    };
+
+    if fire.overlap == OverlapPolicy::Queue {
+        task.queue = true;
+        task.concurrency = Some(task.concurrency.unwrap_or(1)); // after any other run of the task
+    }
+
+    info!("Starting scheduled run of task: {}", task_name);
+
+    match crate::run::start(shared.clone(), task, &principal, true, None).await {
+        Ok((run, _)) => {
+            shared.scheduler.set_last_run(&fire.key, run.record().id);
+        }
+        Err(ServerError::Conflict(message)) => {
+            info!("Skipping scheduled run of task {}: {}", task_name, message);
+        }
+        Err(err) => {
+            error!("Error starting scheduled run of task {}: {:?}", task_name, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    use crate::task_file::TaskFileToml;
+
+    const CARGO_MANIFEST_DIR: &str = env!("CARGO_MANIFEST_DIR");
+
+    fn time(value: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(value).unwrap().with_timezone(&Utc)
+    }
+
+    fn load_tasks() -> HashMap<String, TaskDef> {
+        let path: PathBuf = format!("{}/tests/resources/tasks/scheduled.task.toml", CARGO_MANIFEST_DIR).into();
+        let task = TaskFileToml::load(&path).unwrap();
+        HashMap::from([(task.name.clone(), task)])
+    }
+
+    fn scheduler(clock: Clock) -> Scheduler {
+        Scheduler::new(SchedulerSettings {
+            enabled: true,
+            username: DEFAULT_USERNAME.to_owned(),
+            roles: HashSet::new(),
+        }, clock)
+    }
+
+    fn schedule(cron: &str, missed: MissedPolicy) -> TaskDefSchedule {
+        TaskDefSchedule {
+            cron: cron.parse().unwrap(),
+            params: HashMap::new(),
+            missed,
+            overlap: OverlapPolicy::Skip,
+        }
+    }
+
+    #[test]
+    fn test_take_due() {
+        let schedule = schedule("*/10 * * * *", MissedPolicy::Skip);
+
+        let mut state = ScheduleState { checked_until: time("2024-01-01T00:00:00Z"), last_run_id: None };
+
+        assert_eq!(state.take_due(&schedule, time("2024-01-01T00:09:59Z"), Zone::Utc), Due::No);
+        assert_eq!(state.take_due(&schedule, time("2024-01-01T00:10:00Z"), Zone::Utc), Due::Run);
+        assert_eq!(state.take_due(&schedule, time("2024-01-01T00:10:30Z"), Zone::Utc), Due::No); // already run
+        assert_eq!(state.take_due(&schedule, time("2024-01-01T00:20:59Z"), Zone::Utc), Due::Run); // a bit late
+        assert_eq!(state.checked_until, time("2024-01-01T00:20:59Z"));
+    }
+
+    #[test]
+    fn test_take_due_missed() {
+        let skip = schedule("0 * * * *", MissedPolicy::Skip);
+        let run_once = schedule("0 * * * *", MissedPolicy::RunOnce);
+
+        let mut state = ScheduleState { checked_until: time("2024-01-01T00:00:00Z"), last_run_id: None };
+
+        // hours later (eg, server was down)
+        assert_eq!(state.take_due(&skip, time("2024-01-01T05:30:00Z"), Zone::Utc), Due::Missed(time("2024-01-01T01:00:00Z")));
+        assert_eq!(state.take_due(&skip, time("2024-01-01T05:40:00Z"), Zone::Utc), Due::No);
+
+        let mut state = ScheduleState { checked_until: time("2024-01-01T00:00:00Z"), last_run_id: None };
+
+        assert_eq!(state.take_due(&run_once, time("2024-01-01T05:30:00Z"), Zone::Utc), Due::Run); // just the once
+        assert_eq!(state.take_due(&run_once, time("2024-01-01T05:40:00Z"), Zone::Utc), Due::No);
+
+        // still run on time when others have been missed
+        let mut state = ScheduleState { checked_until: time("2024-01-01T00:00:00Z"), last_run_id: None };
+
+        assert_eq!(state.take_due(&skip, time("2024-01-01T05:00:30Z"), Zone::Utc), Due::Run);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_drive() {
+        let clock = Clock::starting_at(time("2024-01-01T00:00:30Z"), Zone::Utc);
+        let scheduler = Arc::new(scheduler(clock));
+        let tasks = load_tasks();
+
+        let fired = Arc::new(Mutex::new(Vec::<(DateTime<Utc>, HashMap<String, String>)>::new()));
+
+        let driver = tokio::spawn({
+            let scheduler = scheduler.clone();
+            let fired = fired.clone();
+            drive(clock, move |now| {
+                let (fires, wake) = scheduler.tick(&tasks, &HashMap::new(), now);
+                fired.lock().unwrap().extend(fires.into_iter().map(|fire| (now, fire.params)));
+                wake
+            })
+        });
+
+        tokio::time::sleep(std::time::Duration::from_secs(11 * 60)).await;
+
+        let fired = fired.lock().unwrap().clone();
+
+        let times: Vec<DateTime<Utc>> = fired.iter().map(|(time, _)| *time).collect();
+        assert_eq!(times, vec![time("2024-01-01T00:05:00Z"), time("2024-01-01T00:10:00Z")]);
+        assert_eq!(fired[0].1, HashMap::from([("count".to_owned(), "2".to_owned())]));
+
+        let status = scheduler.status("scheduled", 0, &load_tasks()["scheduled"].schedules[0]);
+        assert_eq!(status.next_run_at, Some(time("2024-01-01T00:15:00Z")));
+
+        driver.abort();
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::task_file::ConfigFileError;
+
+/// Shown in place of secret values
+pub const REDACTED: &str = "***";
+
+/// Values for '{ secret = "..." }' in task files (only ever put in the environment of processes)
+#[derive(Default)]
+pub struct Secrets {
+    values: HashMap<String, String>,
+    /// Where they were loaded from (to watch for changes)
+    file: Option<PathBuf>,
+    dir: Option<PathBuf>,
+}
+
+impl Secrets {
+    /// Load from a TOML file of 'name = "value"' and/or a directory with a file per secret, named
+    /// after it (as systemd provides credentials). Secrets in the directory win over the file's.
+    pub fn load(file: Option<&Path>, dir: Option<&Path>) -> Result<Secrets, ConfigFileError> {
+        let mut values = HashMap::new();
+
+        if let Some(file) = file {
+            check_permissions(file)?;
+            values.extend(crate::server_file::load_toml::<HashMap<String, String>>(file)?);
+        }
+
+        if let Some(dir) = dir {
+            let from_io_err = |err: std::io::Error| ConfigFileError::Io(err, Some(dir.to_owned()));
+
+            for entry in fs::read_dir(dir).map_err(from_io_err)? {
+                let path = entry.map_err(from_io_err)?.path();
+
+                let name = match path.file_name().and_then(|name| name.to_str()) {
+                    Some(name) if !name.starts_with('.') && path.is_file() => name.to_owned(),
+                    _ => continue,
+                };
+
+                check_permissions(&path)?;
+
+                let value = fs::read_to_string(&path).map_err(|err| ConfigFileError::Io(err, Some(path.clone())))?;
+
+                // (files are often written with a newline at the end)
+                let value = value.strip_suffix('\n').map(|value| value.strip_suffix('\r').unwrap_or(value)).unwrap_or(&value);
+
+                values.insert(name, value.to_owned());
+            }
+        }
+
+        Ok(Secrets { values, file: file.map(Path::to_owned), dir: dir.map(Path::to_owned) })
+    }
+
+    /// The file and directory they're loaded from, and whatever is in the directory now
+    pub fn paths(&self) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = self.file.iter().chain(&self.dir).cloned().collect();
+
+        if let Some(dir) = &self.dir {
+            match fs::read_dir(dir) {
+                Ok(entries) => paths.extend(entries.filter_map(|entry| entry.ok()).map(|entry| entry.path())),
+                Err(err) => {
+                    debug!("Error reading secrets directory: {}", err); // reported when reloading
+                }
+            }
+        }
+
+        paths
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(String::as_str)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.values.contains_key(name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+}
+
+/// (names only)
+impl fmt::Debug for Secrets {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut names: Vec<&String> = self.values.keys().collect();
+        names.sort();
+        f.debug_struct("Secrets").field("names", &names).finish()
+    }
+}
+
+/// Files of secrets mustn't be readable (or writable) by anyone but their owner
+#[cfg(unix)]
+fn check_permissions(path: &Path) -> Result<(), ConfigFileError> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let metadata = fs::metadata(path).map_err(|err| ConfigFileError::Io(err, Some(path.to_owned())))?;
+
+    if metadata.permissions().mode() & 0o077 != 0 {
+        return Err(ConfigFileError::InsecurePermissions(path.to_owned()));
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_path: &Path) -> Result<(), ConfigFileError> {
+    Ok(())
+}
+
+/// Parameters with the values of secret ones replaced
+pub fn redact_params(params: &HashMap<String, String>, secret_params: &HashSet<String>) -> HashMap<String, String> {
+    params.iter()
+        .map(|(name, value)| {
+            let value = if secret_params.contains(name) { REDACTED.to_owned() } else { value.clone() };
+            (name.clone(), value)
+        })
+        .collect()
+}
+
+/// Line with any secret values in it replaced (longest first, so that a secret containing another
+/// one isn't left partly visible)
+pub fn mask(line: String, values: &[String]) -> String {
+    let mut values: Vec<&String> = values.iter().filter(|value| !value.is_empty()).collect();
+
+    if !values.iter().any(|value| line.contains(value.as_str())) {
+        return line;
+    }
+
+    values.sort_by_key(|value| std::cmp::Reverse(value.len()));
+
+    values.into_iter().fold(line, |line, value| line.replace(value.as_str(), REDACTED))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    fn write_secret(path: &Path, content: &str, mode: u32) {
+        use std::os::unix::fs::PermissionsExt;
+
+        let _ = fs::remove_file(path); // (might not be writable)
+        fs::write(path, content).unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_load() {
+        let dir: std::path::PathBuf = format!("{}/target/test-secrets", env!("CARGO_MANIFEST_DIR")).into();
+
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("credentials")).unwrap();
+
+        write_secret(&dir.join("secrets.toml"), "aws_key = \"from-file\"\ndb_password = \"hunter2\"\n", 0o600);
+        write_secret(&dir.join("credentials").join("aws_key"), "from-dir\n", 0o400);
+        write_secret(&dir.join("credentials").join("api_token"), "abc\r\n", 0o400);
+
+        let secrets = Secrets::load(Some(&dir.join("secrets.toml")), Some(&dir.join("credentials"))).unwrap();
+
+        assert_eq!(secrets.len(), 3);
+        assert_eq!(secrets.get("aws_key"), Some("from-dir"));
+        assert_eq!(secrets.get("db_password"), Some("hunter2"));
+        assert_eq!(secrets.get("api_token"), Some("abc"));
+        assert_eq!(format!("{:?}", secrets), r#"Secrets { names: ["api_token", "aws_key", "db_password"] }"#);
+
+        let mut paths = secrets.paths();
+        paths.sort();
+
+        assert_eq!(paths, vec![
+            dir.join("credentials"),
+            dir.join("credentials").join("api_token"),
+            dir.join("credentials").join("aws_key"),
+            dir.join("secrets.toml"),
+        ]);
+
+        // (anyone else being able to read them isn't allowed)
+        write_secret(&dir.join("credentials").join("api_token"), "abc", 0o644);
+
+        assert!(matches!(Secrets::load(None, Some(&dir.join("credentials"))), Err(ConfigFileError::InsecurePermissions(_))));
+    }
+
+    #[test]
+    fn test_redact_params() {
+        let params = HashMap::from([
+            ("user".to_owned(), "bob".to_owned()),
+            ("token".to_owned(), "hunter2".to_owned()),
+        ]);
+
+        assert_eq!(redact_params(&params, &HashSet::from(["token".to_owned()])), HashMap::from([
+            ("user".to_owned(), "bob".to_owned()),
+            ("token".to_owned(), "***".to_owned()),
+        ]));
+    }
+
+    #[test]
+    fn test_mask() {
+        let values = vec!["abc".to_owned(), "abcdef".to_owned(), "".to_owned()];
+
+        assert_eq!(mask("key=abcdef, other=abc\n".to_owned(), &values), "key=***, other=***\n");
+        assert_eq!(mask("nothing to see\n".to_owned(), &values), "nothing to see\n");
+        assert_eq!(mask("anything\n".to_owned(), &[]), "anything\n");
+    }
+}
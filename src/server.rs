@@ -1,23 +1,23 @@
 use std::collections::{HashMap, HashSet};
 use std::convert::Infallible;
-use std::ffi::OsString;
+use std::cmp::Reverse;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-use futures::future::TryFutureExt;
-use futures::stream::StreamExt;
+use chrono::Utc;
 
-use tokio_stream::wrappers::LinesStream;
+use futures::stream::StreamExt;
 
 use http::{header, HeaderValue, Method, StatusCode};
 use hyper::{Body, Request, Response};
-use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::process::Command;
-use url::{form_urlencoded, Url};
+use url::form_urlencoded;
 
-use crate::{CachedCredential, TaskExec, TaskRequest, UserSession, UserDef};
+use crate::{AuthSettings, CachedCredential, CredentialType, TaskExec, TaskRequest, TokenDef, UserSession, UserDef};
+use crate::history::{History, OutputFile, OutputStream, RunOutcome, RunRecord};
+use crate::output::OutputFormat;
+use crate::run::{ActiveRun, OutputLine, RunEvent};
 use crate::json_conv;
-use crate::task::{TaskDefParameter, TaskMethod, TaskParameterValue};
+use crate::task::{TaskDef, TaskDefEnv, TaskDefParameter, TaskMethod, TaskParameterType, TaskParameterValue};
 
 #[allow(dead_code)] // they'll be used eventually
 #[derive(Debug)]
@@ -28,19 +28,118 @@ pub enum ServerError {
     MethodNotAllowed,
     Forbidden,
     NotAcceptable,
+    Conflict(String),
+    PayloadTooLarge,
     InternalServerError,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrincipalType {
+    /// Authenticated user
+    User,
+    /// Authenticated API token
+    Token,
+    /// Unauthenticated request when guest access is enabled
+    Guest,
+    /// Any request when authentication is disabled
+    Anonymous,
+    /// Runs started by the server itself (eg, on a schedule)
+    System,
+    /// Request signed with a task's webhook secret (only ever used for that task)
+    Webhook,
+}
+
 pub struct UserPrincipal {
     pub username: String,
     pub roles: HashSet<String>,
+    pub principal_type: PrincipalType,
+    /// Set when authenticated by session cookie
+    pub csrf_token: Option<String>,
 }
 
+const GUEST_USERNAME: &str = "guest";
+const ANONYMOUS_USERNAME: &str = "anonymous";
+const WEBHOOK_USERNAME: &str = "webhook";
+
 impl UserPrincipal {
     pub fn from(user_def: &UserDef) -> UserPrincipal {
         UserPrincipal {
             username: user_def.username.clone(),
             roles: user_def.roles.clone(),
+            principal_type: PrincipalType::User,
+            csrf_token: None,
+        }
+    }
+
+    pub fn from_token(token_def: &TokenDef) -> UserPrincipal {
+        UserPrincipal {
+            username: token_def.name.clone(),
+            roles: token_def.roles.clone(),
+            principal_type: PrincipalType::Token,
+            csrf_token: None,
+        }
+    }
+
+    pub fn guest(roles: &HashSet<String>) -> UserPrincipal {
+        UserPrincipal {
+            username: GUEST_USERNAME.to_owned(),
+            roles: roles.clone(),
+            principal_type: PrincipalType::Guest,
+            csrf_token: None,
+        }
+    }
+
+    pub fn anonymous() -> UserPrincipal {
+        UserPrincipal {
+            username: ANONYMOUS_USERNAME.to_owned(),
+            roles: HashSet::new(),
+            principal_type: PrincipalType::Anonymous,
+            csrf_token: None,
+        }
+    }
+
+    pub fn system(username: &str, roles: &HashSet<String>) -> UserPrincipal {
+        UserPrincipal {
+            username: username.to_owned(),
+            roles: roles.clone(),
+            principal_type: PrincipalType::System,
+            csrf_token: None,
+        }
+    }
+
+    pub fn webhook() -> UserPrincipal {
+        UserPrincipal {
+            username: WEBHOOK_USERNAME.to_owned(),
+            roles: HashSet::new(),
+            principal_type: PrincipalType::Webhook,
+            csrf_token: None,
+        }
+    }
+
+    pub fn is_allowed(&self, task: &TaskDef) -> bool {
+        match self.principal_type {
+            PrincipalType::User | PrincipalType::Token | PrincipalType::System => task.is_allowed(&self.roles),
+            // guests only get tasks that have explicitly been opened up to them
+            PrincipalType::Guest => task.auth.roles_allowed.is_some() && task.is_allowed(&self.roles),
+            // nothing to check roles against (the signature is checked against the task's secret)
+            PrincipalType::Anonymous | PrincipalType::Webhook => true,
+        }
+    }
+
+    /// Allowed to administer the server (eg, reload configuration)
+    pub fn is_admin(&self, auth: &AuthSettings) -> bool {
+        match self.principal_type {
+            PrincipalType::User | PrincipalType::Token => !self.roles.is_disjoint(&auth.admin_roles),
+            PrincipalType::Guest | PrincipalType::System | PrincipalType::Webhook => false,
+            PrincipalType::Anonymous => true,
+        }
+    }
+
+    /// Ask guests to log in rather than just turning them away
+    fn forbidden(&self) -> ServerError {
+        match self.principal_type {
+            PrincipalType::Guest => ServerError::Unauthorized,
+            _ => ServerError::Forbidden,
         }
     }
 }
@@ -82,6 +181,12 @@ pub async fn handle(shared: Arc<crate::Shared>, req: Request<Body>) -> Result<Re
         Err(ServerError::NotAcceptable) => {
             html_error(StatusCode::NOT_ACCEPTABLE, "Not acceptable")
         }
+        Err(ServerError::Conflict(message)) => {
+            html_error(StatusCode::CONFLICT, &format!("Conflict: {}", message))
+        }
+        Err(ServerError::PayloadTooLarge) => {
+            html_error(StatusCode::PAYLOAD_TOO_LARGE, "Payload too large")
+        }
         Err(ServerError::InternalServerError) => {
             html_error(StatusCode::INTERNAL_SERVER_ERROR, "Internal server error")
         }
@@ -105,15 +210,13 @@ fn serve_redirect() -> Result<Response<Body>, ServerError> {
     Ok(response)
 }
 
-// const SESSION_ID_COOKIE: &'static str = "session_id";
-
 const SESSION_CACHE_PERIOD: Duration = Duration::from_secs(30 * 60); // 30 minutes
 
 fn prune_expired(sessions: &mut HashMap<CachedCredential, UserSession>) -> () {
     let now = Instant::now();
 
     sessions.retain(|_k, v| {
-        v.expires_at.lt(&now)
+        v.expires_at.gt(&now)
     });
 }
 
@@ -130,96 +233,168 @@ fn prune_expired(sessions: &mut HashMap<CachedCredential, UserSession>) -> () {
 // }
 
 fn ensure_auth(shared: &Arc<crate::Shared>, req: &Request<Body>) -> Result<UserPrincipal, ServerError> {
-    let req_auth = crate::utils::parse_authorization(&req)?
-        .ok_or(ServerError::Unauthorized)?;
-
-    let has_session = |key: &crate::CachedCredential| -> Result<bool, ServerError> {
-        shared.sessions.read()
-            .map(|sessions| sessions.contains_key(key))
-            .map_err(|err| {
-                error!("Could not obtain sessions lock: {:?}", err);
-                ServerError::InternalServerError
-            })
+    if !shared.auth.enabled {
+        return Ok(UserPrincipal::anonymous());
+    }
+
+    let req_auth = match crate::utils::parse_authorization(req)? {
+        Some(req_auth) => req_auth,
+        None => match crate::session::get_session_user(shared, req)? {
+            Some(user) => {
+                return Ok(user);
+            }
+            None if shared.auth.guest => {
+                return Ok(UserPrincipal::guest(&shared.auth.guest_roles));
+            }
+            None => {
+                return Err(ServerError::Unauthorized);
+            }
+        }
     };
 
     match req_auth {
         crate::utils::AuthorizationValue::Basic { username, password } => {
-            let users = shared.users.read().map_err(|err| {
-                error!("Could not obtain users lock: {:?}", err);
-                ServerError::InternalServerError
-            })?;
+            // (not holding the lock while verifying, which is deliberately slow)
+            let found = {
+                let users = shared.users.read().map_err(|err| {
+                    error!("Could not obtain users lock: {:?}", err);
+                    ServerError::InternalServerError
+                })?;
 
-            // let user_def = get_user(shared, &username)?;
-            let user_def = users.get(&username).ok_or_else(|| {
+                users.get(&username).map(|user_def| (user_def.password.clone(), UserPrincipal::from(user_def)))
+            };
+
+            let (expected, user) = found.ok_or_else(|| {
                 warn!("Username not found: {}", username);
+                verify_dummy(&password);
                 ServerError::Unauthorized
             })?;
 
-            let cache_hash = crate::CachedCredential {
-                username: username.clone(),
-                password_hash: ring::digest::digest(&ring::digest::SHA256, password.as_bytes())
-                    .as_ref()
-                    .to_vec(),
-            };
-
-            // found recent session that confirmed password is correct
-            if has_session(&cache_hash)? {
-                return Ok(UserPrincipal::from(user_def));
-            }
+            verify_cached(shared, CredentialType::Password, &username, &password, &expected)?;
 
-            match crate::password::verify_password_parts(&password, &user_def.password) {
-                Ok(true) => Ok(()),
-                Ok(false) => {
-                    warn!("Incorrect password for user: {}", username);
-                    Err(ServerError::Unauthorized)
-                }
-                Err(err) => {
-                    error!("Error verifying password: {:?}", err);
-                    Err(ServerError::InternalServerError)
-                }
-            }?;
+            Ok(user)
+        }
+        crate::utils::AuthorizationValue::Bearer { token } => {
+            let (name, secret) = crate::password::split_token(&token).ok_or_else(|| {
+                warn!("Malformed bearer token");
+                ServerError::Unauthorized
+            })?;
 
-            // don't keep this lock while verifying password above (verifying is slow)
-            let mut sessions = shared.sessions.write()
-                .map_err(|err| {
-                    error!("Could not obtain sessions lock: {:?}", err);
+            let found = {
+                let tokens = shared.tokens.read().map_err(|err| {
+                    error!("Could not obtain tokens lock: {:?}", err);
                     ServerError::InternalServerError
                 })?;
 
-            prune_expired(&mut sessions);
+                tokens.get(name).map(|token_def| (token_def.hash.clone(), token_def.expires_at, UserPrincipal::from_token(token_def)))
+            };
 
-            let expires_at = Instant::now().checked_add(SESSION_CACHE_PERIOD).unwrap_or_else(|| {
-                panic!("Instant value overflow"); // not expecting this (our duration value is quite short)
-            });
+            let (expected, expires_at, user) = found.ok_or_else(|| {
+                warn!("Token not found: {}", name);
+                verify_dummy(secret);
+                ServerError::Unauthorized
+            })?;
 
-            sessions.insert(cache_hash, UserSession { expires_at });
+            verify_cached(shared, CredentialType::Token, name, secret, &expected)?;
 
-            Ok(UserPrincipal::from(user_def))
+            // (only checked once the secret is known to be right, so as not to give away which tokens exist)
+            if expires_at.map(|expires_at| expires_at <= Utc::now()).unwrap_or(false) {
+                warn!("Token has expired: {}", name);
+                return Err(ServerError::Unauthorized);
+            }
+
+            Ok(user)
         }
     }
 }
 
+/// Take as long as verifying known credentials, so that unknown usernames (and tokens) can't be
+/// told apart by how quickly they're turned away
+fn verify_dummy(secret: &str) {
+    let _ = crate::password::verify_password_parts(secret, &crate::password::DUMMY_PASSWORD);
+}
+
+/// Verifying hashes is deliberately slow, so remember credentials that were recently verified
+fn verify_cached(shared: &Arc<crate::Shared>,
+                 credential_type: CredentialType,
+                 username: &str,
+                 secret: &str,
+                 expected: &crate::password::PasswordParts) -> Result<(), ServerError> {
+    let cache_hash = crate::CachedCredential {
+        credential_type,
+        username: username.to_owned(),
+        password_hash: ring::digest::digest(&ring::digest::SHA256, secret.as_bytes())
+            .as_ref()
+            .to_vec(),
+    };
+
+    let has_session = shared.sessions.read()
+        .map(|sessions| sessions.get(&cache_hash)
+            .map(|session| session.expires_at > Instant::now())
+            .unwrap_or(false))
+        .map_err(|err| {
+            error!("Could not obtain sessions lock: {:?}", err);
+            ServerError::InternalServerError
+        })?;
+
+    // found recent session that confirmed password is correct
+    if has_session {
+        return Ok(());
+    }
+
+    match crate::password::verify_password_parts(secret, expected) {
+        Ok(true) => Ok(()),
+        Ok(false) => {
+            warn!("Incorrect password for: {}", username);
+            Err(ServerError::Unauthorized)
+        }
+        Err(err) => {
+            error!("Error verifying password: {:?}", err);
+            Err(ServerError::InternalServerError)
+        }
+    }?;
+
+    // don't keep this lock while verifying password above (verifying is slow)
+    let mut sessions = shared.sessions.write()
+        .map_err(|err| {
+            error!("Could not obtain sessions lock: {:?}", err);
+            ServerError::InternalServerError
+        })?;
+
+    prune_expired(&mut sessions);
+
+    let expires_at = Instant::now().checked_add(SESSION_CACHE_PERIOD).unwrap_or_else(|| {
+        panic!("Instant value overflow"); // not expecting this (our duration value is quite short)
+    });
+
+    sessions.insert(cache_hash, UserSession { expires_at });
+
+    Ok(())
+}
+
 async fn match_path(shared: Arc<crate::Shared>, req: Request<Body>) -> Result<Response<Body>, ServerError> {
     let uri = req.uri().clone();
 
     let path: Vec<&str> = uri.path().split("/").skip(1).collect();
 
-    ensure_auth(&shared, &req)?;
-
+    // anything needed to show the login page doesn't need authentication
     let res = match &path[..] {
-        [""] => {
-            serve_redirect()
-        }
-        ["api", tail @ ..] => {
-            match_path_api(shared, req, tail).await
-        }
         all @ ["favicon.ico"] => {
             crate::web::serve_static(all)
         }
-        ["web", tail @ ..] => {
-            crate::web::match_path_web(tail).await
+        ["web", "login"] | ["web", "main.css"] | ["web", "modules", _] => {
+            crate::web::match_path_web(&path[1..]).await
+        }
+        ["api", "login"] => {
+            crate::session::handle_login(shared, req).await
+        }
+        // authenticated by signature rather than by user
+        ["hooks", task_name] => {
+            handle_hook(shared, req, task_name).await
+        }
+        _ => {
+            match_path_authenticated(shared, req, &path).await
         }
-        _ => Err(ServerError::NotFound)
     };
 
     if let Err(ref err) = res {
@@ -229,16 +404,83 @@ async fn match_path(shared: Arc<crate::Shared>, req: Request<Body>) -> Result<Re
     res
 }
 
-async fn match_path_api(shared: Arc<crate::Shared>, req: Request<Body>, path: &[&str]) -> Result<Response<Body>, ServerError> {
+async fn match_path_authenticated(shared: Arc<crate::Shared>, req: Request<Body>, path: &[&str]) -> Result<Response<Body>, ServerError> {
+    let user = match (ensure_auth(&shared, &req), path) {
+        (Ok(user), _) => user,
+        (Err(ServerError::Unauthorized), ["web", ..]) => {
+            return Ok(crate::session::redirect_to_login(&req)); // browsers get the login page rather than a Basic auth prompt
+        }
+        (Err(err), _) => {
+            return Err(err);
+        }
+    };
+
+    let req = match (&user.csrf_token, req.method()) {
+        (Some(csrf_token), &Method::POST) => crate::session::ensure_csrf(req, csrf_token).await?,
+        (Some(csrf_token), &Method::GET) if crate::websocket::is_upgrade(&req) => crate::session::ensure_csrf_query(req, csrf_token)?,
+        _ => req
+    };
+
+    match path {
+        [""] => {
+            serve_redirect()
+        }
+        ["api", tail @ ..] => {
+            match_path_api(shared, req, tail, user).await
+        }
+        ["web", tail @ ..] => {
+            crate::web::match_path_web(tail).await
+        }
+        _ => Err(ServerError::NotFound)
+    }
+}
+
+async fn match_path_api(shared: Arc<crate::Shared>, req: Request<Body>, path: &[&str], user: UserPrincipal) -> Result<Response<Body>, ServerError> {
     match &path[..] {
+        ["session"] => {
+            handle_session(req, &user)
+        }
+        ["logout"] => {
+            crate::session::handle_logout(shared, req).await
+        }
         ["tasks"] => {
-            handle_tasks(shared, req)
+            handle_tasks(shared, req, &user)
         }
         ["tasks", task_name] => {
-            handle_task(shared, req, task_name.to_owned()).await
+            handle_task(shared, req, task_name.to_owned(), &user).await
         }
         ["tasks", task_name, "run"] => {
-            handle_task_run(shared, req, task_name.to_owned()).await
+            handle_task_run(shared, req, task_name.to_owned(), user).await
+        }
+        ["tasks", task_name, "ws"] => {
+            handle_task_ws(shared, req, task_name.to_owned(), user).await
+        }
+        ["runs"] => {
+            handle_runs(shared, req, &user).await
+        }
+        ["history"] => {
+            handle_history(shared, req, &user).await
+        }
+        ["runs", run_id] => {
+            handle_run(shared, req, run_id, &user).await
+        }
+        ["runs", run_id, "output"] => {
+            handle_run_output(shared, req, run_id, &user).await
+        }
+        ["runs", run_id, "attach"] => {
+            handle_run_attach(shared, req, run_id, &user).await
+        }
+        ["runs", run_id, "cancel"] => {
+            handle_run_cancel(shared, req, run_id, &user).await
+        }
+        ["queue"] => {
+            handle_queue(shared, req)
+        }
+        ["schedules"] => {
+            handle_schedules(shared, req, &user)
+        }
+        ["admin", "reload"] => {
+            handle_reload(shared, req, &user).await
         }
         _ => {
             Err(ServerError::NotFound)
@@ -246,14 +488,86 @@ async fn match_path_api(shared: Arc<crate::Shared>, req: Request<Body>, path: &[
     }
 }
 
-fn handle_tasks(shared: Arc<crate::Shared>, req: Request<Body>) -> Result<Response<Body>, ServerError> {
+fn handle_session(req: Request<Body>, user: &UserPrincipal) -> Result<Response<Body>, ServerError> {
+    if req.method() != Method::GET {
+        return Err(ServerError::MethodNotAllowed);
+    }
+
+    let mut roles: Vec<String> = user.roles.iter().cloned().collect();
+    roles.sort();
+
+    let session_json = crate::json::SessionJson {
+        username: user.username.clone(),
+        roles,
+        guest: user.principal_type == PrincipalType::Guest,
+        csrf_token: user.csrf_token.clone(),
+    };
+
+    let session_bytes = serde_json::to_vec(&session_json).unwrap(); // TODO: handle error
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json; charset=utf-8")
+        .header("Cache-Control", "no-store")
+        .body(Body::from(session_bytes))
+        .unwrap(); // TODO: handle error
+
+    Ok(response)
+}
+
+async fn handle_reload(shared: Arc<crate::Shared>, req: Request<Body>, user: &UserPrincipal) -> Result<Response<Body>, ServerError> {
+    if req.method() != Method::POST {
+        return Err(ServerError::MethodNotAllowed);
+    }
+
+    if !user.is_admin(&shared.auth) {
+        warn!("User {} is not allowed to reload configuration", user.username);
+        return Err(user.forbidden());
+    }
+
+    info!("Reload requested by: {}", user.username);
+
+    // report what is in effect either way (previous definitions are kept if reload fails)
+    let (status, error) = match crate::reload::reload(&shared).await {
+        Ok(_) => (StatusCode::OK, None),
+        Err(err) => {
+            error!("Error reloading configuration (keeping previous definitions): {}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, Some(err.to_string()))
+        }
+    };
+
+    let summary = crate::reload::summary(&shared);
+
+    let reload_json = crate::json::ReloadJson {
+        reloaded: error.is_none(),
+        error,
+        tasks: summary.tasks,
+        users: summary.users,
+        tokens: summary.tokens,
+        secrets: summary.secrets,
+    };
+
+    let reload_bytes = serde_json::to_vec(&reload_json).unwrap(); // TODO: handle error
+
+    let response = Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json; charset=utf-8")
+        .body(Body::from(reload_bytes))
+        .unwrap(); // TODO: handle error
+
+    Ok(response)
+}
+
+fn handle_tasks(shared: Arc<crate::Shared>, req: Request<Body>, user: &UserPrincipal) -> Result<Response<Body>, ServerError> {
     if req.method() != Method::GET {
         return Err(ServerError::MethodNotAllowed);
     }
 
     let tasks = shared.tasks.read().unwrap(); // TODO: handle error
 
+    // only list tasks the user is allowed to run
     let tasks_json: Vec<crate::json::TaskJson> = tasks.iter()
+        .filter(|(_, task)| user.is_allowed(task))
         .map(|(_, task)| json_conv::to_task_json(task))
         .collect();
 
@@ -268,18 +582,19 @@ fn handle_tasks(shared: Arc<crate::Shared>, req: Request<Body>) -> Result<Respon
     Ok(response)
 }
 
-async fn handle_task(shared: Arc<crate::Shared>, req: Request<Body>, task_name: &str) -> Result<Response<Body>, ServerError> {
+async fn handle_task(shared: Arc<crate::Shared>, req: Request<Body>, task_name: &str, user: &UserPrincipal) -> Result<Response<Body>, ServerError> {
     if req.method() != Method::GET {
         return Err(ServerError::MethodNotAllowed);
     }
 
     let tasks = shared.tasks.read().unwrap(); // TODO: handle error
 
-    let task_json = tasks.get(task_name)
-        .map(|task| json_conv::to_task_json(task));
-
-    let task_json = match task_json {
-        Some(x) => x,
+    let task_json = match tasks.get(task_name) {
+        Some(task) if user.is_allowed(task) => json_conv::to_task_json(task),
+        Some(_) => {
+            warn!("User {} is not allowed to access task: {}", user.username, task_name);
+            return Err(user.forbidden());
+        }
         None => {
             return Err(ServerError::NotFound);
         }
@@ -296,20 +611,549 @@ async fn handle_task(shared: Arc<crate::Shared>, req: Request<Body>, task_name:
     Ok(response)
 }
 
-async fn handle_task_run(shared: Arc<crate::Shared>, req: Request<Body>, task_name: &str) -> Result<Response<Body>, ServerError> {
+async fn handle_task_run(shared: Arc<crate::Shared>, req: Request<Body>, task_name: &str, user: UserPrincipal) -> Result<Response<Body>, ServerError> {
+    let detach = prefers_async(&req);
+    let format = OutputFormat::from_request(&req);
+
     let task_req = parse_task_req(req, task_name).await?;
 
-    let task_exec = validate_task_req(shared, task_req)?;
+    let task_exec = validate_task_req(shared.clone(), task_req, &user)?;
+
+    let detach = detach || task_exec.detach;
+
+    info!("Executing task: {}{}", task_name, if detach { " (detached)" } else { "" });
+
+    exec_task(shared, task_exec, user, detach, format).await
+}
+
+/// Run a task over a WebSocket (the only way to send input to tasks that accept it)
+async fn handle_task_ws(shared: Arc<crate::Shared>, mut req: Request<Body>, task_name: &str, user: UserPrincipal) -> Result<Response<Body>, ServerError> {
+    let accept_key = crate::websocket::accept_key(&req)
+        .ok_or_else(|| ServerError::BadRequest("Expected a WebSocket upgrade request".to_owned()))?;
+
+    if req.method() != Method::GET {
+        return Err(ServerError::MethodNotAllowed);
+    }
+
+    if !crate::websocket::is_same_origin(&req) {
+        warn!("Rejecting WebSocket upgrade from another origin: {:?}", req.headers().get(header::ORIGIN));
+        return Err(ServerError::Forbidden);
+    }
+
+    let upgrade = hyper::upgrade::on(&mut req);
+
+    let mut task_req = parse_task_req(req, task_name).await?;
+    task_req.method = None; // parameters always come from the query string
+
+    let task_exec = validate_task_req(shared.clone(), task_req, &user)?;
+
+    let (input_tx, input_rx) = if task_exec.stdin {
+        let (input_tx, input_rx) = tokio::sync::mpsc::channel(crate::websocket::INPUT_BUFFER);
+        (Some(input_tx), Some(input_rx))
+    } else {
+        (None, None)
+    };
+
+    info!("Executing task: {} (WebSocket)", task_name);
+
+    // never detached (process is killed when the socket closes)
+    let (run, guard) = crate::run::start(shared, task_exec, &user, false, input_rx).await?;
+
+    let run_id = run.record().id;
+
+    tokio::spawn(crate::websocket::session(upgrade, run, guard, input_tx));
+
+    let response = Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(header::CONNECTION, "Upgrade")
+        .header(header::UPGRADE, "websocket")
+        .header(header::SEC_WEBSOCKET_ACCEPT, accept_key)
+        .header("X-Run-Id", run_id)
+        .body(Body::empty())
+        .unwrap(); // TODO: handle error
+
+    Ok(response)
+}
+
+/// Run a task for a webhook request, with parameters from its (JSON) body
+async fn handle_hook(shared: Arc<crate::Shared>, req: Request<Body>, task_name: &str) -> Result<Response<Body>, ServerError> {
+    if req.method() != Method::POST {
+        return Err(ServerError::MethodNotAllowed);
+    }
+
+    let has_webhook = shared.tasks.read().unwrap().get(task_name).is_some_and(|task| !task.webhooks.is_empty());
+
+    // (not reading bodies that would never be used)
+    if !has_webhook {
+        warn!("No webhook for task: {}", task_name);
+        return Err(ServerError::NotFound);
+    }
+
+    let (parts, body) = req.into_parts();
+
+    let body = crate::webhook::read_body(&parts.headers, body, crate::webhook::MAX_BODY_SIZE).await.map_err(|err| match err {
+        crate::webhook::BodyError::TooLarge => {
+            warn!("Webhook body for task {} is too large", task_name);
+            ServerError::PayloadTooLarge
+        }
+        crate::webhook::BodyError::Read(err) => {
+            error!("Error reading webhook body: {}", err);
+            ServerError::BadRequest("Unable to read body".to_owned())
+        }
+    })?;
+
+    let mapping = {
+        let tasks = shared.tasks.read().unwrap();
+
+        let webhooks = match tasks.get(task_name) {
+            Some(task) if !task.webhooks.is_empty() => &task.webhooks,
+            _ => {
+                warn!("No webhook for task: {}", task_name); // (removed by a reload while reading)
+                return Err(ServerError::NotFound);
+            }
+        };
+
+        // any of the task's webhooks (each with their own secret)
+        let mut result = Err(crate::webhook::SignatureError::Mismatch);
+        for webhook in webhooks {
+            result = crate::webhook::verify(webhook, &parts.headers, &body).map(|_| webhook.params.clone());
+            if result.is_ok() {
+                break;
+            }
+        }
+
+        result.map_err(|err| {
+            warn!("Rejected webhook for task {}: {}", task_name, err);
+            ServerError::Forbidden
+        })?
+    };
+
+    let params = if mapping.is_empty() {
+        HashMap::new()
+    } else {
+        let body_json: serde_json::Value = serde_json::from_slice(&body)
+            .map_err(|err| ServerError::BadRequest(format!("Invalid JSON: {}", err)))?;
+
+        crate::webhook::extract_params(&mapping, &body_json).map_err(ServerError::BadRequest)?
+    };
+
+    let task_req = TaskRequest {
+        name: task_name.to_owned(),
+        method: None,
+        params,
+    };
+
+    let user = UserPrincipal::webhook();
+
+    let task_exec = validate_task_req(shared.clone(), task_req, &user)?;
+
+    info!("Executing task: {} (webhook)", task_name);
+
+    // nobody is around to watch the output
+    exec_task(shared, task_exec, user, true, OutputFormat::Text).await
+}
+
+fn get_history(shared: &Arc<crate::Shared>) -> Result<&History, ServerError> {
+    shared.history.as_ref().ok_or_else(|| {
+        warn!("Execution history is not configured");
+        ServerError::NotFound
+    })
+}
+
+/// Runs are only visible to users that are allowed to run the task (or to admins, once the task
+/// is gone and there's no telling who was allowed)
+fn can_see_run(shared: &Arc<crate::Shared>, run: &RunRecord, user: &UserPrincipal) -> bool {
+    shared.tasks.read().unwrap()
+        .get(&run.task)
+        .map(|task| user.is_allowed(task))
+        .unwrap_or_else(|| user.is_admin(&shared.auth))
+}
+
+/// Active and stored runs the user can see, most recent first
+async fn list_runs(shared: &Arc<crate::Shared>, user: &UserPrincipal) -> Result<Vec<RunRecord>, ServerError> {
+    let mut runs: Vec<RunRecord> = shared.runs.read().unwrap()
+        .values()
+        .map(|run| run.record())
+        .collect();
+
+    if let Some(history) = &shared.history {
+        let stored = history.list().await.map_err(|err| {
+            error!("Error listing runs: {}", err);
+            ServerError::InternalServerError
+        })?;
+
+        // active runs are also stored (but stored record won't be up-to-date)
+        let active: HashSet<String> = runs.iter().map(|run| run.id.clone()).collect();
+
+        runs.extend(stored.into_iter().filter(|run| !active.contains(&run.id)));
+    }
+
+    runs.retain(|run| can_see_run(shared, run, user));
+
+    runs.sort_by_key(|run| Reverse(run.started_at));
+
+    Ok(runs)
+}
+
+async fn handle_runs(shared: Arc<crate::Shared>, req: Request<Body>, user: &UserPrincipal) -> Result<Response<Body>, ServerError> {
+    if req.method() != Method::GET {
+        return Err(ServerError::MethodNotAllowed);
+    }
+
+    let runs = list_runs(&shared, user).await?;
+
+    let runs_json: Vec<crate::json::RunJson> = runs.iter()
+        .map(json_conv::to_run_json)
+        .collect();
+
+    let runs_bytes = serde_json::to_vec(&runs_json).unwrap(); // TODO: handle error
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json; charset=utf-8")
+        .body(Body::from(runs_bytes))
+        .unwrap(); // TODO: handle error
+
+    Ok(response)
+}
+
+const DEFAULT_PER_PAGE: usize = 20;
+const MAX_PER_PAGE: usize = 100;
+
+/// Runs a page at a time, optionally filtered by task, user and outcome ('running', 'success' or 'failure')
+async fn handle_history(shared: Arc<crate::Shared>, req: Request<Body>, user: &UserPrincipal) -> Result<Response<Body>, ServerError> {
+    if req.method() != Method::GET {
+        return Err(ServerError::MethodNotAllowed);
+    }
+
+    let mut query = get_query_params(&req);
+
+    let parse_number = |name: &str, value: Option<String>, default: usize| -> Result<usize, ServerError> {
+        match value {
+            Some(value) => value.parse::<usize>().ok()
+                .filter(|number| *number > 0)
+                .ok_or_else(|| ServerError::BadRequest(format!("Invalid {}: {}", name, value))),
+            None => Ok(default)
+        }
+    };
+
+    let page = parse_number("page", query.remove("page"), 1)?;
+    let per_page = parse_number("per_page", query.remove("per_page"), DEFAULT_PER_PAGE)?.min(MAX_PER_PAGE);
+
+    let outcome = match query.remove("outcome").as_deref().filter(|outcome| !outcome.is_empty()) {
+        Some("running") => Some(RunOutcome::Running),
+        Some("success") => Some(RunOutcome::Success),
+        Some("failure") => Some(RunOutcome::Failure),
+        Some(other) => {
+            return Err(ServerError::BadRequest(format!("Unknown outcome: {}", other)));
+        }
+        None => None
+    };
+
+    let task = query.remove("task").filter(|task| !task.is_empty());
+    let username = query.remove("user").filter(|username| !username.is_empty());
+
+    let mut runs = list_runs(&shared, user).await?;
+
+    runs.retain(|run| {
+        task.as_ref().map(|task| &run.task == task).unwrap_or(true)
+            && username.as_ref().map(|username| &run.username == username).unwrap_or(true)
+            && outcome.map(|outcome| run.outcome() == outcome).unwrap_or(true)
+    });
+
+    let history_json = crate::json::RunHistoryJson {
+        total: runs.len(),
+        runs: runs.iter()
+            .skip((page - 1).saturating_mul(per_page))
+            .take(per_page)
+            .map(json_conv::to_run_json)
+            .collect(),
+        page,
+        per_page,
+    };
+
+    let history_bytes = serde_json::to_vec(&history_json).unwrap(); // TODO: handle error
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json; charset=utf-8")
+        .body(Body::from(history_bytes))
+        .unwrap(); // TODO: handle error
+
+    Ok(response)
+}
+
+/// How busy the server is (only counts, so anyone can see it)
+fn handle_queue(shared: Arc<crate::Shared>, req: Request<Body>) -> Result<Response<Body>, ServerError> {
+    if req.method() != Method::GET {
+        return Err(ServerError::MethodNotAllowed);
+    }
+
+    let usage = shared.slots.usage();
+
+    let queue_json = crate::json::QueueJson {
+        max_concurrent_runs: usage.max_running,
+        running: usage.running,
+        queued: usage.queued,
+    };
+
+    let queue_bytes = serde_json::to_vec(&queue_json).unwrap(); // TODO: handle error
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json; charset=utf-8")
+        .body(Body::from(queue_bytes))
+        .unwrap(); // TODO: handle error
+
+    Ok(response)
+}
+
+fn handle_schedules(shared: Arc<crate::Shared>, req: Request<Body>, user: &UserPrincipal) -> Result<Response<Body>, ServerError> {
+    if req.method() != Method::GET {
+        return Err(ServerError::MethodNotAllowed);
+    }
+
+    let tasks = shared.tasks.read().unwrap(); // TODO: handle error
+
+    // only list schedules of tasks the user is allowed to run
+    let mut schedules_json: Vec<crate::json::ScheduleJson> = tasks.values()
+        .filter(|task| user.is_allowed(task))
+        .flat_map(|task| task.schedules.iter().enumerate().map(move |(index, schedule)| (task, index, schedule)))
+        .map(|(task, index, schedule)| {
+            let status = shared.scheduler.status(&task.name, index, schedule);
+            crate::json::ScheduleJson {
+                task: task.name.clone(),
+                cron: schedule.cron.to_string(),
+                params: schedule.params.clone(),
+                missed: schedule.missed.into(),
+                overlap: schedule.overlap.into(),
+                next_run_at: status.next_run_at,
+                last_run_id: status.last_run_id,
+            }
+        })
+        .collect();
+
+    // soonest first (any that won't run again last)
+    schedules_json.sort_by(|a, b| {
+        (a.next_run_at.is_none(), a.next_run_at, &a.task).cmp(&(b.next_run_at.is_none(), b.next_run_at, &b.task))
+    });
+
+    let schedules_bytes = serde_json::to_vec(&schedules_json).unwrap(); // TODO: handle error
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json; charset=utf-8")
+        .body(Body::from(schedules_bytes))
+        .unwrap(); // TODO: handle error
+
+    Ok(response)
+}
+
+fn get_active_run(shared: &Arc<crate::Shared>, run_id: &str) -> Option<Arc<ActiveRun>> {
+    shared.runs.read().unwrap().get(run_id).cloned()
+}
+
+async fn load_run(shared: &Arc<crate::Shared>, run_id: &str, user: &UserPrincipal) -> Result<RunRecord, ServerError> {
+    let run = match get_active_run(shared, run_id) {
+        Some(run) => run.record(),
+        None => {
+            get_history(shared)?.load(run_id).await
+                .map_err(|err| {
+                    error!("Error loading run: {} ({})", run_id, err);
+                    ServerError::InternalServerError
+                })?
+                .ok_or(ServerError::NotFound)?
+        }
+    };
+
+    if !can_see_run(shared, &run, user) {
+        warn!("User {} is not allowed to access run: {}", user.username, run_id);
+        return Err(user.forbidden());
+    }
+
+    Ok(run)
+}
+
+async fn handle_run(shared: Arc<crate::Shared>, req: Request<Body>, run_id: &str, user: &UserPrincipal) -> Result<Response<Body>, ServerError> {
+    if req.method() != Method::GET {
+        return Err(ServerError::MethodNotAllowed);
+    }
+
+    let run = load_run(&shared, run_id, user).await?;
+
+    let run_bytes = serde_json::to_vec(&json_conv::to_run_json(&run)).unwrap(); // TODO: handle error
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json; charset=utf-8")
+        .body(Body::from(run_bytes))
+        .unwrap(); // TODO: handle error
+
+    Ok(response)
+}
+
+async fn handle_run_cancel(shared: Arc<crate::Shared>, req: Request<Body>, run_id: &str, user: &UserPrincipal) -> Result<Response<Body>, ServerError> {
+    if req.method() != Method::POST {
+        return Err(ServerError::MethodNotAllowed);
+    }
+
+    let run = match get_active_run(&shared, run_id) {
+        Some(run) => run,
+        None => {
+            let run = load_run(&shared, run_id, user).await?; // not found, or finished
+            return Err(ServerError::Conflict(format!("Run has already finished: {}", run.id)));
+        }
+    };
+
+    if !can_see_run(&shared, &run.record(), user) {
+        warn!("User {} is not allowed to cancel run: {}", user.username, run_id);
+        return Err(user.forbidden());
+    }
+
+    if run.cancel(&user.username) {
+        info!("Run cancelled by {}: {}", user.username, run_id);
+    }
 
-    info!("Executing task: {}", task_name);
+    // respond with the final status (may take up to the grace period)
+    let record = run.finished().await;
 
-    exec_task(task_exec)
+    let run_bytes = serde_json::to_vec(&json_conv::to_run_json(&record)).unwrap(); // TODO: handle error
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json; charset=utf-8")
+        .body(Body::from(run_bytes))
+        .unwrap(); // TODO: handle error
+
+    Ok(response)
+}
+
+async fn handle_run_output(shared: Arc<crate::Shared>, req: Request<Body>, run_id: &str, user: &UserPrincipal) -> Result<Response<Body>, ServerError> {
+    if req.method() != Method::GET {
+        return Err(ServerError::MethodNotAllowed);
+    }
+
+    let output_file = match get_query_param(&req, "stream").as_deref() {
+        None => OutputFile::Combined,
+        Some("stdout") => OutputFile::Stdout,
+        Some("stderr") => OutputFile::Stderr,
+        Some(other) => {
+            return Err(ServerError::BadRequest(format!("Unknown output stream: {}", other)));
+        }
+    };
+
+    let run = load_run(&shared, run_id, user).await?;
+
+    let path = get_history(&shared)?.output_path(&run.id, output_file);
+
+    let bytes = tokio::fs::read(&path).await.map_err(|err| {
+        error!("Error reading run output: {:?} ({})", path, err);
+        ServerError::InternalServerError
+    })?;
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .header("X-Content-Type-Options", "nosniff")
+        .body(Body::from(bytes))
+        .unwrap();
+
+    Ok(response)
+}
+
+async fn handle_run_attach(shared: Arc<crate::Shared>, req: Request<Body>, run_id: &str, user: &UserPrincipal) -> Result<Response<Body>, ServerError> {
+    if req.method() != Method::GET {
+        return Err(ServerError::MethodNotAllowed);
+    }
+
+    let offset = match get_query_param(&req, "offset") {
+        Some(value) => value.parse::<usize>().map_err(|_| {
+            ServerError::BadRequest(format!("Invalid offset: {}", value))
+        })?,
+        None => 0
+    };
+
+    let format = OutputFormat::from_request(&req);
+
+    let body = match get_active_run(&shared, run_id) {
+        Some(run) => {
+            if !can_see_run(&shared, &run.record(), user) {
+                warn!("User {} is not allowed to access run: {}", user.username, run_id);
+                return Err(user.forbidden());
+            }
+
+            format.body(crate::run::events(run, offset))
+        }
+        None => {
+            // already finished, replay whatever was stored
+            let run = load_run(&shared, run_id, user).await?;
+
+            match format {
+                OutputFormat::Text => {
+                    let output = read_output(&shared, &run.id, OutputFile::Combined).await?;
+
+                    let mut lines: String = output.split_inclusive('\n').skip(offset).collect();
+                    lines.push_str(&crate::run::trailer(&run));
+
+                    Body::from(lines)
+                }
+                _ => {
+                    format.body(futures::stream::iter(stored_events(&shared, run, offset).await?))
+                }
+            }
+        }
+    };
+
+    let response = format.response()
+        .body(body)
+        .unwrap();
+
+    Ok(response)
+}
+
+async fn read_output(shared: &Arc<crate::Shared>, run_id: &str, file: OutputFile) -> Result<String, ServerError> {
+    let path = get_history(shared)?.output_path(run_id, file);
+
+    tokio::fs::read_to_string(&path).await.map_err(|err| {
+        error!("Error reading run output: {:?} ({})", path, err);
+        ServerError::InternalServerError
+    })
+}
+
+/// Events for a finished run. Only the streams are stored separately, not how they were
+/// interleaved, so all of stdout comes before stderr (the offset counts lines of both).
+async fn stored_events(shared: &Arc<crate::Shared>, run: RunRecord, offset: usize) -> Result<Vec<RunEvent>, ServerError> {
+    let stdout = read_output(shared, &run.id, OutputFile::Stdout).await?;
+    let stderr = read_output(shared, &run.id, OutputFile::Stderr).await?;
+
+    let lines = stdout.split_inclusive('\n').map(|line| (OutputStream::Stdout, line))
+        .chain(stderr.split_inclusive('\n').map(|line| (OutputStream::Stderr, line)))
+        .enumerate()
+        .skip(offset)
+        .map(|(seq, (stream, text))| RunEvent::Output(OutputLine { seq, stream, time: None, text: text.to_owned() }));
+
+    let mut events = vec![RunEvent::Started { run_id: run.id.clone(), pid: None }];
+    events.extend(lines);
+    events.push(RunEvent::Finished(run));
+
+    Ok(events)
+}
+
+fn get_query_params<T>(req: &Request<T>) -> HashMap<String, String> {
+    // (only the query string is needed, so there's no URL to fail to parse)
+    req.uri().query()
+        .map(|query| form_urlencoded::parse(query.as_bytes()).into_owned().collect())
+        .unwrap_or_default()
+}
+
+fn get_query_param<T>(req: &Request<T>, name: &str) -> Option<String> {
+    get_query_params(req).remove(name)
 }
 
 async fn parse_task_req(req: Request<Body>, task_name: &str) -> Result<TaskRequest, ServerError> {
     let method = req.method().clone();
 
-    let params = match *req.method() {
+    let mut params = match *req.method() {
         Method::POST => {
             match req.headers().get(header::CONTENT_TYPE) {
                 Some(value) => {
@@ -333,28 +1177,25 @@ async fn parse_task_req(req: Request<Body>, task_name: &str) -> Result<TaskReque
             }
         }
         Method::GET => {
-            let url = format!("http://127.0.0.1/{}", req.uri().to_string()); // URLs must be relative
-            Url::parse(&url)
-                .expect(&format!("Malformed URL: {}", url))
-                .query_pairs()
-                .into_owned()
-                .collect()
+            get_query_params(&req)
         }
         _ => {
             return Err(ServerError::MethodNotAllowed);
         }
     };
 
+    params.remove(crate::session::CSRF_FORM_FIELD); // already checked (not a task parameter)
+
     let task_req = TaskRequest {
         name: task_name.to_owned(),
-        method,
+        method: Some(method),
         params,
     };
 
     Ok(task_req)
 }
 
-fn param_to_string(param: &TaskParameterValue) -> String {
+pub fn param_to_string(param: &TaskParameterValue) -> String {
     match param {
         TaskParameterValue::String(s) => s.clone(),
         TaskParameterValue::Number(n) => n.to_string(),
@@ -362,7 +1203,7 @@ fn param_to_string(param: &TaskParameterValue) -> String {
     }
 }
 
-fn validate_params(req_params: HashMap<String, String>,
+pub fn validate_params(req_params: HashMap<String, String>,
                    task_params: &Vec<TaskDefParameter>) -> Result<HashMap<String, String>, ServerError> {
     let mut result = HashMap::<String, String>::new();
 
@@ -401,7 +1242,7 @@ fn validate_params(req_params: HashMap<String, String>,
     Ok(result)
 }
 
-fn validate_task_req(shared: Arc<crate::Shared>, task_req: TaskRequest) -> Result<TaskExec, ServerError> {
+pub fn validate_task_req(shared: Arc<crate::Shared>, task_req: TaskRequest, user: &UserPrincipal) -> Result<TaskExec, ServerError> {
     let tasks = shared.tasks.read().unwrap();
 
     let task_def = match tasks.get(&task_req.name) {
@@ -412,99 +1253,110 @@ fn validate_task_req(shared: Arc<crate::Shared>, task_req: TaskRequest) -> Resul
         }
     };
 
+    if !user.is_allowed(task_def) {
+        warn!("User {} is not allowed to run task: {}", user.username, task_req.name);
+        return Err(user.forbidden());
+    }
+
     let allowed_methods: HashSet<Method> = task_def.method.iter()
         .map(|method| match method {
             TaskMethod::GET => Method::GET,
             TaskMethod::POST => Method::POST
         }).collect();
 
-    if !allowed_methods.contains(&task_req.method) {
-        error!("Method not allowed for task: {}", task_req.method);
-        return Err(ServerError::MethodNotAllowed);
+    if let Some(method) = &task_req.method {
+        if !allowed_methods.contains(method) {
+            error!("Method not allowed for task: {}", method);
+            return Err(ServerError::MethodNotAllowed);
+        }
     }
 
     let params = validate_params(task_req.params, &task_def.parameters)?;
 
-    let env: HashMap<String, String> = task_def.parameters.iter()
+    let mut env: HashMap<String, String> = HashMap::new();
+    let mut secret_env: HashMap<String, String> = HashMap::new();
+
+    for (name, value) in &task_def.exec.env {
+        match value {
+            TaskDefEnv::Value(value) => env.insert(name.clone(), value.clone()),
+            TaskDefEnv::Secret(secret) => secret_env.insert(name.clone(), secret.clone()),
+        };
+    }
+
+    env.extend(task_def.parameters.iter()
         .flat_map(|task_param| {
             match (&task_param.env, params.get(&task_param.name)) {
                 (Some(env), Some(value)) => Some((env.to_owned(), value.to_owned())),
                 _ => None
             }
-        })
+        }));
+
+    let secret_params: HashSet<String> = task_def.parameters.iter()
+        .filter(|task_param| matches!(task_param._type, TaskParameterType::Secret))
+        .map(|task_param| task_param.name.clone())
         .collect();
 
     let args = task_def.exec.args.as_ref().map(|x| x.clone()).unwrap_or(Vec::new());
 
     Ok(TaskExec {
+        name: task_def.name.clone(),
+        detach: task_def.exec.detach,
+        params,
+        secret_params,
         command: task_def.exec.command.clone(),
         args,
         dir: task_def.exec.dir.clone(),
         env,
+        secret_env,
+        timeout: task_def.exec.timeout.or(shared.exec.timeout),
+        grace_period: task_def.exec.grace_period.unwrap_or(shared.exec.grace_period),
+        concurrency: task_def.concurrency,
+        lock: task_def.lock.clone(),
+        queue: task_def.queue,
+        stdin: task_def.exec.stdin,
+        notify: task_def.notify.clone(),
+        email: task_def.email.clone(),
     })
 }
 
-fn exec_task(task: TaskExec) -> Result<Response<Body>, ServerError> {
-    info!("Executing: {:?}", task); // TODO: may need a 'secret' parameter type to avoid logging secret parameters here
-
-    let args: Vec<OsString> = task.args
+/// Clients can ask for a run to be detached from the connection (RFC 7240)
+fn prefers_async<T>(req: &Request<T>) -> bool {
+    req.headers().get_all(header::HeaderName::from_static("prefer"))
         .iter()
-        .map(|a| OsString::from(&a))
-        .collect();
-
-    let mut command = Command::new(&task.command);
-
-    // kill process if the connection is dropped (if nobody is around to see output process shouldn't keep running)
-    command.current_dir(task.dir)
-        .args(&args)
-        .envs(&task.env)
-        .kill_on_drop(true) // this seems to leave zombies around...
-        .stdout(std::process::Stdio::piped())
-        .stderr(std::process::Stdio::piped());
-
-    let mut child = command.spawn().map_err(|e| {
-        error!("Error executing command: {:?}", e);
-        ServerError::InternalServerError
-    })?;
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|pref| pref.trim().eq_ignore_ascii_case("respond-async"))
+}
 
-    let stdout = child.stdout.take().unwrap(); // TODO: handle not having both streams
-    let stderr = child.stderr.take().unwrap();
+async fn exec_task(shared: Arc<crate::Shared>, task: TaskExec, user: UserPrincipal, detach: bool, format: OutputFormat) -> Result<Response<Body>, ServerError> {
+    let (run, guard) = crate::run::start(shared, task, &user, detach, None).await?;
 
-    // not buffering so that all output (however small) is sent through to browser
-    let stdout_stream = LinesStream::new(BufReader::with_capacity(1, stdout).lines()).map(|line| line.map(|l| l + "\n"));
-    let stderr_stream = LinesStream::new(BufReader::with_capacity(1, stderr).lines()).map(|line| line.map(|l| l + "\n"));
+    let record = run.record();
 
-    let interleaved = crate::interleave::Interleave::new(
-        stdout_stream.fuse(),
-        stderr_stream.fuse());
+    if detach {
+        let run_bytes = serde_json::to_vec(&json_conv::to_run_json(&record)).unwrap(); // TODO: handle error
 
-    // using 'wait_with_output()' rather than 'wait()' (even though we don't need the process output), as this function takes ownership of child (not sure if there's a better way to do this)
-    let exit_code_fut = child.wait_with_output().map_ok(|output| {
-        let exit_code = output.status;
+        let response = Response::builder()
+            .status(StatusCode::ACCEPTED)
+            .header("Content-Type", "application/json; charset=utf-8")
+            .header("Location", format!("/api/runs/{}", record.id))
+            .header("X-Run-Id", record.id)
+            .body(Body::from(run_bytes))
+            .unwrap();
 
-        info!("Process exited with code: {}",
-            exit_code.code()
-            .map(|x| x.to_string())
-            .unwrap_or("<none>".to_owned()));
+        return Ok(response);
+    }
 
-        format!("[Exit code: {}]",
-                exit_code.code()
-                    .map(|x| x.to_string())
-                    .unwrap_or("<unknown>".to_owned()))
+    // process is killed when the guard is dropped along with the response body
+    let events = crate::run::events(run, 0).map(move |event| {
+        let _guard = &guard;
+        event
     });
 
-    let exit_code = futures::stream::once(exit_code_fut);
-
-    let chained = interleaved.chain(exit_code);
-
-    let body = Body::wrap_stream(chained);
-
-    // being very explicit about content type to prevent browser from buffering (so every line is printed as it executes)
-    let response = Response::builder()
-        .header("Content-Type", "text/plain; charset=utf-8")
-        .header("X-Content-Type-Options", "nosniff")
-        .body(body)
+    let response = format.response()
+        .header("X-Run-Id", record.id)
+        .body(format.body(events))
         .unwrap();
 
     Ok(response)
-}
\ No newline at end of file
+}
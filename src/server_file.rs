@@ -12,21 +12,102 @@ use crate::task_file::ConfigFileError;
 pub struct ServerToml {
     pub server: Option<ServerServerToml>,
     pub auth: Option<ServerAuthToml>,
+    pub history: Option<ServerHistoryToml>,
+    pub exec: Option<ServerExecToml>,
+    pub scheduler: Option<ServerSchedulerToml>,
+    /// Sent for runs of every task (as well as any in task files)
+    #[serde(default)]
+    pub notify: Vec<crate::task_file::Notify>,
+    pub smtp: Option<ServerSmtpToml>,
+    pub secrets: Option<ServerSecretsToml>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct ServerServerToml {
     pub listen: Option<String>,
     pub dir: Option<String>,
+    /// Reload when task files, secrets or this file change (otherwise only on SIGHUP or 'POST /api/admin/reload')
+    pub watch: Option<bool>,
+    /// Where users find the server (eg, 'https://henchman.example.com'), for links in emails
+    pub url: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
-pub struct ServerAuthToml {
+pub struct ServerHistoryToml {
+    /// Directory to store run records and their output (relative to the server configuration file)
+    pub dir: String,
+}
+
+/// Defaults for all tasks (tasks can override these in their own '[exec]' section)
+#[derive(Debug, Deserialize)]
+pub struct ServerExecToml {
+    /// Stop processes that run longer than this (eg, '1h')
+    pub timeout: Option<String>,
+    /// How long to wait after asking a process to stop before killing it (defaults to '10s')
+    #[serde(rename = "grace-period")]
+    pub grace_period: Option<String>,
+    /// Runs wait in a queue while this many are already executing (no limit if not set)
+    #[serde(rename = "max-concurrent-runs")]
+    pub max_concurrent_runs: Option<usize>,
+}
+
+/// Runs started by '[[schedule]]' entries in task files
+#[derive(Debug, Deserialize)]
+pub struct ServerSchedulerToml {
+    /// Start scheduled runs (defaults to true)
+    pub enabled: Option<bool>,
+    /// Recorded as having started scheduled runs (defaults to 'scheduler')
+    pub username: Option<String>,
+    /// Roles for scheduled runs (tasks that aren't allowed for any of these aren't run)
     #[serde(default)]
-    pub users: Vec<ServerAuthUserToml>
+    pub roles: Vec<String>,
+}
+
+/// Mail server for sending '[[email]]' notifications in task files
+#[derive(Debug, Deserialize)]
+pub struct ServerSmtpToml {
+    pub host: String,
+    /// Defaults to 587 (or 25 without STARTTLS)
+    pub port: Option<u16>,
+    /// Switch to TLS before logging in or sending anything (defaults to true)
+    pub starttls: Option<bool>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Log in even without STARTTLS, sending the password in the clear (defaults to false)
+    #[serde(rename = "allow-insecure-auth")]
+    pub allow_insecure_auth: Option<bool>,
+    /// Sender address (eg, 'Henchman <henchman@example.com>')
+    pub from: String,
 }
 
+/// Values for '{ secret = "..." }' in task files' '[exec.env]' (reloaded along with task files)
 #[derive(Debug, Deserialize)]
+pub struct ServerSecretsToml {
+    /// TOML file of 'name = "value"' (relative to this file, only readable by its owner)
+    pub file: Option<String>,
+    /// Directory with a file per secret, named after it (eg, '/run/credentials/henchman.service')
+    pub dir: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ServerAuthToml {
+    /// Require authentication (defaults to true)
+    pub enabled: Option<bool>,
+    /// Allow unauthenticated requests (with the guest roles)
+    pub guest: Option<bool>,
+    #[serde(rename = "guest-roles")]
+    #[serde(default)]
+    pub guest_roles: Vec<String>,
+    /// Roles allowed to administer the server (defaults to 'ADMIN')
+    #[serde(rename = "admin-roles")]
+    pub admin_roles: Option<Vec<String>>,
+    #[serde(default)]
+    pub users: Vec<ServerAuthUserToml>,
+    #[serde(default)]
+    pub tokens: Vec<ServerAuthTokenToml>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct ServerAuthUserToml {
     pub username: String,
     pub password: String,
@@ -34,6 +115,16 @@ pub struct ServerAuthUserToml {
     pub roles: Vec<String>,
 }
 
+/// API token for machine clients (see `henchman-password --token`)
+#[derive(Debug, Clone, Deserialize)]
+pub struct ServerAuthTokenToml {
+    pub name: String,
+    pub hash: String,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    pub expires: Option<toml::value::Datetime>,
+}
+
 pub fn load_toml<T>(path: &Path) -> Result<T, ConfigFileError> where T: serde::de::DeserializeOwned {
     let from_io_err = |err: IoError| -> ConfigFileError {
         ConfigFileError::Io(err, Some(path.into()))
@@ -77,5 +168,15 @@ mod tests {
         assert_eq!(server_toml.auth.as_ref().unwrap().users[0].username, "admin");
         assert_eq!(server_toml.auth.as_ref().unwrap().users[0].password, EXPECTED_PASSWORD_HASH);
         assert_eq!(server_toml.auth.as_ref().unwrap().users[0].roles, vec!["ADMIN"]);
+
+        assert_eq!(server_toml.auth.as_ref().unwrap().tokens.len(), 2);
+        assert_eq!(server_toml.auth.as_ref().unwrap().tokens[0].name, "ci");
+        assert_eq!(server_toml.auth.as_ref().unwrap().tokens[0].roles, vec!["DEPLOYER"]);
+        assert!(server_toml.auth.as_ref().unwrap().tokens[0].expires.is_none());
+        assert_eq!(server_toml.auth.as_ref().unwrap().tokens[1].expires.as_ref().map(|x| x.to_string()),
+                   Some("2020-01-01T00:00:00Z".to_owned()));
+
+        assert!(server_toml.history.is_some());
+        assert_eq!(server_toml.history.as_ref().unwrap().dir, "../../target/test-history");
     }
-}
\ No newline at end of file
+}
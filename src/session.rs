@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use http::{header, HeaderValue, Method, StatusCode};
+use hyper::{Body, Request, Response};
+use url::form_urlencoded;
+
+use crate::{LoginSession, Shared};
+use crate::password::DUMMY_PASSWORD;
+use crate::server::{ServerError, UserPrincipal};
+
+pub const SESSION_ID_COOKIE: &str = "session_id";
+
+/// Form field for the CSRF token (alternatively, the 'X-CSRF-Token' header)
+pub const CSRF_FORM_FIELD: &str = "_csrf";
+const CSRF_HEADER: &str = "x-csrf-token";
+
+const LOGIN_SESSION_PERIOD: Duration = Duration::from_secs(12 * 60 * 60); // 12 hours
+
+const LOGIN_PAGE: &str = "/web/login";
+const DEFAULT_REDIRECT: &str = "/web/tasks";
+
+fn prune_expired(sessions: &mut HashMap<String, LoginSession>) {
+    let now = Instant::now();
+
+    sessions.retain(|_k, v| {
+        v.expires_at > now
+    });
+}
+
+/// User of the session identified by the request's session cookie (if it hasn't expired)
+pub fn get_session_user(shared: &Arc<Shared>, req: &Request<Body>) -> Result<Option<UserPrincipal>, ServerError> {
+    let session_id = match crate::utils::get_cookie_value(req, SESSION_ID_COOKIE)? {
+        Some(session_id) => session_id,
+        None => {
+            return Ok(None);
+        }
+    };
+
+    let sessions = shared.login_sessions.read().map_err(|err| {
+        error!("Could not obtain login sessions lock: {:?}", err);
+        ServerError::InternalServerError
+    })?;
+
+    let session = match sessions.get(&session_id) {
+        Some(session) if session.expires_at > Instant::now() => session,
+        _ => {
+            info!("Session not found or expired");
+            return Ok(None);
+        }
+    };
+
+    let users = shared.users.read().map_err(|err| {
+        error!("Could not obtain users lock: {:?}", err);
+        ServerError::InternalServerError
+    })?;
+
+    // look up the user every time (in case they've been removed)
+    match users.get(&session.username) {
+        Some(user_def) => {
+            let mut principal = UserPrincipal::from(user_def);
+            principal.csrf_token = Some(session.csrf_token.clone());
+            Ok(Some(principal))
+        }
+        None => {
+            warn!("Session user no longer exists: {}", session.username);
+            Ok(None)
+        }
+    }
+}
+
+/// Browsers send cookies with every request, so a POST authenticated by session cookie must also
+/// prove it came from one of our own pages (body is buffered to check the form field, if needed)
+pub async fn ensure_csrf(req: Request<Body>, expected: &str) -> Result<Request<Body>, ServerError> {
+    let matches = |value: &str| {
+        ring::constant_time::verify_slices_are_equal(value.as_bytes(), expected.as_bytes()).is_ok()
+    };
+
+    if let Some(value) = req.headers().get(CSRF_HEADER) {
+        return if value.to_str().map(matches).unwrap_or(false) {
+            Ok(req)
+        } else {
+            warn!("Invalid CSRF token header");
+            Err(ServerError::Forbidden)
+        };
+    }
+
+    let is_form = req.headers().get(header::CONTENT_TYPE)
+        .map(|value| value == HeaderValue::from_static("application/x-www-form-urlencoded"))
+        .unwrap_or(false);
+
+    if !is_form {
+        warn!("Missing CSRF token");
+        return Err(ServerError::Forbidden);
+    }
+
+    let (parts, body) = req.into_parts();
+
+    let body = hyper::body::to_bytes(body).await.map_err(|err| {
+        error!("Error reading request body: {}", err);
+        ServerError::InternalServerError
+    })?;
+
+    let valid = form_urlencoded::parse(&body)
+        .any(|(name, value)| name == CSRF_FORM_FIELD && matches(&value));
+
+    if !valid {
+        warn!("Missing or invalid CSRF token form field");
+        return Err(ServerError::Forbidden);
+    }
+
+    Ok(Request::from_parts(parts, Body::from(body)))
+}
+
+/// Browsers can't set headers when opening a WebSocket, so the token must be in the query string
+/// instead (the handshake is a GET request, which would otherwise need no token at all)
+pub fn ensure_csrf_query(req: Request<Body>, expected: &str) -> Result<Request<Body>, ServerError> {
+    let valid = req.uri().query()
+        .map(|query| form_urlencoded::parse(query.as_bytes())
+            .any(|(name, value)| name == CSRF_FORM_FIELD
+                && ring::constant_time::verify_slices_are_equal(value.as_bytes(), expected.as_bytes()).is_ok()))
+        .unwrap_or(false);
+
+    if !valid {
+        warn!("Missing or invalid CSRF token query parameter");
+        return Err(ServerError::Forbidden);
+    }
+
+    Ok(req)
+}
+
+/// Only redirect to paths on this server (don't want to be an open redirect)
+fn safe_redirect(redirect: Option<&str>) -> &str {
+    match redirect {
+        Some(path) if path.starts_with('/') && !path.starts_with("//") && !path.contains('\\') => path,
+        _ => DEFAULT_REDIRECT
+    }
+}
+
+fn redirect(location: &str) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(header::LOCATION, location)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Send browsers to the login page, coming back to the current page afterwards
+pub fn redirect_to_login<T>(req: &Request<T>) -> Response<Body> {
+    let current = req.uri().path_and_query().map(|x| x.as_str()).unwrap_or(DEFAULT_REDIRECT);
+
+    let query = form_urlencoded::Serializer::new(String::new())
+        .append_pair("redirect", current)
+        .finish();
+
+    redirect(&format!("{}?{}", LOGIN_PAGE, query))
+}
+
+pub async fn handle_login(shared: Arc<Shared>, req: Request<Body>) -> Result<Response<Body>, ServerError> {
+    if req.method() != Method::POST {
+        return Err(ServerError::MethodNotAllowed);
+    }
+
+    let body = hyper::body::to_bytes(req.into_body()).await.map_err(|err| {
+        error!("Error reading request body: {}", err);
+        ServerError::InternalServerError
+    })?;
+
+    let form: HashMap<String, String> = form_urlencoded::parse(&body)
+        .into_owned()
+        .collect();
+
+    let username = form.get("username").map(|x| x.as_str()).unwrap_or("");
+    let password = form.get("password").map(|x| x.as_str()).unwrap_or("");
+    let redirect_path = safe_redirect(form.get("redirect").map(|x| x.as_str()));
+
+    // (not holding the lock while verifying, which is deliberately slow)
+    let expected = {
+        let users = shared.users.read().map_err(|err| {
+            error!("Could not obtain users lock: {:?}", err);
+            ServerError::InternalServerError
+        })?;
+
+        users.get(username).map(|user_def| user_def.password.clone())
+    };
+
+    // unknown users are checked against a dummy password, so that they can't be told apart by
+    // how long it takes
+    let verified = match crate::password::verify_password_parts(password, expected.as_ref().unwrap_or(&DUMMY_PASSWORD)) {
+        Ok(verified) => verified && expected.is_some(),
+        Err(err) => {
+            error!("Error verifying password: {:?}", err);
+            return Err(ServerError::InternalServerError);
+        }
+    };
+
+    if !verified {
+        warn!("Failed login for user: {}", username);
+
+        let query = form_urlencoded::Serializer::new(String::new())
+            .append_pair("error", "invalid")
+            .append_pair("redirect", redirect_path)
+            .finish();
+
+        return Ok(redirect(&format!("{}?{}", LOGIN_PAGE, query)));
+    }
+
+    let session_id = crate::password::generate_secret();
+
+    let expires_at = Instant::now().checked_add(LOGIN_SESSION_PERIOD).unwrap_or_else(|| {
+        panic!("Instant value overflow"); // not expecting this (our duration value is quite short)
+    });
+
+    {
+        let mut sessions = shared.login_sessions.write().map_err(|err| {
+            error!("Could not obtain login sessions lock: {:?}", err);
+            ServerError::InternalServerError
+        })?;
+
+        prune_expired(&mut sessions);
+
+        sessions.insert(session_id.clone(), LoginSession {
+            username: username.to_owned(),
+            csrf_token: crate::password::generate_secret(),
+            expires_at,
+        });
+    }
+
+    info!("User logged in: {}", username);
+
+    let mut response = redirect(redirect_path);
+
+    response.headers_mut().insert(header::SET_COOKIE, session_cookie(&session_id, LOGIN_SESSION_PERIOD));
+
+    Ok(response)
+}
+
+pub async fn handle_logout(shared: Arc<Shared>, req: Request<Body>) -> Result<Response<Body>, ServerError> {
+    if req.method() != Method::POST {
+        return Err(ServerError::MethodNotAllowed);
+    }
+
+    if let Some(session_id) = crate::utils::get_cookie_value(&req, SESSION_ID_COOKIE)? {
+        let mut sessions = shared.login_sessions.write().map_err(|err| {
+            error!("Could not obtain login sessions lock: {:?}", err);
+            ServerError::InternalServerError
+        })?;
+
+        if let Some(session) = sessions.remove(&session_id) {
+            info!("User logged out: {}", session.username);
+        }
+    }
+
+    let mut response = redirect(LOGIN_PAGE);
+
+    // expire the cookie in the browser too
+    response.headers_mut().insert(header::SET_COOKIE, session_cookie("", Duration::ZERO));
+
+    Ok(response)
+}
+
+fn session_cookie(session_id: &str, max_age: Duration) -> HeaderValue {
+    let value = format!("{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Strict",
+                        SESSION_ID_COOKIE, session_id, max_age.as_secs());
+
+    HeaderValue::from_str(&value).expect("Invalid cookie value") // only ever contains hex characters
+}
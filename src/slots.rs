@@ -0,0 +1,450 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::{oneshot, watch};
+
+/// What a run needs to be allowed to start
+#[derive(Debug, Clone)]
+pub struct Requirements {
+    pub task: String,
+    /// Maximum number of runs of the task at once
+    pub concurrency: Option<usize>,
+    /// Only one run holding the same lock at once (can be shared between tasks)
+    pub lock: Option<String>,
+}
+
+/// Keeps track of executing runs, to decide which runs may start (runs that have to wait are
+/// started in the order they arrived)
+pub struct Slots {
+    /// Maximum number of runs executing at once, across all tasks (runs queue for a free slot)
+    max_running: Option<usize>,
+    state: Mutex<SlotsState>,
+}
+
+struct SlotsState {
+    /// Requirements of executing runs, by run ID
+    running: HashMap<String, Requirements>,
+    /// Only ever contains runs that can't start yet
+    queue: VecDeque<Waiter>,
+}
+
+struct Waiter {
+    run_id: String,
+    requirements: Requirements,
+    ready: oneshot::Sender<SlotGuard>,
+    /// Place in the queue, starting from 1
+    position: watch::Sender<usize>,
+}
+
+/// Number of runs executing and waiting
+#[derive(Debug, PartialEq, Eq)]
+pub struct Usage {
+    pub max_running: Option<usize>,
+    pub running: usize,
+    pub queued: usize,
+}
+
+/// Run may execute until this is dropped
+pub struct SlotGuard {
+    slots: Arc<Slots>,
+    run_id: String,
+}
+
+/// Place in the queue (leaves the queue if dropped before it's our turn)
+pub struct Ticket {
+    slots: Arc<Slots>,
+    run_id: String,
+    ready: oneshot::Receiver<SlotGuard>,
+    position: watch::Receiver<usize>,
+}
+
+pub enum Progress {
+    Started(SlotGuard),
+    /// Moved up the queue (to the given position)
+    Moved(usize),
+}
+
+pub enum Admission {
+    Started(SlotGuard),
+    Queued(Ticket),
+    /// Not queueing, and another run is in the way (runs always queue when all slots are in use)
+    Conflict(Blocker),
+}
+
+/// Run preventing another from starting
+#[derive(Debug, PartialEq, Eq)]
+pub struct Blocker {
+    pub run_id: String,
+    pub reason: BlockReason,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum BlockReason {
+    /// Task already has as many runs as it's allowed
+    Concurrency { task: String, limit: usize },
+    Lock { lock: String },
+}
+
+impl Requirements {
+    /// Whether this would have to wait behind the other run (if the other run is ahead of it)
+    fn blocked_by(&self, other: &Requirements) -> Option<BlockReason> {
+        match (&self.lock, &other.lock) {
+            (Some(lock), Some(other_lock)) if lock == other_lock => {
+                return Some(BlockReason::Lock { lock: lock.clone() });
+            }
+            _ => {}
+        }
+
+        match self.concurrency {
+            Some(limit) if self.task == other.task => Some(BlockReason::Concurrency { task: self.task.clone(), limit }),
+            _ => None
+        }
+    }
+}
+
+impl SlotsState {
+    /// First run that stops a run with these requirements from starting right now
+    fn find_blocker(&self, requirements: &Requirements, ahead: usize) -> Option<Blocker> {
+        if let Some(lock) = &requirements.lock {
+            let holder = self.running.iter()
+                .find(|(_, running)| running.lock.as_ref() == Some(lock));
+
+            if let Some((run_id, _)) = holder {
+                return Some(Blocker { run_id: run_id.clone(), reason: BlockReason::Lock { lock: lock.clone() } });
+            }
+        }
+
+        if let Some(limit) = requirements.concurrency {
+            let mut same_task: Vec<&String> = self.running.iter()
+                .filter(|(_, running)| running.task == requirements.task)
+                .map(|(run_id, _)| run_id)
+                .collect();
+
+            if same_task.len() >= limit {
+                same_task.sort(); // just to be deterministic
+                if let Some(run_id) = same_task.first() {
+                    return Some(Blocker {
+                        run_id: (*run_id).clone(),
+                        reason: BlockReason::Concurrency { task: requirements.task.clone(), limit },
+                    });
+                }
+            }
+        }
+
+        // don't jump the queue
+        self.queue.iter()
+            .take(ahead)
+            .find_map(|waiter| {
+                requirements.blocked_by(&waiter.requirements).map(|reason| Blocker {
+                    run_id: waiter.run_id.clone(),
+                    reason,
+                })
+            })
+    }
+
+    fn is_full(&self, max_running: Option<usize>) -> bool {
+        // anyone queued ahead only for a slot would also mean we're full (nothing in the queue could start now)
+        max_running.map(|max| self.running.len() >= max).unwrap_or(false)
+    }
+
+    /// Start whoever can start now, in order (so nobody skips ahead of a run they'd be blocked by)
+    fn start_ready(&mut self, max_running: Option<usize>) -> Vec<(oneshot::Sender<SlotGuard>, String)> {
+        let mut ready = Vec::new();
+        let mut index = 0;
+
+        while index < self.queue.len() && !self.is_full(max_running) {
+            if self.find_blocker(&self.queue[index].requirements, index).is_none() {
+                let waiter = self.queue.remove(index).unwrap();
+                self.running.insert(waiter.run_id.clone(), waiter.requirements);
+                ready.push((waiter.ready, waiter.run_id));
+            } else {
+                index += 1;
+            }
+        }
+
+        for (index, waiter) in self.queue.iter().enumerate() {
+            waiter.position.send_if_modified(|position| {
+                let changed = *position != index + 1;
+                *position = index + 1;
+                changed
+            });
+        }
+
+        ready
+    }
+}
+
+impl Slots {
+    pub fn new(max_running: Option<usize>) -> Slots {
+        Slots {
+            max_running,
+            state: Mutex::new(SlotsState {
+                running: HashMap::new(),
+                queue: VecDeque::new(),
+            })
+        }
+    }
+
+    /// Start the run now if possible, otherwise queue it (or report what's in the way if not queueing)
+    pub fn admit(self: &Arc<Self>, run_id: &str, requirements: Requirements, queue: bool) -> Admission {
+        let mut state = self.state.lock().unwrap();
+
+        let ahead = state.queue.len();
+
+        let blocker = state.find_blocker(&requirements, ahead);
+
+        match blocker {
+            None if !state.is_full(self.max_running) => {
+                state.running.insert(run_id.to_owned(), requirements);
+                Admission::Started(SlotGuard { slots: self.clone(), run_id: run_id.to_owned() })
+            }
+            Some(blocker) if !queue => {
+                Admission::Conflict(blocker)
+            }
+            _ => {
+                let (ready_tx, ready_rx) = oneshot::channel();
+                let (position_tx, position_rx) = watch::channel(state.queue.len() + 1);
+                state.queue.push_back(Waiter {
+                    run_id: run_id.to_owned(),
+                    requirements,
+                    ready: ready_tx,
+                    position: position_tx,
+                });
+                Admission::Queued(Ticket { slots: self.clone(), run_id: run_id.to_owned(), ready: ready_rx, position: position_rx })
+            }
+        }
+    }
+
+    pub fn usage(&self) -> Usage {
+        let state = self.state.lock().unwrap();
+
+        Usage {
+            max_running: self.max_running,
+            running: state.running.len(),
+            queued: state.queue.len(),
+        }
+    }
+
+    /// Remove a run (executing or queued), starting anyone who was waiting for it
+    fn remove(self: &Arc<Self>, run_id: &str) {
+        let ready = {
+            let mut state = self.state.lock().unwrap();
+
+            state.running.remove(run_id);
+            state.queue.retain(|waiter| waiter.run_id != run_id);
+
+            state.start_ready(self.max_running)
+        };
+
+        // outside the lock, because a guard is released straight away if its waiter has gone
+        for (ready_tx, run_id) in ready {
+            let _ = ready_tx.send(SlotGuard { slots: self.clone(), run_id });
+        }
+    }
+}
+
+impl Drop for SlotGuard {
+    fn drop(&mut self) {
+        self.slots.remove(&self.run_id);
+    }
+}
+
+impl Ticket {
+    pub fn position(&self) -> usize {
+        *self.position.borrow()
+    }
+
+    /// Wait for our turn, or until we move up the queue (cancel safe)
+    pub async fn wait(&mut self) -> Progress {
+        tokio::select! {
+            biased;
+            slot = &mut self.ready => {
+                Progress::Started(slot.expect("Queue dropped")) // waiters are only ever removed by sending, or by us
+            }
+            Ok(()) = self.position.changed() => {
+                Progress::Moved(*self.position.borrow())
+            }
+        }
+    }
+}
+
+/// Leaving the queue can let runs behind us start
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        let in_queue = self.slots.state.lock().unwrap().queue.iter().any(|waiter| waiter.run_id == self.run_id);
+        if in_queue {
+            self.slots.remove(&self.run_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn requirements(task: &str, concurrency: Option<usize>, lock: Option<&str>) -> Requirements {
+        Requirements { task: task.to_owned(), concurrency, lock: lock.map(str::to_owned) }
+    }
+
+    fn started(admission: Admission) -> SlotGuard {
+        match admission {
+            Admission::Started(guard) => guard,
+            _ => panic!("Expected run to start")
+        }
+    }
+
+    fn queued(admission: Admission) -> Ticket {
+        match admission {
+            Admission::Queued(ticket) => ticket,
+            _ => panic!("Expected run to be queued")
+        }
+    }
+
+    fn conflict(admission: Admission) -> Blocker {
+        match admission {
+            Admission::Conflict(blocker) => blocker,
+            _ => panic!("Expected a conflict")
+        }
+    }
+
+    fn is_ready(ticket: &mut Ticket) -> bool {
+        match ticket.ready.try_recv() {
+            Ok(guard) => {
+                std::mem::forget(guard); // keep holding the slot
+                true
+            }
+            Err(_) => false
+        }
+    }
+
+    #[test]
+    fn test_concurrency_limit() {
+        let slots = Arc::new(Slots::new(None));
+
+        let first = started(slots.admit("1", requirements("task", Some(2), None), false));
+        let _second = started(slots.admit("2", requirements("task", Some(2), None), false));
+
+        assert_eq!(conflict(slots.admit("3", requirements("task", Some(2), None), false)), Blocker {
+            run_id: "1".to_owned(),
+            reason: BlockReason::Concurrency { task: "task".to_owned(), limit: 2 },
+        });
+
+        // other tasks aren't affected
+        let _other = started(slots.admit("4", requirements("other", None, None), false));
+
+        drop(first);
+
+        let _third = started(slots.admit("5", requirements("task", Some(2), None), false));
+
+        // (a limit of zero is rejected when loading task files, but mustn't take the slots down)
+        let _zero = started(slots.admit("6", requirements("zero", Some(0), None), false));
+    }
+
+    #[test]
+    fn test_lock_shared_between_tasks() {
+        let slots = Arc::new(Slots::new(None));
+
+        let holder = started(slots.admit("1", requirements("deploy", None, Some("prod-db")), false));
+
+        assert_eq!(conflict(slots.admit("2", requirements("migrate", None, Some("prod-db")), false)), Blocker {
+            run_id: "1".to_owned(),
+            reason: BlockReason::Lock { lock: "prod-db".to_owned() },
+        });
+
+        let _other_lock = started(slots.admit("3", requirements("migrate", None, Some("test-db")), false));
+
+        drop(holder);
+
+        let _next = started(slots.admit("4", requirements("migrate", None, Some("prod-db")), false));
+    }
+
+    #[test]
+    fn test_queue_in_order() {
+        let slots = Arc::new(Slots::new(None));
+
+        let holder = started(slots.admit("1", requirements("deploy", None, Some("prod-db")), false));
+
+        let mut second = queued(slots.admit("2", requirements("migrate", None, Some("prod-db")), true));
+        let mut third = queued(slots.admit("3", requirements("deploy", None, Some("prod-db")), true));
+
+        // not locked right now, but would skip ahead of a queued run it conflicts with
+        assert!(!is_ready(&mut second));
+
+        drop(holder);
+
+        assert!(is_ready(&mut second));
+        assert!(!is_ready(&mut third));
+
+        // runs behind in the queue that don't conflict can start
+        let _unrelated = started(slots.admit("4", requirements("other", None, Some("test-db")), true));
+    }
+
+    #[test]
+    fn test_leave_queue() {
+        let slots = Arc::new(Slots::new(None));
+
+        let holder = started(slots.admit("1", requirements("task", Some(1), None), false));
+
+        let second = queued(slots.admit("2", requirements("task", Some(1), None), true));
+        let mut third = queued(slots.admit("3", requirements("task", Some(1), None), true));
+
+        drop(second);
+        drop(holder);
+
+        assert!(is_ready(&mut third));
+    }
+
+    #[test]
+    fn test_slot_released_if_waiter_gone() {
+        let slots = Arc::new(Slots::new(None));
+
+        let holder = started(slots.admit("1", requirements("task", Some(1), None), false));
+        let mut second = queued(slots.admit("2", requirements("task", Some(1), None), true));
+
+        second.ready.close(); // as if the run went away just as its turn came
+        drop(holder);
+        drop(second);
+
+        let _next = started(slots.admit("3", requirements("task", Some(1), None), false));
+    }
+
+    #[test]
+    fn test_max_running() {
+        let slots = Arc::new(Slots::new(Some(2)));
+
+        let first = started(slots.admit("1", requirements("task", None, None), false));
+        let _second = started(slots.admit("2", requirements("other", None, None), false));
+
+        // always queue for a free slot, even if not queueing for conflicts
+        let mut third = queued(slots.admit("3", requirements("task", None, None), false));
+        let mut fourth = queued(slots.admit("4", requirements("other", None, None), false));
+
+        assert_eq!(third.position(), 1);
+        assert_eq!(fourth.position(), 2);
+        assert_eq!(slots.usage(), Usage { max_running: Some(2), running: 2, queued: 2 });
+
+        drop(first);
+
+        assert!(is_ready(&mut third));
+        assert!(!is_ready(&mut fourth));
+        assert_eq!(fourth.position(), 1);
+        assert_eq!(slots.usage(), Usage { max_running: Some(2), running: 2, queued: 1 });
+    }
+
+    #[test]
+    fn test_max_running_skips_runs_blocked_by_lock() {
+        let slots = Arc::new(Slots::new(Some(2)));
+
+        let _holder = started(slots.admit("1", requirements("deploy", None, Some("prod-db")), false));
+        let other = started(slots.admit("2", requirements("other", None, None), false));
+
+        let mut locked = queued(slots.admit("3", requirements("migrate", None, Some("prod-db")), true));
+        let mut unrelated = queued(slots.admit("4", requirements("other", None, None), false));
+
+        drop(other);
+
+        // first in the queue still can't start, so shouldn't hold up the free slot
+        assert!(!is_ready(&mut locked));
+        assert!(is_ready(&mut unrelated));
+    }
+}
@@ -0,0 +1,118 @@
+use std::convert::Infallible;
+use std::time::Duration;
+
+use futures::stream::{Stream, StreamExt};
+
+use crate::history::OutputStream;
+use crate::json::{RunExitJson, RunQueuedJson, RunStartedJson};
+use crate::run::RunEvent;
+
+pub const TEXT_EVENT_STREAM: &str = "text/event-stream";
+
+/// Sent while there's no output, so idle connections aren't closed by proxies (or the client)
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Server-Sent Events for a run: 'queued', 'started', 'stdout', 'stderr' and 'exit' (along with
+/// a 'heartbeat' whenever nothing else has been sent for a while)
+pub fn event_stream<S>(events: S) -> impl Stream<Item=Result<String, Infallible>>
+    where S: Stream<Item=RunEvent> + Send + 'static {
+    let heartbeat = tokio::time::sleep(HEARTBEAT_INTERVAL);
+
+    futures::stream::unfold((events.boxed(), Box::pin(heartbeat)), |(mut events, mut heartbeat)| async move {
+        let message = tokio::select! {
+            event = events.next() => to_message(&event?),
+            _ = &mut heartbeat => message("heartbeat", "{}"),
+        };
+
+        heartbeat.as_mut().reset(tokio::time::Instant::now() + HEARTBEAT_INTERVAL);
+
+        Some((Ok(message), (events, heartbeat)))
+    })
+}
+
+fn to_message(event: &RunEvent) -> String {
+    match event {
+        RunEvent::Queued(position) => {
+            let queued_json = RunQueuedJson { position: *position };
+            message("queued", &serde_json::to_string(&queued_json).unwrap()) // TODO: handle error
+        }
+        RunEvent::Started { run_id, pid } => {
+            let started_json = RunStartedJson { run_id: run_id.clone(), pid: *pid };
+            message("started", &serde_json::to_string(&started_json).unwrap()) // TODO: handle error
+        }
+        RunEvent::Output(line) => {
+            let event = match line.stream {
+                OutputStream::Stdout => "stdout",
+                OutputStream::Stderr => "stderr",
+            };
+            message(event, line.text.strip_suffix('\n').unwrap_or(&line.text))
+        }
+        RunEvent::Finished(record) => {
+            let exit_json = RunExitJson {
+                exit_code: record.exit_code,
+                signal: record.signal,
+                timed_out_after_secs: record.timed_out_after_secs,
+                cancelled_by: record.cancelled_by.clone(),
+            };
+            message("exit", &serde_json::to_string(&exit_json).unwrap()) // TODO: handle error
+        }
+    }
+}
+
+/// Data is split into one field per line (a carriage return on its own also ends a line)
+fn message(event: &str, data: &str) -> String {
+    let mut message = format!("event: {}\n", event);
+
+    for line in data.split(['\n', '\r']) {
+        message.push_str("data: ");
+        message.push_str(line);
+        message.push('\n');
+    }
+
+    message.push('\n');
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::run::OutputLine;
+
+    use tokio::sync::mpsc;
+    use tokio_stream::wrappers::UnboundedReceiverStream;
+
+    #[test]
+    fn test_message() {
+        assert_eq!(message("stdout", "Hello"), "event: stdout\ndata: Hello\n\n");
+        assert_eq!(message("stdout", ""), "event: stdout\ndata: \n\n");
+        assert_eq!(message("stderr", "10%\r20%"), "event: stderr\ndata: 10%\ndata: 20%\n\n");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_heartbeat() {
+        let (events_tx, events_rx) = mpsc::unbounded_channel();
+
+        let stream = event_stream(UnboundedReceiverStream::new(events_rx));
+        futures::pin_mut!(stream);
+
+        events_tx.send(RunEvent::Output(OutputLine {
+            seq: 0,
+            stream: OutputStream::Stderr,
+            time: None,
+            text: "Starting\n".to_owned(),
+        })).unwrap();
+
+        assert_eq!(stream.next().await.unwrap().unwrap(), "event: stderr\ndata: Starting\n\n");
+
+        // nothing else to send (time advances on its own while paused and idle)
+        let started = tokio::time::Instant::now();
+
+        assert_eq!(stream.next().await.unwrap().unwrap(), "event: heartbeat\ndata: {}\n\n");
+        assert_eq!(started.elapsed(), HEARTBEAT_INTERVAL);
+
+        drop(events_tx);
+
+        assert!(stream.next().await.is_none());
+    }
+}
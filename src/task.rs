@@ -1,7 +1,11 @@
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
+use std::time::Duration;
 
 use either::Either;
 
+use crate::cron::CronSchedule;
+
 pub enum TaskMethod {
     GET,
     POST,
@@ -11,6 +15,8 @@ pub enum TaskParameterType {
     String,
     Number,
     Boolean,
+    /// String that's kept out of logs, run history and notifications
+    Secret,
 }
 
 pub enum TaskParameterValue {
@@ -24,7 +30,18 @@ pub struct TaskDef {
     pub description: Option<String>,
     pub method: Vec<TaskMethod>,
     pub parameters: Vec<TaskDefParameter>,
+    /// Maximum number of runs executing at once (no limit if not specified)
+    pub concurrency: Option<usize>,
+    /// Name of a lock that only one run may hold at once (shared by all tasks with the same lock)
+    pub lock: Option<String>,
+    /// Whether runs wait for conflicting runs to finish (otherwise they're rejected)
+    pub queue: bool,
     pub exec: TaskDefExec,
+    pub auth: TaskDefAuth,
+    pub schedules: Vec<TaskDefSchedule>,
+    pub webhooks: Vec<TaskDefWebhook>,
+    pub notify: Vec<NotifyDef>,
+    pub email: Vec<EmailDef>,
 }
 
 pub struct TaskDefParameter {
@@ -40,12 +57,122 @@ pub struct TaskDefExec {
     pub command: String,
     pub args: Option<Vec<String>>,
     pub dir: PathBuf,
+    pub detach: bool,
+    /// Server default applies if not specified
+    pub timeout: Option<Duration>,
+    pub grace_period: Option<Duration>,
+    pub stdin: bool,
+    pub env: HashMap<String, TaskDefEnv>,
+}
+
+pub enum TaskDefEnv {
+    Value(String),
+    /// Name of a secret, only looked up when the process is spawned
+    Secret(String),
+}
+
+impl TaskDefExec {
+    /// Secrets used in the environment
+    pub fn secret_names(&self) -> impl Iterator<Item=&String> {
+        self.env.values().filter_map(|value| match value {
+            TaskDefEnv::Secret(name) => Some(name),
+            TaskDefEnv::Value(_) => None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MissedPolicy {
+    /// Only run at times the server was there to see
+    Skip,
+    /// Run once as soon as possible (however many runs were missed)
+    RunOnce,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverlapPolicy {
+    /// Don't run if the last scheduled run is still going, or another run is in the way (see
+    /// `concurrency` and `lock`)
+    Skip,
+    /// Wait for any other run of the task (and its lock) to finish first
+    Queue,
+}
+
+pub struct TaskDefSchedule {
+    pub cron: CronSchedule,
+    pub params: HashMap<String, String>,
+    pub missed: MissedPolicy,
+    pub overlap: OverlapPolicy,
+}
+
+pub const DEFAULT_WEBHOOK_HEADER: &str = "x-hub-signature-256";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebhookAlgorithm {
+    HmacSha256,
+}
+
+pub struct TaskDefWebhook {
+    pub secret: String,
+    /// Request header holding the signature of the body
+    pub header: http::header::HeaderName,
+    pub algorithm: WebhookAlgorithm,
+    /// Fields of the JSON body (dotted paths) by parameter name
+    pub params: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotifyOn {
+    Success,
+    Failure,
+    Always,
+}
+
+impl NotifyOn {
+    pub fn matches(&self, succeeded: bool) -> bool {
+        match self {
+            NotifyOn::Success => succeeded,
+            NotifyOn::Failure => !succeeded,
+            NotifyOn::Always => true,
+        }
+    }
+}
+
+/// Where to send word of finished runs
+#[derive(Debug, Clone)]
+pub struct NotifyDef {
+    pub url: http::Uri,
+    pub on: NotifyOn,
+    /// Body with '{{...}}' placeholders for details of the run (sent as is if not set)
+    pub body: Option<String>,
+    /// Attempts after the first one fails
+    pub retries: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct EmailDef {
+    pub to: Vec<String>,
+    pub on: NotifyOn,
+}
+
+pub struct TaskDefAuth {
+    /// Users need at least one of these roles to see and run the task (anyone may if not specified)
+    pub roles_allowed: Option<HashSet<String>>,
+}
+
+impl TaskDef {
+    pub fn is_allowed(&self, roles: &HashSet<String>) -> bool {
+        match &self.auth.roles_allowed {
+            Some(roles_allowed) => !roles_allowed.is_disjoint(roles),
+            None => true
+        }
+    }
 }
 
 impl TaskDefParameter {
     pub fn validate(&self, str: &str) -> bool {
         match self._type {
-            TaskParameterType::String => {
+            TaskParameterType::String | TaskParameterType::Secret => {
                 self._enum.is_empty()
                     || self._enum.iter().any(|x| x == str)
             },
@@ -1,6 +1,8 @@
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::io::{Error as IoError};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use toml::de::Error as TomlError;
 
@@ -22,6 +24,12 @@ pub struct Task {
     pub method: Vec<Method>,
     #[serde(default)]
     pub parameters: Vec<TaskParameter>,
+    /// Maximum number of runs of this task executing at once
+    pub concurrency: Option<usize>,
+    /// Only one run holding a lock may execute at once (any task can name the same lock)
+    pub lock: Option<String>,
+    /// Wait for a conflicting run to finish, rather than responding with a conflict
+    pub queue: Option<bool>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -30,6 +38,7 @@ pub enum TaskParameterType {
     String,
     Number,
     Boolean,
+    Secret,
 }
 
 #[derive(Deserialize, Debug, PartialEq)]
@@ -60,12 +69,120 @@ pub struct Exec {
     pub command: String,
     pub args: Option<Vec<String>>,
     pub dir: Option<String>,
+    /// Keep running when the client disconnects (respond with a run ID rather than streaming output)
+    pub detach: Option<bool>,
+    /// Stop the process if it runs longer than this (eg, '10m')
+    pub timeout: Option<String>,
+    /// How long to wait after asking the process to stop before killing it (eg, '10s')
+    pub grace_period: Option<String>,
+    /// Accept input for the process (only from runs started via WebSocket, otherwise input is empty)
+    pub stdin: Option<bool>,
+    /// Environment variables for the process (as well as those set by parameters)
+    #[serde(default)]
+    pub env: HashMap<String, EnvValue>,
+}
+
+/// Value of an '[exec.env]' variable
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum EnvValue {
+    Value(String),
+    /// From the server's secrets (eg, '{ secret = "aws_key" }')
+    Secret { secret: String },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MissedPolicy {
+    Skip,
+    RunOnce,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverlapPolicy {
+    Skip,
+    Queue,
+}
+
+/// Run the task at times given by a cron expression (eg, '30 3 * * *' for 3:30am every day)
+#[derive(Debug, Deserialize)]
+pub struct Schedule {
+    pub cron: String,
+    /// Parameter values for every scheduled run
+    #[serde(default)]
+    pub params: HashMap<String, TaskParameterValue>,
+    /// What to do about runs that should have happened while the server wasn't running (defaults to 'skip')
+    pub missed: Option<MissedPolicy>,
+    /// What to do when the task is still running, or can't run yet (defaults to 'skip')
+    pub overlap: Option<OverlapPolicy>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WebhookAlgorithm {
+    HmacSha256,
+}
+
+/// Run the task when a request signed with the secret is posted to '/hooks/{task}'
+#[derive(Debug, Deserialize)]
+pub struct Webhook {
+    pub secret: String,
+    /// Header holding the signature (defaults to 'X-Hub-Signature-256', as sent by GitHub)
+    pub header: Option<String>,
+    /// Defaults to 'hmac-sha256' (the only one supported)
+    pub algorithm: Option<WebhookAlgorithm>,
+    /// Task parameters taken from fields of the JSON body (eg, 'branch = "ref"', with dots for nested fields)
+    #[serde(default)]
+    pub params: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NotifyOn {
+    Success,
+    Failure,
+    Always,
+}
+
+/// Post details of finished runs to the URL (used in server configuration too)
+#[derive(Debug, Clone, Deserialize)]
+pub struct Notify {
+    pub url: String,
+    /// Which runs to send (defaults to 'always')
+    pub on: Option<NotifyOn>,
+    /// Template for the body, with placeholders like '{{task}}' (defaults to a JSON description of the run)
+    pub body: Option<String>,
+    /// Attempts after the first one fails (defaults to 3)
+    pub retries: Option<u32>,
+}
+
+/// Email the recipients about finished runs (needs '[smtp]' in the server configuration)
+#[derive(Debug, Deserialize)]
+pub struct Email {
+    pub to: Vec<String>,
+    /// Which runs to send (defaults to 'failure')
+    pub on: Option<NotifyOn>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Auth {
+    pub roles_allowed: Option<Vec<String>>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct TaskFileToml {
     pub task: Task,
     pub exec: Exec,
+    pub auth: Option<Auth>,
+    #[serde(default, rename = "schedule")]
+    pub schedules: Vec<Schedule>,
+    #[serde(default, rename = "webhook")]
+    pub webhooks: Vec<Webhook>,
+    #[serde(default)]
+    pub notify: Vec<Notify>,
+    #[serde(default)]
+    pub email: Vec<Email>,
 }
 
 #[derive(Debug)]
@@ -74,6 +191,18 @@ pub enum ConfigFileError {
     Io(IoError, Option<PathBuf>),
     InvalidTaskFileName(PathBuf),
     InvalidPasswordHash { username: String },
+    InvalidTokenName { name: String },
+    InvalidTokenHash { name: String },
+    InvalidTokenExpiry { name: String },
+    InvalidDuration { value: String, path: PathBuf },
+    InvalidMaxConcurrentRuns(PathBuf),
+    InvalidConcurrency(PathBuf),
+    InvalidSchedule { value: String, reason: String, path: PathBuf },
+    InvalidHeaderName { value: String, path: PathBuf },
+    InvalidUrl { value: String, path: PathBuf },
+    InvalidTemplate { reason: String, path: PathBuf },
+    InsecurePermissions(PathBuf),
+    UnknownSecret { name: String, task: String },
 }
 
 const TASK_FILE_SUFFIX: &'static str = ".task.toml";
@@ -97,11 +226,21 @@ impl TaskFileToml {
     }
 }
 
+/// Task files in the directory and its sub-directories (following symlinks)
 pub fn find_task_files(dir: &Path) -> Result<Vec<PathBuf>, ConfigFileError> {
+    find_task_files_in(dir, &mut HashSet::new())
+}
+
+/// (directories already searched are skipped, so that symlinks can't cause a loop)
+fn find_task_files_in(dir: &Path, visited: &mut HashSet<PathBuf>) -> Result<Vec<PathBuf>, ConfigFileError> {
     let from_io_err = |err: IoError| -> ConfigFileError {
         ConfigFileError::Io(err, Some(dir.to_owned()))
     };
 
+    if !visited.insert(fs::canonicalize(dir).map_err(from_io_err)?) {
+        return Ok(Vec::new());
+    }
+
     let files = fs::read_dir(&dir).map_err(from_io_err)?;
 
     let mut res = Vec::<PathBuf>::new();
@@ -113,18 +252,17 @@ pub fn find_task_files(dir: &Path) -> Result<Vec<PathBuf>, ConfigFileError> {
             ConfigFileError::Io(err, Some(entry.path()))
         };
 
-        let file_type = entry.file_type().map_err(from_io_err)?;
+        // (the type of whatever a symlink points to, a broken one is an error)
+        let file_type = fs::metadata(entry.path()).map_err(from_io_err)?.file_type();
 
         if file_type.is_dir() {
-            res.append(&mut find_task_files(&entry.path())?)
+            res.append(&mut find_task_files_in(&entry.path(), visited)?)
         } else if file_type.is_file() {
             if entry.path().to_string_lossy().ends_with(TASK_FILE_SUFFIX) {
                 res.push(entry.path())
             }
-        } else if file_type.is_symlink() {
-            todo!("Follow symlinks"); // TODO: follow symlinks
         } else {
-            panic!("Unexpected file type: {:?}", entry) // not expected
+            debug!("Ignoring special file: {:?}", entry.path()); // eg, a socket
         }
     }
 
@@ -182,7 +320,9 @@ fn to_task_parameter_type(toml: TaskParameterType) -> task::TaskParameterType {
         TaskParameterType::Number =>
             task::TaskParameterType::Number,
         TaskParameterType::Boolean =>
-            task::TaskParameterType::Boolean
+            task::TaskParameterType::Boolean,
+        TaskParameterType::Secret =>
+            task::TaskParameterType::Secret
     }
 }
 
@@ -198,7 +338,17 @@ fn to_task_def_parameter(toml: TaskParameter) -> task::TaskDefParameter {
     }
 }
 
-fn to_task_def_exec(toml: Exec, path: &Path) -> task::TaskDefExec {
+fn to_duration(value: Option<String>, path: &Path) -> Result<Option<Duration>, ConfigFileError> {
+    match value {
+        Some(value) => match crate::utils::parse_duration(&value) {
+            Some(duration) => Ok(Some(duration)),
+            None => Err(ConfigFileError::InvalidDuration { value, path: path.to_owned() })
+        },
+        None => Ok(None)
+    }
+}
+
+fn to_task_def_exec(toml: Exec, path: &Path) -> Result<task::TaskDefExec, ConfigFileError> {
     let parent_dir = path.parent().unwrap_or_else(||
         panic!("Path has no parent directory: {:?}", path)); // shouldn't be possible on regular file systems
 
@@ -206,15 +356,101 @@ fn to_task_def_exec(toml: Exec, path: &Path) -> task::TaskDefExec {
         .map(|d| parent_dir.join(d))
         .unwrap_or_else(|| parent_dir.to_owned());
 
-    task::TaskDefExec {
+    Ok(task::TaskDefExec {
         command: toml.command,
         args: toml.args,
         dir,
+        detach: toml.detach.unwrap_or(false),
+        timeout: to_duration(toml.timeout, path)?,
+        grace_period: to_duration(toml.grace_period, path)?,
+        stdin: toml.stdin.unwrap_or(false),
+        env: toml.env.into_iter()
+            .map(|(name, value)| match value {
+                EnvValue::Value(value) => (name, task::TaskDefEnv::Value(value)),
+                EnvValue::Secret { secret } => (name, task::TaskDefEnv::Secret(secret)),
+            })
+            .collect(),
+    })
+}
+
+fn to_task_def_schedule(toml: Schedule, path: &Path) -> Result<task::TaskDefSchedule, ConfigFileError> {
+    let cron = toml.cron.parse().map_err(|reason| {
+        ConfigFileError::InvalidSchedule { value: toml.cron.clone(), reason, path: path.to_owned() }
+    })?;
+
+    let params = toml.params.into_iter()
+        .map(|(name, value)| (name, crate::server::param_to_string(&to_task_parameter_value(value))))
+        .collect();
+
+    Ok(task::TaskDefSchedule {
+        cron,
+        params,
+        missed: match toml.missed {
+            Some(MissedPolicy::RunOnce) => task::MissedPolicy::RunOnce,
+            Some(MissedPolicy::Skip) | None => task::MissedPolicy::Skip,
+        },
+        overlap: match toml.overlap {
+            Some(OverlapPolicy::Queue) => task::OverlapPolicy::Queue,
+            Some(OverlapPolicy::Skip) | None => task::OverlapPolicy::Skip,
+        },
+    })
+}
+
+fn to_task_def_webhook(toml: Webhook, path: &Path) -> Result<task::TaskDefWebhook, ConfigFileError> {
+    let header = match toml.header {
+        Some(header) => http::header::HeaderName::from_bytes(header.as_bytes()).map_err(|_| {
+            ConfigFileError::InvalidHeaderName { value: header.clone(), path: path.to_owned() }
+        })?,
+        None => http::header::HeaderName::from_static(task::DEFAULT_WEBHOOK_HEADER),
+    };
+
+    Ok(task::TaskDefWebhook {
+        secret: toml.secret,
+        header,
+        algorithm: match toml.algorithm {
+            Some(WebhookAlgorithm::HmacSha256) | None => task::WebhookAlgorithm::HmacSha256,
+        },
+        params: toml.params,
+    })
+}
+
+fn to_notify_on(toml: Option<NotifyOn>, default: task::NotifyOn) -> task::NotifyOn {
+    match toml {
+        Some(NotifyOn::Success) => task::NotifyOn::Success,
+        Some(NotifyOn::Failure) => task::NotifyOn::Failure,
+        Some(NotifyOn::Always) => task::NotifyOn::Always,
+        None => default,
     }
 }
 
+fn to_email_def(toml: Email) -> task::EmailDef {
+    task::EmailDef {
+        to: toml.to,
+        on: to_notify_on(toml.on, task::NotifyOn::Failure),
+    }
+}
+
+pub fn to_notify_def(toml: Notify, path: &Path) -> Result<task::NotifyDef, ConfigFileError> {
+    let url = toml.url.parse::<http::Uri>().ok()
+        .filter(|url| matches!(url.scheme_str(), Some("http") | Some("https")))
+        .ok_or_else(|| ConfigFileError::InvalidUrl { value: toml.url.clone(), path: path.to_owned() })?;
+
+    if let Some(body) = &toml.body {
+        crate::notify::check_template(body).map_err(|reason| {
+            ConfigFileError::InvalidTemplate { reason, path: path.to_owned() }
+        })?;
+    }
+
+    Ok(task::NotifyDef {
+        url,
+        on: to_notify_on(toml.on, task::NotifyOn::Always),
+        body: toml.body,
+        retries: toml.retries.unwrap_or(crate::notify::DEFAULT_RETRIES),
+    })
+}
+
 fn to_task_def(toml: TaskFileToml, path: &Path) -> Result<task::TaskDef, ConfigFileError> {
-    let TaskFileToml { task, exec } = toml;
+    let TaskFileToml { task, exec, auth, schedules, webhooks, notify, email } = toml;
 
     let name = get_task_name(path)?;
 
@@ -227,13 +463,43 @@ fn to_task_def(toml: TaskFileToml, path: &Path) -> Result<task::TaskDef, ConfigF
 
     let parameters = task.parameters.into_iter().map(to_task_def_parameter).collect();
 
-    let exec = to_task_def_exec(exec, path);
+    if task.concurrency == Some(0) {
+        return Err(ConfigFileError::InvalidConcurrency(path.to_owned()));
+    }
+
+    let exec = to_task_def_exec(exec, path)?;
+
+    let auth = task::TaskDefAuth {
+        roles_allowed: auth
+            .and_then(|auth| auth.roles_allowed)
+            .map(|roles| roles.into_iter().collect()),
+    };
+
+    let schedules = schedules.into_iter()
+        .map(|schedule| to_task_def_schedule(schedule, path))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let webhooks = webhooks.into_iter()
+        .map(|webhook| to_task_def_webhook(webhook, path))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let notify = notify.into_iter()
+        .map(|notify| to_notify_def(notify, path))
+        .collect::<Result<Vec<_>, _>>()?;
 
     Ok(task::TaskDef {
         name,
         description: task.description,
         method,
         parameters,
+        concurrency: task.concurrency,
+        lock: task.lock,
+        queue: task.queue.unwrap_or(false),
         exec,
+        auth,
+        schedules,
+        webhooks,
+        notify,
+        email: email.into_iter().map(to_email_def).collect(),
     })
 }
\ No newline at end of file
@@ -1,3 +1,6 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
 use lazy_static::lazy_static;
 use regex::Regex;
 use http::{Request, header::HeaderName};
@@ -9,7 +12,10 @@ pub enum AuthorizationValue {
     Basic {
         username: String,
         password: String,
-    }
+    },
+    Bearer {
+        token: String,
+    },
 }
 
 fn ellipsis(s: &str, max: usize) -> String {
@@ -23,6 +29,19 @@ fn ellipsis(s: &str, max: usize) -> String {
 pub fn parse_authorization_value(value: &str) -> Result<AuthorizationValue, ServerError> {
     lazy_static! {
         static ref BASIC_PATTERN: Regex = Regex::new("^Basic ([A-Za-z0-9+/=]+)$").unwrap();
+        static ref BEARER_PATTERN: Regex = Regex::new("^Bearer ([A-Za-z0-9._~+/-]+=*)$").unwrap();
+    }
+
+    if let Some(captures) = BEARER_PATTERN.captures(value) {
+        return match captures.get(1) {
+            Some(token) => Ok(AuthorizationValue::Bearer {
+                token: token.as_str().to_owned(),
+            }),
+            None => {
+                error!("Regular expression missing capture"); // logic error
+                Err(ServerError::BadRequest("Malformed Authorization header".to_owned()))
+            }
+        };
     }
 
     if let Some(captures) = BASIC_PATTERN.captures(value) {
@@ -72,36 +91,115 @@ fn get_header<T>(req: &Request<T>, name: &HeaderName) -> Result<Option<String>,
     }).transpose()
 }
 
-// pub fn get_cookie_value<T>(req: &Request<T>, cookie_name: &str) -> Result<Option<String>, ServerError> {
-//     get_header(&req, &http::header::COOKIE).map(|result| {
-//         result.and_then(|value| {
-//             let cookies = parse_cookies(&value);
-//             match cookies.get(cookie_name) {
-//                 Some(value) => {
-//                     Some(value.to_owned())
-//                 }
-//                 None => None
-//             }
-//         })
-//     })
-// }
-//
-// /// Very rudimentary cookie parsing (can't seem to find a library to do this)
-// pub fn parse_cookies(header_value: &str) -> HashMap<String, String> {
-//     let mut result: HashMap<String, String> = HashMap::new();
-//
-//     for cookie in header_value.split("; ") {
-//         let parts: Vec<&str> = cookie.split("=").collect();
-//
-//         // don't explode if we can't parse cookies because we never know what we're going to get from third-party trackers etc.
-//         if parts.len() != 2 {
-//             warn!("Could not parse cookie (expected 2 parts, was {})", parts.len()); // not logging value because may contain access token
-//             continue;
-//         }
-//
-//         result.insert(parts[0].to_owned(), parts[1].to_owned());
-//     }
-//
-//     return result;
-// }
+pub fn get_cookie_value<T>(req: &Request<T>, cookie_name: &str) -> Result<Option<String>, ServerError> {
+    get_header(req, &http::header::COOKIE).map(|result| {
+        result.and_then(|value| {
+            let mut cookies = parse_cookies(&value);
+            cookies.remove(cookie_name)
+        })
+    })
+}
+
+/// Very rudimentary cookie parsing (can't seem to find a library to do this)
+pub fn parse_cookies(header_value: &str) -> HashMap<String, String> {
+    let mut result: HashMap<String, String> = HashMap::new();
+
+    for cookie in header_value.split("; ") {
+        let parts: Vec<&str> = cookie.split('=').collect();
+
+        // don't explode if we can't parse cookies because we never know what we're going to get from third-party trackers etc.
+        if parts.len() != 2 {
+            warn!("Could not parse cookie (expected 2 parts, was {})", parts.len()); // not logging value because may contain access token
+            continue;
+        }
+
+        result.insert(parts[0].to_owned(), parts[1].to_owned());
+    }
+
+    result
+}
+
+
+/// Durations such as '30s', '10m', '1h30m' or '2d' (whole seconds only)
+pub fn parse_duration(value: &str) -> Option<Duration> {
+    lazy_static! {
+        static ref DURATION_PART_PATTERN: Regex = Regex::new(r"(\d+)([smhd])").unwrap();
+    }
+
+    let mut secs: u64 = 0;
+    let mut end = 0;
+
+    for captures in DURATION_PART_PATTERN.captures_iter(value) {
+        let part = captures.get(0).unwrap();
+        if part.start() != end {
+            return None; // something other than a duration part in between
+        }
+        end = part.end();
+
+        let unit: u64 = match &captures[2] {
+            "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            _ => 24 * 60 * 60,
+        };
 
+        secs = captures[1].parse::<u64>().ok()
+            .and_then(|count| count.checked_mul(unit))
+            .and_then(|part_secs| secs.checked_add(part_secs))?;
+    }
+
+    if end == 0 || end != value.len() {
+        return None;
+    }
+
+    Some(Duration::from_secs(secs))
+}
+
+/// Opposite of `parse_duration` (eg, '1h30m')
+pub fn format_duration(duration: Duration) -> String {
+    let mut secs = duration.as_secs();
+
+    if secs == 0 {
+        return "0s".to_owned();
+    }
+
+    let mut result = String::new();
+
+    for (unit, unit_secs) in &[("d", 24 * 60 * 60), ("h", 60 * 60), ("m", 60), ("s", 1)] {
+        if secs >= *unit_secs {
+            result.push_str(&format!("{}{}", secs / unit_secs, unit));
+            secs %= unit_secs;
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("30s"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration("10m"), Some(Duration::from_secs(600)));
+        assert_eq!(parse_duration("1h30m"), Some(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("2d"), Some(Duration::from_secs(172800)));
+
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("10"), None);
+        assert_eq!(parse_duration("10 m"), None);
+        assert_eq!(parse_duration("1h 30m"), None);
+        assert_eq!(parse_duration("m"), None);
+        assert_eq!(parse_duration("99999999999999999999s"), None);
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_secs(0)), "0s");
+        assert_eq!(format_duration(Duration::from_secs(30)), "30s");
+        assert_eq!(format_duration(Duration::from_secs(600)), "10m");
+        assert_eq!(format_duration(Duration::from_secs(5430)), "1h30m30s");
+        assert_eq!(format_duration(Duration::from_millis(1500)), "1s");
+    }
+}
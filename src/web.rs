@@ -12,6 +12,9 @@ pub async fn match_path_web(path: &[&str]) -> Result<Response<Body>, ServerError
         ["tasks", _name] => {
             serve_static(&["tasks", "task"])
         }
+        ["runs", _id] => {
+            serve_static(&["runs", "run"])
+        }
         [tail @ ..] => {
             serve_static(tail)
         }
@@ -44,12 +47,19 @@ macro_rules! resource {
 }
 
 const WEB_RESOURCES: &'static [WebResource] = &[
+    resource!(&["login"], "resources/login.html", TEXT_HTML),
     resource!(&["tasks"], "resources/tasks.html", TEXT_HTML),
+    resource!(&["history"], "resources/history.html", TEXT_HTML),
     resource!(&["tasks", "task"], "resources/tasks/task.html", TEXT_HTML),
+    resource!(&["runs", "run"], "resources/runs/run.html", TEXT_HTML),
     resource!(&["favicon.ico"], "resources/favicon.ico", IMAGE_PNG),
     resource!(&["main.css"], "resources/main.css", TEXT_CSS),
     resource!(&["modules", "api"], "resources/modules/api.mjs", APPLICATION_JAVASCRIPT),
+    resource!(&["modules", "history"], "resources/modules/history.mjs", APPLICATION_JAVASCRIPT),
     resource!(&["modules", "html"], "resources/modules/html.mjs", APPLICATION_JAVASCRIPT),
+    resource!(&["modules", "login"], "resources/modules/login.mjs", APPLICATION_JAVASCRIPT),
+    resource!(&["modules", "run"], "resources/modules/run.mjs", APPLICATION_JAVASCRIPT),
+    resource!(&["modules", "session"], "resources/modules/session.mjs", APPLICATION_JAVASCRIPT),
     resource!(&["modules", "task"], "resources/modules/task.mjs", APPLICATION_JAVASCRIPT),
     resource!(&["modules", "tasks"], "resources/modules/tasks.mjs", APPLICATION_JAVASCRIPT),
     resource!(&["modules", "utils"], "resources/modules/utils.mjs", APPLICATION_JAVASCRIPT)
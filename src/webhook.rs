@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use http::{header, HeaderMap};
+
+use hyper::Body;
+use hyper::body::HttpBody;
+
+use ring::hmac;
+
+use serde_json::Value;
+
+use crate::task::{TaskDefWebhook, WebhookAlgorithm};
+
+/// Signatures may be prefixed with the algorithm (eg, 'sha256=...', as sent by GitHub)
+const SHA256_PREFIX: &str = "sha256=";
+
+/// Largest body accepted (anyone can send one, the signature is only checked once it's all read)
+pub const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug)]
+pub enum BodyError {
+    TooLarge,
+    Read(hyper::Error),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SignatureError {
+    Missing(String),
+    Malformed(String),
+    Mismatch,
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignatureError::Missing(header) => write!(f, "no signature in header {}", header),
+            SignatureError::Malformed(header) => write!(f, "malformed signature in header {}", header),
+            SignatureError::Mismatch => write!(f, "signature doesn't match"),
+        }
+    }
+}
+
+/// Read the whole body, as long as it's no larger than the limit
+pub async fn read_body(headers: &HeaderMap, mut body: Body, limit: usize) -> Result<Vec<u8>, BodyError> {
+    let content_length = headers.get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+
+    if content_length.is_some_and(|length| length > limit as u64) {
+        return Err(BodyError::TooLarge);
+    }
+
+    let mut result = Vec::with_capacity(content_length.unwrap_or(0) as usize);
+
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(BodyError::Read)?;
+
+        if result.len() + chunk.len() > limit {
+            return Err(BodyError::TooLarge); // (no, or the wrong, 'Content-Length')
+        }
+
+        result.extend_from_slice(&chunk);
+    }
+
+    Ok(result)
+}
+
+/// Check the request body was signed with the webhook's secret
+pub fn verify(webhook: &TaskDefWebhook, headers: &HeaderMap, body: &[u8]) -> Result<(), SignatureError> {
+    let header = webhook.header.as_str();
+
+    let value = headers.get(&webhook.header)
+        .ok_or_else(|| SignatureError::Missing(header.to_owned()))?
+        .to_str()
+        .map_err(|_| SignatureError::Malformed(header.to_owned()))?
+        .trim();
+
+    let signature = match webhook.algorithm {
+        WebhookAlgorithm::HmacSha256 => {
+            let hex_value = value.strip_prefix(SHA256_PREFIX).unwrap_or(value);
+            hex::decode(hex_value).map_err(|_| SignatureError::Malformed(header.to_owned()))?
+        }
+    };
+
+    let key = match webhook.algorithm {
+        WebhookAlgorithm::HmacSha256 => hmac::Key::new(hmac::HMAC_SHA256, webhook.secret.as_bytes()),
+    };
+
+    // (constant time comparison)
+    hmac::verify(&key, body, &signature).map_err(|_| SignatureError::Mismatch)
+}
+
+/// Parameter values from fields of the body (fields that aren't there, or are null, are left out
+/// so that parameter defaults apply)
+pub fn extract_params(mapping: &HashMap<String, String>, body: &Value) -> Result<HashMap<String, String>, String> {
+    let mut params = HashMap::new();
+
+    for (name, field) in mapping {
+        let value = field.split('.').try_fold(body, |value, key| match value {
+            Value::Array(items) => key.parse::<usize>().ok().and_then(|index| items.get(index)),
+            value => value.get(key),
+        });
+
+        let value = match value {
+            None | Some(Value::Null) => continue,
+            Some(Value::String(value)) => value.clone(),
+            Some(Value::Number(value)) => value.to_string(),
+            Some(Value::Bool(value)) => value.to_string(),
+            Some(_) => return Err(format!("Field {} is not a single value", field)),
+        };
+
+        params.insert(name.clone(), value);
+    }
+
+    Ok(params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use http::HeaderValue;
+    use serde_json::json;
+
+    fn webhook() -> TaskDefWebhook {
+        TaskDefWebhook {
+            secret: "It's a Secret to Everybody".to_owned(),
+            header: http::header::HeaderName::from_static(crate::task::DEFAULT_WEBHOOK_HEADER),
+            algorithm: WebhookAlgorithm::HmacSha256,
+            params: HashMap::new(),
+        }
+    }
+
+    fn headers(signature: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Hub-Signature-256", HeaderValue::from_str(signature).unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_verify() {
+        // example from GitHub's documentation
+        let signature = "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+
+        assert_eq!(verify(&webhook(), &headers(signature), b"Hello, World!"), Ok(()));
+        assert_eq!(verify(&webhook(), &headers(&signature[7..]), b"Hello, World!"), Ok(()));
+
+        assert_eq!(verify(&webhook(), &headers(signature), b"Hello, World?"), Err(SignatureError::Mismatch));
+        assert_eq!(verify(&webhook(), &headers("sha256=nothex"), b"Hello, World!"),
+                   Err(SignatureError::Malformed("x-hub-signature-256".to_owned())));
+        assert_eq!(verify(&webhook(), &HeaderMap::new(), b"Hello, World!"),
+                   Err(SignatureError::Missing("x-hub-signature-256".to_owned())));
+    }
+
+    #[tokio::test]
+    async fn test_read_body() {
+        assert_eq!(read_body(&HeaderMap::new(), Body::from("0123456789"), 10).await.unwrap(), b"0123456789");
+
+        assert!(matches!(read_body(&HeaderMap::new(), Body::from("0123456789a"), 10).await, Err(BodyError::TooLarge)));
+
+        // turned away before reading anything
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from_static("11"));
+
+        assert!(matches!(read_body(&headers, Body::empty(), 10).await, Err(BodyError::TooLarge)));
+    }
+
+    #[test]
+    fn test_extract_params() {
+        let body = json!({
+            "ref": "refs/heads/main",
+            "repository": {"name": "henchman", "size": 42, "private": false},
+            "commits": [{"id": "abc"}],
+            "deleted": null,
+        });
+
+        let mapping = HashMap::from([
+            ("branch".to_owned(), "ref".to_owned()),
+            ("repo".to_owned(), "repository.name".to_owned()),
+            ("size".to_owned(), "repository.size".to_owned()),
+            ("private".to_owned(), "repository.private".to_owned()),
+            ("commit".to_owned(), "commits.0.id".to_owned()),
+            ("deleted".to_owned(), "deleted".to_owned()),
+            ("missing".to_owned(), "repository.owner.name".to_owned()),
+        ]);
+
+        assert_eq!(extract_params(&mapping, &body), Ok(HashMap::from([
+            ("branch".to_owned(), "refs/heads/main".to_owned()),
+            ("repo".to_owned(), "henchman".to_owned()),
+            ("size".to_owned(), "42".to_owned()),
+            ("private".to_owned(), "false".to_owned()),
+            ("commit".to_owned(), "abc".to_owned()),
+        ])));
+
+        let mapping = HashMap::from([("repo".to_owned(), "repository".to_owned())]);
+
+        assert!(extract_params(&mapping, &body).is_err());
+    }
+}
@@ -0,0 +1,131 @@
+use std::sync::Arc;
+
+use futures::sink::SinkExt;
+use futures::stream::StreamExt;
+
+use http::{header, HeaderValue};
+use hyper::Request;
+use hyper::upgrade::OnUpgrade;
+
+use tokio::sync::mpsc;
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::protocol::Role;
+
+use crate::json::RunInputJson;
+use crate::json_conv;
+use crate::run::{ActiveRun, AttachGuard};
+
+/// How many input frames can be waiting for the process to read them
+pub const INPUT_BUFFER: usize = 16;
+
+fn has_token(value: Option<&HeaderValue>, expected: &str) -> bool {
+    value.and_then(|value| value.to_str().ok())
+        .map(|value| value.split(',').any(|token| token.trim().eq_ignore_ascii_case(expected)))
+        .unwrap_or(false)
+}
+
+/// Whether the client is asking to switch to the WebSocket protocol
+pub fn is_upgrade<T>(req: &Request<T>) -> bool {
+    has_token(req.headers().get(header::CONNECTION), "upgrade")
+        && has_token(req.headers().get(header::UPGRADE), "websocket")
+}
+
+/// Value of the 'Sec-WebSocket-Accept' header for a valid upgrade request (RFC 6455, section 4.2)
+pub fn accept_key<T>(req: &Request<T>) -> Option<String> {
+    if !is_upgrade(req) || req.headers().get(header::SEC_WEBSOCKET_VERSION) != Some(&HeaderValue::from_static("13")) {
+        return None;
+    }
+
+    req.headers().get(header::SEC_WEBSOCKET_KEY)
+        .map(|key| tokio_tungstenite::tungstenite::handshake::derive_accept_key(key.as_bytes()))
+}
+
+/// Whether the upgrade request comes from a page served by this server. Browsers send cached Basic
+/// credentials with WebSocket handshakes from any site (and don't stop pages reading the frames),
+/// so other sites' pages are turned away. Clients that aren't browsers don't send 'Origin'.
+pub fn is_same_origin<T>(req: &Request<T>) -> bool {
+    let origin = match req.headers().get(header::ORIGIN) {
+        Some(origin) => origin,
+        None => return true,
+    };
+
+    let origin_host = origin.to_str().ok()
+        .and_then(|origin| origin.parse::<http::Uri>().ok())
+        .and_then(|origin| origin.authority().map(|authority| authority.as_str().to_owned()));
+
+    let host = req.headers().get(header::HOST).and_then(|host| host.to_str().ok());
+
+    match (origin_host, host) {
+        (Some(origin_host), Some(host)) => origin_host.eq_ignore_ascii_case(host),
+        _ => false // eg, 'Origin: null'
+    }
+}
+
+/// Send the run's events to the client as JSON text frames (the same objects as NDJSON output),
+/// and pass input frames on to the process. The process is killed if the client goes away first.
+pub async fn session(upgrade: OnUpgrade,
+                     run: Arc<ActiveRun>,
+                     guard: Option<AttachGuard>,
+                     mut input: Option<mpsc::Sender<Vec<u8>>>) {
+    let run_id = run.record().id;
+
+    let upgraded = match upgrade.await {
+        Ok(upgraded) => upgraded,
+        Err(err) => {
+            error!("Error upgrading connection for run {}: {}", run_id, err);
+            return;
+        }
+    };
+
+    let socket = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+    let (mut sink, mut stream) = socket.split();
+
+    let events = crate::run::events(run, 0);
+    futures::pin_mut!(events);
+
+    loop {
+        tokio::select! {
+            event = events.next() => match event {
+                Some(event) => {
+                    let event_json = serde_json::to_string(&json_conv::to_run_event_json(&event)).unwrap(); // TODO: handle error
+                    if sink.send(Message::Text(event_json)).await.is_err() {
+                        break;
+                    }
+                }
+                None => {
+                    let _ = sink.send(Message::Close(None)).await; // finished
+                    break;
+                }
+            },
+            message = stream.next() => match message {
+                Some(Ok(Message::Text(text))) => match serde_json::from_str::<RunInputJson>(&text) {
+                    Ok(RunInputJson::Input { data }) => match &input {
+                        Some(sender) => {
+                            if sender.send(data.into_bytes()).await.is_err() {
+                                input = None; // process isn't reading any more
+                            }
+                        }
+                        None => warn!("Ignoring input for run {} (task doesn't accept input, or input was closed)", run_id)
+                    },
+                    Ok(RunInputJson::Eof) => {
+                        input = None; // closes the process's input once everything sent is written
+                    }
+                    Err(err) => {
+                        warn!("Invalid WebSocket message for run {}: {}", run_id, err);
+                    }
+                },
+                Some(Ok(Message::Close(_))) | None => {
+                    break;
+                }
+                Some(Ok(_)) => {} // pings are answered automatically
+                Some(Err(err)) => {
+                    warn!("WebSocket error for run {}: {}", run_id, err);
+                    break;
+                }
+            }
+        }
+    }
+
+    drop(guard);
+}
@@ -27,6 +27,8 @@ use hyper::{Body, Client};
 
 use serde_json::{Value, json};
 
+use chrono::{DateTime, Timelike, Utc};
+
 use url::form_urlencoded;
 
 // const LISTEN_ADDR: &'static str = "127.0.0.1:0"; // choose a free port for each test
@@ -36,6 +38,9 @@ const CARGO_MANIFEST_DIR: &'static str = env!("CARGO_MANIFEST_DIR");
 //noinspection SpellCheckingInspection
 const DEFAULT_BASIC_AUTH: &'static str = "Basic YWRtaW46c2VjcmV0"; // base-64 encoded 'admin:secret'
 
+//noinspection SpellCheckingInspection
+const CI_TOKEN_SECRET: &str = "72a2db5cce2eb1f3cf644a14304d882d06cf3497f19f4924c46c0a4511372a3e";
+
 use std::error::{Error as StdError};
 use std::fmt::{Display, Formatter, Result as FormatResult};
 use http::Uri;
@@ -56,7 +61,7 @@ impl StdError for RuntimeError {}
 
 type GenericError = Box<dyn StdError>;
 
-async fn start_server() -> (SocketAddr, impl Future<Output=Result<(), Box<dyn StdError + Send>>>, oneshot::Sender<()>) {
+async fn start_server(config_file: &str) -> (SocketAddr, impl Future<Output=Result<(), Box<dyn StdError + Send>>>, oneshot::Sender<()>) {
     let (started_tx, started_rx) = oneshot::channel::<SocketAddr>();
 
     let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
@@ -64,7 +69,7 @@ async fn start_server() -> (SocketAddr, impl Future<Output=Result<(), Box<dyn St
     let resources_dir: PathBuf = format!("{}/tests/resources", CARGO_MANIFEST_DIR).into();
 
     let config = ServerConfig {
-        config: resources_dir.join("server.toml")
+        config: resources_dir.join(config_file)
     };
 
     let server_fut = tokio::spawn(async move {
@@ -97,11 +102,15 @@ async fn start_server() -> (SocketAddr, impl Future<Output=Result<(), Box<dyn St
 static LOG_INIT: Once = Once::new();
 
 async fn init_test() -> Result<(SocketAddr, impl Future<Output=Result<(), GenericError>>), GenericError> {
+    init_test_with("server.toml").await
+}
+
+async fn init_test_with(config_file: &str) -> Result<(SocketAddr, impl Future<Output=Result<(), GenericError>>), GenericError> {
     LOG_INIT.call_once(|| {
         env_logger::builder().filter_level(LevelFilter::Info).try_init().unwrap();
     });
 
-    let (local_addr, server_fut, shutdown_tx) = start_server().await;
+    let (local_addr, server_fut, shutdown_tx) = start_server(config_file).await;
 
     let server_fut = async {
         shutdown_tx.send(()).expect("Shutdown receiver was dropped");
@@ -179,12 +188,27 @@ async fn test_get_tasks() -> Result<(), GenericError> {
             .as_str().unwrap())
         .collect();
 
+    // restricted task should not be listed (user doesn't have the required role)
     let expected_names: HashSet<&str> = vec![
+        "admin_only",
+        "background",
+        "background_exit",
+        "detached",
         "example1",
+        "guest",
+        "hooked",
+        "locked",
+        "long",
         "param_boolean",
         "param_enum",
         "param_number",
         "param_required",
+        "queued",
+        "scheduled",
+        "stdin",
+        "streams",
+        "timeout",
+        "timeout_ignored",
     ].into_iter().collect();
 
     assert_eq!(res_names, expected_names);
@@ -384,3 +408,1956 @@ async fn should_validate_parameter_required() -> Result<(), Box<dyn std::error::
 }
 
 
+#[tokio::test]
+async fn should_record_run_history() -> Result<(), Box<dyn std::error::Error>> {
+    let (local_addr, server_fut) = init_test().await?;
+
+    let client = Client::new();
+
+    // Given a task has been run
+    let uri: Uri = format!("http://{}/api/tasks/example1/run", local_addr).parse()?;
+
+    let req_form: String = form_urlencoded::Serializer::new(String::new())
+        .append_pair("param1", "bar")
+        .finish();
+
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri(uri)
+        .header(header::AUTHORIZATION, DEFAULT_BASIC_AUTH)
+        .header(header::CONTENT_TYPE, HeaderValue::from_static("application/x-www-form-urlencoded"))
+        .body(hyper::Body::from(req_form))?;
+
+    let res: Response<hyper::Body> = client.request(req).await?;
+
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let run_id = res.headers().get("X-Run-Id")
+        .expect("Expected run ID header")
+        .to_str()?
+        .to_owned();
+
+    assert_eq!(get_response_text(res).await, "Parameter 1: bar\nParameter 2: 3\n[Exit code: 0]");
+
+    // When I fetch the run record
+    let req = Request::builder()
+        .method(Method::GET)
+        .uri(format!("http://{}/api/runs/{}", local_addr, run_id))
+        .header(header::AUTHORIZATION, DEFAULT_BASIC_AUTH)
+        .body(Body::empty())?;
+
+    let res: Response<hyper::Body> = client.request(req).await?;
+
+    // Then it should describe the completed run
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let res_json: Value = serde_json::from_slice(&hyper::body::to_bytes(res.into_body()).await?)?;
+
+    assert_eq!(res_json["id"], json!(run_id));
+    assert_eq!(res_json["task"], json!("example1"));
+    assert_eq!(res_json["user"], json!("admin"));
+    assert_eq!(res_json["params"], json!({"param1": "bar", "param2": "3"}));
+    assert_eq!(res_json["exit_code"], json!(0));
+    assert!(res_json["started_at"].is_string());
+    assert!(res_json["finished_at"].is_string());
+
+    // And the run should be listed
+    let req = Request::builder()
+        .method(Method::GET)
+        .uri(format!("http://{}/api/runs", local_addr))
+        .header(header::AUTHORIZATION, DEFAULT_BASIC_AUTH)
+        .body(Body::empty())?;
+
+    let res: Response<hyper::Body> = client.request(req).await?;
+
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let res_json: Value = serde_json::from_slice(&hyper::body::to_bytes(res.into_body()).await?)?;
+
+    assert!(res_json.as_array().unwrap().iter().any(|run| run["id"] == json!(run_id)));
+
+    // And its output should have been stored
+    let req = Request::builder()
+        .method(Method::GET)
+        .uri(format!("http://{}/api/runs/{}/output", local_addr, run_id))
+        .header(header::AUTHORIZATION, DEFAULT_BASIC_AUTH)
+        .body(Body::empty())?;
+
+    let res: Response<hyper::Body> = client.request(req).await?;
+
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(get_response_text(res).await, "Parameter 1: bar\nParameter 2: 3\n");
+
+    server_fut.await
+}
+
+async fn get_json(client: &Client<hyper::client::HttpConnector>, uri: String) -> Result<Value, Box<dyn std::error::Error>> {
+    let req = Request::builder()
+        .method(Method::GET)
+        .uri(uri)
+        .header(header::AUTHORIZATION, DEFAULT_BASIC_AUTH)
+        .body(Body::empty())?;
+
+    let res: Response<hyper::Body> = client.request(req).await?;
+
+    assert_eq!(res.status(), StatusCode::OK);
+
+    Ok(serde_json::from_slice(&hyper::body::to_bytes(res.into_body()).await?)?)
+}
+
+async fn get_text(client: &Client<hyper::client::HttpConnector>, uri: String) -> Result<String, Box<dyn std::error::Error>> {
+    let req = Request::builder()
+        .method(Method::GET)
+        .uri(uri)
+        .header(header::AUTHORIZATION, DEFAULT_BASIC_AUTH)
+        .body(Body::empty())?;
+
+    let res: Response<hyper::Body> = client.request(req).await?;
+
+    assert_eq!(res.status(), StatusCode::OK);
+
+    Ok(get_response_text(res).await)
+}
+
+#[tokio::test]
+async fn should_detach_task_run() -> Result<(), Box<dyn std::error::Error>> {
+    let (local_addr, server_fut) = init_test().await?;
+
+    let client = Client::new();
+
+    // Given a task configured to run detached
+    let uri: Uri = format!("http://{}/api/tasks/detached/run", local_addr).parse()?;
+
+    // When I run the task
+    let req = Request::builder()
+        .method(Method::GET)
+        .uri(uri)
+        .header(header::AUTHORIZATION, DEFAULT_BASIC_AUTH)
+        .body(Body::empty())?;
+
+    let res: Response<hyper::Body> = client.request(req).await?;
+
+    // Then the request should be accepted without waiting for the process
+    assert_eq!(res.status(), StatusCode::ACCEPTED);
+
+    let location = res.headers().get(header::LOCATION)
+        .expect("Expected location header")
+        .to_str()?
+        .to_owned();
+
+    let res_json: Value = serde_json::from_slice(&hyper::body::to_bytes(res.into_body()).await?)?;
+
+    let run_id = res_json["id"].as_str().unwrap().to_owned();
+
+    assert_eq!(location, format!("/api/runs/{}", run_id));
+    assert_eq!(res_json["finished_at"], Value::Null);
+
+    // And I can attach to the running process to see all of its output
+    let res_text = get_text(&client, format!("http://{}/api/runs/{}/attach", local_addr, run_id)).await?;
+
+    assert_eq!(res_text, "Started\nFinished\n[Exit code: 0]");
+
+    // And I can attach again after it has finished (from an offset)
+    let res_text = get_text(&client, format!("http://{}/api/runs/{}/attach?offset=1", local_addr, run_id)).await?;
+
+    assert_eq!(res_text, "Finished\n[Exit code: 0]");
+
+    let res_json = get_json(&client, format!("http://{}{}", local_addr, location)).await?;
+
+    assert_eq!(res_json["exit_code"], json!(0));
+
+    server_fut.await
+}
+
+#[tokio::test]
+async fn should_detach_task_run_when_requested() -> Result<(), Box<dyn std::error::Error>> {
+    let (local_addr, server_fut) = init_test().await?;
+
+    let client = Client::new();
+
+    // When I ask for a task to run asynchronously
+    let uri: Uri = format!("http://{}/api/tasks/param_required/run?param1=foo", local_addr).parse()?;
+
+    let req = Request::builder()
+        .method(Method::GET)
+        .uri(uri)
+        .header(header::AUTHORIZATION, DEFAULT_BASIC_AUTH)
+        .header("Prefer", "respond-async")
+        .body(Body::empty())?;
+
+    let res: Response<hyper::Body> = client.request(req).await?;
+
+    // Then the request should be accepted
+    assert_eq!(res.status(), StatusCode::ACCEPTED);
+
+    let run_id = res.headers().get("X-Run-Id")
+        .expect("Expected run ID header")
+        .to_str()?
+        .to_owned();
+
+    // And the output should be available by attaching
+    let res_text = get_text(&client, format!("http://{}/api/runs/{}/attach", local_addr, run_id)).await?;
+
+    assert_eq!(res_text, "Success: foo\n[Exit code: 0]");
+
+    server_fut.await
+}
+
+#[tokio::test]
+async fn should_authorize_task_by_role() -> Result<(), Box<dyn std::error::Error>> {
+    let (local_addr, server_fut) = init_test().await?;
+
+    let client = Client::new();
+
+    // Given a task that requires a role the user doesn't have
+    let uri: Uri = format!("http://{}/api/tasks/restricted/run", local_addr).parse()?;
+
+    // When I attempt to run it
+    let req = Request::builder()
+        .method(Method::GET)
+        .uri(uri)
+        .header(header::AUTHORIZATION, DEFAULT_BASIC_AUTH)
+        .body(Body::empty())?;
+
+    let res: Response<hyper::Body> = client.request(req).await?;
+
+    // Then the request should be forbidden
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    assert_eq!(get_response_html(res).await, "Forbidden");
+
+    // When I run a task requiring one of the user's roles
+    let res_text = get_text(&client, format!("http://{}/api/tasks/admin_only/run", local_addr)).await?;
+
+    // Then the task should be run
+    assert_eq!(res_text, "Admin\n[Exit code: 0]");
+
+    server_fut.await
+}
+
+async fn get_unauthenticated(client: &Client<hyper::client::HttpConnector>, uri: String) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let req = Request::builder()
+        .method(Method::GET)
+        .uri(uri)
+        .body(Body::empty())?;
+
+    Ok(client.request(req).await?)
+}
+
+async fn get_task_names(res: Response<Body>) -> Result<HashSet<String>, Box<dyn std::error::Error>> {
+    let res_json: Value = serde_json::from_slice(&hyper::body::to_bytes(res.into_body()).await?)?;
+
+    Ok(res_json.as_array().unwrap()
+        .iter()
+        .map(|x| x["name"].as_str().unwrap().to_owned())
+        .collect())
+}
+
+#[tokio::test]
+async fn should_allow_guest_access() -> Result<(), Box<dyn std::error::Error>> {
+    // Given guest access is enabled
+    let (local_addr, server_fut) = init_test_with("server_guest.toml").await?;
+
+    let client = Client::new();
+
+    // When I list tasks without credentials
+    let res = get_unauthenticated(&client, format!("http://{}/api/tasks", local_addr)).await?;
+
+    // Then I should only see tasks allowed for guests
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(get_task_names(res).await?, vec!["guest".to_owned()].into_iter().collect());
+
+    // And I should be able to run them
+    let res = get_unauthenticated(&client, format!("http://{}/api/tasks/guest/run", local_addr)).await?;
+
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(get_response_text(res).await, "Guest\n[Exit code: 0]");
+
+    // But I should be asked to log in for any other task
+    let res = get_unauthenticated(&client, format!("http://{}/api/tasks/param_required/run?param1=foo", local_addr)).await?;
+
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+    // And logging in should still work
+    let res_text = get_text(&client, format!("http://{}/api/tasks/param_required/run?param1=foo", local_addr)).await?;
+
+    assert_eq!(res_text, "Success: foo\n[Exit code: 0]");
+
+    server_fut.await
+}
+
+#[tokio::test]
+async fn should_skip_auth_when_disabled() -> Result<(), Box<dyn std::error::Error>> {
+    // Given authentication is disabled
+    let (local_addr, server_fut) = init_test_with("server_noauth.toml").await?;
+
+    let client = Client::new();
+
+    // When I list tasks without credentials
+    let res = get_unauthenticated(&client, format!("http://{}/api/tasks", local_addr)).await?;
+
+    // Then I should see all tasks (including restricted ones)
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(get_task_names(res).await?.contains("restricted"));
+
+    // And I should be able to run them
+    let res = get_unauthenticated(&client, format!("http://{}/api/tasks/restricted/run", local_addr)).await?;
+
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(get_response_text(res).await, "Restricted\n[Exit code: 0]");
+
+    server_fut.await
+}
+
+async fn get_with_authorization(client: &Client<hyper::client::HttpConnector>, uri: String, authorization: String) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let req = Request::builder()
+        .method(Method::GET)
+        .uri(uri)
+        .header(header::AUTHORIZATION, authorization)
+        .body(Body::empty())?;
+
+    Ok(client.request(req).await?)
+}
+
+#[tokio::test]
+async fn should_authenticate_bearer_token() -> Result<(), Box<dyn std::error::Error>> {
+    let (local_addr, server_fut) = init_test().await?;
+
+    let client = Client::new();
+
+    let uri = format!("http://{}/api/tasks/restricted/run", local_addr);
+
+    // When I run a task with a token that has the required role
+    let res = get_with_authorization(&client, uri.clone(), format!("Bearer ci.{}", CI_TOKEN_SECRET)).await?;
+
+    // Then the task should be run (as the token)
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let run_id = res.headers().get("X-Run-Id").unwrap().to_str()?.to_owned();
+
+    assert_eq!(get_response_text(res).await, "Restricted\n[Exit code: 0]");
+
+    let res = get_with_authorization(&client, format!("http://{}/api/runs/{}", local_addr, run_id), format!("Bearer ci.{}", CI_TOKEN_SECRET)).await?;
+
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let res_json: Value = serde_json::from_slice(&hyper::body::to_bytes(res.into_body()).await?)?;
+
+    assert_eq!(res_json["user"], json!("ci"));
+
+    // When I use the wrong secret
+    let res = get_with_authorization(&client, uri.clone(), "Bearer ci.0123456789abcdef".to_owned()).await?;
+
+    // Then the request should be unauthorized
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+    // When I use an expired token (even with the right secret)
+    let res = get_with_authorization(&client, uri.clone(), format!("Bearer expired.{}", CI_TOKEN_SECRET)).await?;
+
+    // Then the request should be unauthorized
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+    server_fut.await
+}
+
+fn get_session_cookie(res: &Response<Body>) -> Option<String> {
+    res.headers().get(header::SET_COOKIE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(|value| value.to_owned())
+}
+
+async fn post_form(client: &Client<hyper::client::HttpConnector>, uri: String, cookie: Option<&str>, form: &[(&str, &str)]) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let req_form: String = form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(form)
+        .finish();
+
+    let mut req = Request::builder()
+        .method(Method::POST)
+        .uri(uri)
+        .header(header::CONTENT_TYPE, HeaderValue::from_static("application/x-www-form-urlencoded"));
+
+    if let Some(cookie) = cookie {
+        req = req.header(header::COOKIE, cookie);
+    }
+
+    Ok(client.request(req.body(Body::from(req_form))?).await?)
+}
+
+async fn get_with_cookie(client: &Client<hyper::client::HttpConnector>, uri: String, cookie: &str) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let req = Request::builder()
+        .method(Method::GET)
+        .uri(uri)
+        .header(header::COOKIE, cookie)
+        .body(Body::empty())?;
+
+    Ok(client.request(req).await?)
+}
+
+#[tokio::test]
+async fn should_login_with_session_cookie() -> Result<(), Box<dyn std::error::Error>> {
+    let (local_addr, server_fut) = init_test().await?;
+
+    let client = Client::new();
+
+    // When I open a web page without logging in
+    let res = get_unauthenticated(&client, format!("http://{}/web/tasks", local_addr)).await?;
+
+    // Then I should be sent to the login page
+    assert_eq!(res.status(), StatusCode::SEE_OTHER);
+    assert_eq!(res.headers().get(header::LOCATION), Some(&HeaderValue::from_static("/web/login?redirect=%2Fweb%2Ftasks")));
+
+    let res = get_unauthenticated(&client, format!("http://{}/web/login", local_addr)).await?;
+
+    assert_eq!(res.status(), StatusCode::OK);
+
+    // When I log in with the wrong password
+    let res = post_form(&client, format!("http://{}/api/login", local_addr), None,
+                        &[("username", "admin"), ("password", "wrong"), ("redirect", "/web/tasks")]).await?;
+
+    // Then I should be sent back to the login page
+    assert_eq!(res.status(), StatusCode::SEE_OTHER);
+    assert_eq!(res.headers().get(header::LOCATION), Some(&HeaderValue::from_static("/web/login?error=invalid&redirect=%2Fweb%2Ftasks")));
+    assert_eq!(get_session_cookie(&res), None);
+
+    // Likewise for a user that doesn't exist
+    let res = post_form(&client, format!("http://{}/api/login", local_addr), None,
+                        &[("username", "nobody"), ("password", "secret"), ("redirect", "/web/tasks")]).await?;
+
+    assert_eq!(res.status(), StatusCode::SEE_OTHER);
+    assert_eq!(res.headers().get(header::LOCATION), Some(&HeaderValue::from_static("/web/login?error=invalid&redirect=%2Fweb%2Ftasks")));
+    assert_eq!(get_session_cookie(&res), None);
+
+    // When I log in with the correct password
+    let res = post_form(&client, format!("http://{}/api/login", local_addr), None,
+                        &[("username", "admin"), ("password", "secret"), ("redirect", "/web/tasks")]).await?;
+
+    // Then I should get a session cookie and be sent to where I was going
+    assert_eq!(res.status(), StatusCode::SEE_OTHER);
+    assert_eq!(res.headers().get(header::LOCATION), Some(&HeaderValue::from_static("/web/tasks")));
+
+    let set_cookie = res.headers().get(header::SET_COOKIE).unwrap().to_str()?.to_owned();
+    assert!(set_cookie.contains("HttpOnly"));
+    assert!(set_cookie.contains("SameSite=Strict"));
+
+    let cookie = get_session_cookie(&res).unwrap();
+
+    // And the cookie should authenticate me
+    let res = get_with_cookie(&client, format!("http://{}/api/session", local_addr), &cookie).await?;
+
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let session_json: Value = serde_json::from_slice(&hyper::body::to_bytes(res.into_body()).await?)?;
+
+    assert_eq!(session_json["username"], json!("admin"));
+
+    let csrf_token = session_json["csrf_token"].as_str().unwrap().to_owned();
+
+    // When I run a task without the CSRF token
+    let uri = format!("http://{}/api/tasks/example1/run", local_addr);
+
+    let res = post_form(&client, uri.clone(), Some(&cookie), &[("param1", "foo")]).await?;
+
+    // Then the request should be forbidden
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    // When I run a task with the CSRF token
+    let res = post_form(&client, uri.clone(), Some(&cookie), &[("param1", "foo"), ("_csrf", &csrf_token)]).await?;
+
+    // Then the task should be run
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(get_response_text(res).await, "Parameter 1: foo\nParameter 2: 3\n[Exit code: 0]");
+
+    // When I log out
+    let res = post_form(&client, format!("http://{}/api/logout", local_addr), Some(&cookie), &[("_csrf", &csrf_token)]).await?;
+
+    // Then the cookie should be cleared
+    assert_eq!(res.status(), StatusCode::SEE_OTHER);
+    assert_eq!(get_session_cookie(&res), Some("session_id=".to_owned()));
+
+    // And the session should no longer be valid
+    let res = get_with_cookie(&client, format!("http://{}/api/session", local_addr), &cookie).await?;
+
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+    server_fut.await
+}
+
+async fn post_with_authorization(client: &Client<hyper::client::HttpConnector>, uri: String, authorization: String) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri(uri)
+        .header(header::AUTHORIZATION, authorization)
+        .body(Body::empty())?;
+
+    Ok(client.request(req).await?)
+}
+
+fn write_reload_task(dir: &std::path::Path, name: &str, content: &str) {
+    std::fs::write(dir.join("tasks").join(format!("{}.task.toml", name)), content).unwrap();
+}
+
+//noinspection SpellCheckingInspection
+const RELOAD_SERVER_TOML: &str = r#"
+[server]
+dir = "tasks"
+listen = "127.0.0.1:0"
+
+[[auth.users]]
+username = "admin"
+password = "0100002710053615732b4de713b68cf98b3405e06ac373182d28c9932f19569177addbb63889db74bc6ecdb6ab54a5d5f395356c1e"
+roles = ["ADMIN"]
+
+[[auth.tokens]]
+name = "ci"
+hash = "0100002710728bbce9de7e6270c199a3b6b54f30bba846f7a76318e8c379cde2293aade09caf1d22de25acc1a0caea65b0d6155e12"
+roles = ["DEPLOYER"]
+"#;
+
+const RELOAD_TASK_TOML: &str = r#"
+[task]
+description = "Reloaded task"
+method = ["POST"]
+
+[exec]
+command = "echo"
+"#;
+
+#[tokio::test]
+async fn should_reload_tasks_on_request() -> Result<(), Box<dyn std::error::Error>> {
+    // copy of the configuration that the test can change
+    let config_dir: PathBuf = format!("{}/target/test-reload", CARGO_MANIFEST_DIR).into();
+
+    let _ = std::fs::remove_dir_all(&config_dir);
+    std::fs::create_dir_all(config_dir.join("tasks"))?;
+    std::fs::write(config_dir.join("server.toml"), RELOAD_SERVER_TOML)?;
+
+    write_reload_task(&config_dir, "first", RELOAD_TASK_TOML);
+
+    let (local_addr, server_fut) = init_test_with(config_dir.join("server.toml").to_str().unwrap()).await?;
+
+    let client = Client::new();
+
+    let tasks_uri = format!("http://{}/api/tasks", local_addr);
+    let reload_uri = format!("http://{}/api/admin/reload", local_addr);
+
+    let res = get_with_authorization(&client, tasks_uri.clone(), DEFAULT_BASIC_AUTH.to_owned()).await?;
+
+    assert_eq!(get_task_names(res).await?, HashSet::from(["first".to_owned()]));
+
+    // Given a new task file
+    write_reload_task(&config_dir, "second", RELOAD_TASK_TOML);
+
+    // When a user without an admin role asks for a reload
+    let res = post_with_authorization(&client, reload_uri.clone(), format!("Bearer ci.{}", CI_TOKEN_SECRET)).await?;
+
+    // Then it should be forbidden
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    // When an admin asks for a reload
+    let res = post_with_authorization(&client, reload_uri.clone(), DEFAULT_BASIC_AUTH.to_owned()).await?;
+
+    // Then the new task should be loaded
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let reload_json: Value = serde_json::from_slice(&hyper::body::to_bytes(res.into_body()).await?)?;
+
+    assert_eq!(reload_json, json!({"reloaded": true, "tasks": 2, "users": 1, "tokens": 1, "secrets": 0}));
+
+    let res = get_with_authorization(&client, tasks_uri.clone(), DEFAULT_BASIC_AUTH.to_owned()).await?;
+
+    assert_eq!(get_task_names(res).await?, HashSet::from(["first".to_owned(), "second".to_owned()]));
+
+    // Given a broken task file
+    write_reload_task(&config_dir, "third", "[task\n");
+
+    // When an admin asks for a reload
+    let res = post_with_authorization(&client, reload_uri.clone(), DEFAULT_BASIC_AUTH.to_owned()).await?;
+
+    // Then the error should be reported
+    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+    let reload_json: Value = serde_json::from_slice(&hyper::body::to_bytes(res.into_body()).await?)?;
+
+    assert_eq!(reload_json["reloaded"], json!(false));
+    assert_eq!(reload_json["tasks"], json!(2));
+    assert!(reload_json["error"].as_str().unwrap().contains("third.task.toml"));
+
+    // And the previous tasks should still be available
+    let res = get_with_authorization(&client, tasks_uri.clone(), DEFAULT_BASIC_AUTH.to_owned()).await?;
+
+    assert_eq!(get_task_names(res).await?, HashSet::from(["first".to_owned(), "second".to_owned()]));
+
+    server_fut.await
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn should_follow_symlinks_when_reloading() -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::symlink;
+
+    let config_dir: PathBuf = format!("{}/target/test-reload-symlinks", CARGO_MANIFEST_DIR).into();
+
+    let _ = std::fs::remove_dir_all(&config_dir);
+    std::fs::create_dir_all(config_dir.join("tasks"))?;
+    std::fs::create_dir_all(config_dir.join("shared"))?;
+    std::fs::write(config_dir.join("server.toml"), RELOAD_SERVER_TOML)?;
+
+    write_reload_task(&config_dir, "first", RELOAD_TASK_TOML);
+
+    let (local_addr, server_fut) = init_test_with(config_dir.join("server.toml").to_str().unwrap()).await?;
+
+    let client = Client::new();
+
+    let tasks_uri = format!("http://{}/api/tasks", local_addr);
+    let reload_uri = format!("http://{}/api/admin/reload", local_addr);
+
+    // Given a symlink to a task file elsewhere, and one to a directory (that links back to the task directory)
+    std::fs::write(config_dir.join("shared").join("linked.task.toml"), RELOAD_TASK_TOML)?;
+    symlink(config_dir.join("shared").join("linked.task.toml"), config_dir.join("tasks").join("linked.task.toml"))?;
+    symlink(config_dir.join("tasks"), config_dir.join("shared").join("loop"))?;
+    symlink(config_dir.join("shared"), config_dir.join("tasks").join("shared"))?;
+
+    // When an admin asks for a reload
+    let res = post_with_authorization(&client, reload_uri.clone(), DEFAULT_BASIC_AUTH.to_owned()).await?;
+
+    // Then the linked task should be loaded (once)
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let res = get_with_authorization(&client, tasks_uri.clone(), DEFAULT_BASIC_AUTH.to_owned()).await?;
+
+    assert_eq!(get_task_names(res).await?, HashSet::from(["first".to_owned(), "linked".to_owned()]));
+
+    // Given a broken symlink
+    symlink(config_dir.join("missing.task.toml"), config_dir.join("tasks").join("broken.task.toml"))?;
+
+    // When an admin asks for a reload
+    let res = post_with_authorization(&client, reload_uri.clone(), DEFAULT_BASIC_AUTH.to_owned()).await?;
+
+    // Then the error should be reported (rather than taking the server down)
+    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+    let reload_json: Value = serde_json::from_slice(&hyper::body::to_bytes(res.into_body()).await?)?;
+
+    assert!(reload_json["error"].as_str().unwrap().contains("broken.task.toml"), "{}", reload_json);
+
+    let res = get_with_authorization(&client, tasks_uri.clone(), DEFAULT_BASIC_AUTH.to_owned()).await?;
+
+    assert_eq!(get_task_names(res).await?, HashSet::from(["first".to_owned(), "linked".to_owned()]));
+
+    server_fut.await
+}
+
+#[tokio::test]
+async fn should_only_show_runs_of_removed_tasks_to_admins() -> Result<(), Box<dyn std::error::Error>> {
+    let config_dir: PathBuf = format!("{}/target/test-removed-task-runs", CARGO_MANIFEST_DIR).into();
+
+    let _ = std::fs::remove_dir_all(&config_dir);
+    std::fs::create_dir_all(config_dir.join("tasks"))?;
+    std::fs::write(config_dir.join("server.toml"), format!("{}\n[history]\ndir = \"history\"\n", RELOAD_SERVER_TOML))?;
+
+    write_reload_task(&config_dir, "first", RELOAD_TASK_TOML);
+
+    let (local_addr, server_fut) = init_test_with(config_dir.join("server.toml").to_str().unwrap()).await?;
+
+    let client = Client::new();
+
+    let ci_auth = format!("Bearer ci.{}", CI_TOKEN_SECRET);
+
+    // Given a run of a task, by a user without an admin role
+    let res = post_with_authorization(&client, format!("http://{}/api/tasks/first/run", local_addr), ci_auth.clone()).await?;
+
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let run_id = res.headers().get("X-Run-Id").unwrap().to_str()?.to_owned();
+    let run_uri = format!("http://{}/api/runs/{}", local_addr, run_id);
+
+    get_response_text(res).await;
+
+    let res = get_with_authorization(&client, run_uri.clone(), ci_auth.clone()).await?;
+
+    assert_eq!(res.status(), StatusCode::OK);
+
+    // When the task is removed
+    std::fs::remove_file(config_dir.join("tasks").join("first.task.toml"))?;
+
+    let res = post_with_authorization(&client, format!("http://{}/api/admin/reload", local_addr), DEFAULT_BASIC_AUTH.to_owned()).await?;
+
+    assert_eq!(res.status(), StatusCode::OK);
+
+    // Then only admins should be able to see its runs
+    let res = get_with_authorization(&client, run_uri.clone(), ci_auth.clone()).await?;
+
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    let res = get_with_authorization(&client, format!("http://{}/api/runs", local_addr), ci_auth.clone()).await?;
+    let runs_json: Value = serde_json::from_slice(&hyper::body::to_bytes(res.into_body()).await?)?;
+
+    assert_eq!(runs_json, json!([]));
+
+    let run_json = get_json(&client, run_uri.clone()).await?;
+
+    assert_eq!(run_json["task"], json!("first"));
+
+    server_fut.await
+}
+
+#[tokio::test]
+async fn should_stop_task_after_timeout() -> Result<(), Box<dyn std::error::Error>> {
+    let (local_addr, server_fut) = init_test().await?;
+
+    let client = Client::new();
+
+    // When I run a task that takes longer than its timeout
+    let res = get_with_authorization(&client, format!("http://{}/api/tasks/timeout/run", local_addr), DEFAULT_BASIC_AUTH.to_owned()).await?;
+
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let run_id = res.headers().get("X-Run-Id").unwrap().to_str()?.to_owned();
+
+    // Then it should be asked to stop, and the output should say why
+    assert_eq!(get_response_text(res).await, "Started\nStopping\n[Timed out after 1s]");
+
+    let run_json = get_json(&client, format!("http://{}/api/runs/{}", local_addr, run_id)).await?;
+
+    assert_eq!(run_json["exit_code"], json!(3));
+    assert_eq!(run_json["timed_out_after_secs"], json!(1));
+
+    // And the stored output should end the same way
+    let output = get_text(&client, format!("http://{}/api/runs/{}/attach", local_addr, run_id)).await?;
+
+    assert_eq!(output, "Started\nStopping\n[Timed out after 1s]");
+
+    server_fut.await
+}
+
+#[tokio::test]
+async fn should_kill_task_ignoring_timeout() -> Result<(), Box<dyn std::error::Error>> {
+    let (local_addr, server_fut) = init_test().await?;
+
+    let client = Client::new();
+
+    // When I run a task that doesn't stop when asked
+    let res = get_with_authorization(&client, format!("http://{}/api/tasks/timeout_ignored/run", local_addr), DEFAULT_BASIC_AUTH.to_owned()).await?;
+
+    let run_id = res.headers().get("X-Run-Id").unwrap().to_str()?.to_owned();
+
+    // Then it should be killed after the grace period
+    assert_eq!(get_response_text(res).await, "Started\n[Timed out after 1s]");
+
+    let run_json = get_json(&client, format!("http://{}/api/runs/{}", local_addr, run_id)).await?;
+
+    assert_eq!(run_json["exit_code"], json!(null)); // killed by signal
+    assert_eq!(run_json["signal"], json!(9));
+    assert_eq!(run_json["timed_out_after_secs"], json!(1));
+
+    server_fut.await
+}
+
+/// Process IDs of the background jobs printed by 'background.sh'
+fn get_background_pids(output: &str) -> Vec<String> {
+    output.lines()
+        .filter_map(|line| line.strip_prefix("Background: "))
+        .map(|pid| pid.to_owned())
+        .collect()
+}
+
+/// Zombies count as stopped (they're up to whoever inherited them to reap)
+fn is_process_running(pid: &str) -> bool {
+    let output = std::process::Command::new("ps")
+        .args(["-o", "stat=", "-p", pid])
+        .output()
+        .expect("Error running ps");
+
+    let stat = String::from_utf8_lossy(&output.stdout);
+
+    !stat.trim().is_empty() && !stat.trim().starts_with('Z')
+}
+
+async fn wait_for_processes_to_stop(pids: &[String]) {
+    for _ in 0..50 {
+        if !pids.iter().any(|pid| is_process_running(pid)) {
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    panic!("Processes still running: {:?}", pids.iter().filter(|pid| is_process_running(pid)).collect::<Vec<_>>());
+}
+
+#[tokio::test]
+async fn should_kill_background_jobs_when_disconnected() -> Result<(), Box<dyn std::error::Error>> {
+    use hyper::body::HttpBody;
+
+    let (local_addr, server_fut) = init_test().await?;
+
+    let client = Client::new();
+
+    // Given a task with background jobs is running
+    let res = get_with_authorization(&client, format!("http://{}/api/tasks/background/run", local_addr), DEFAULT_BASIC_AUTH.to_owned()).await?;
+
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let mut body = res.into_body();
+    let mut output = String::new();
+
+    while !output.contains("Waiting") {
+        let chunk = body.data().await.expect("Output ended early")?;
+        output.push_str(std::str::from_utf8(&chunk)?);
+    }
+
+    let pids = get_background_pids(&output);
+
+    assert_eq!(pids.len(), 2);
+    assert!(pids.iter().all(|pid| is_process_running(pid)));
+
+    // When the client goes away
+    drop(body);
+
+    // Then the background jobs should be killed too
+    wait_for_processes_to_stop(&pids).await;
+
+    server_fut.await
+}
+
+#[tokio::test]
+async fn should_stop_background_jobs_after_timeout() -> Result<(), Box<dyn std::error::Error>> {
+    let (local_addr, server_fut) = init_test().await?;
+
+    let client = Client::new();
+
+    // When a task with background jobs times out
+    let res = get_with_authorization(&client, format!("http://{}/api/tasks/background/run", local_addr), DEFAULT_BASIC_AUTH.to_owned()).await?;
+
+    let output = get_response_text(res).await;
+
+    // Then it should be stopped without waiting for the background jobs
+    assert!(output.ends_with("Waiting\n[Timed out after 1s]"), "Unexpected output: {}", output);
+
+    // And the background jobs should be stopped too
+    wait_for_processes_to_stop(&get_background_pids(&output)).await;
+
+    server_fut.await
+}
+
+#[tokio::test]
+async fn should_kill_background_jobs_left_behind() -> Result<(), Box<dyn std::error::Error>> {
+    let (local_addr, server_fut) = init_test().await?;
+
+    let client = Client::new();
+
+    // When a task exits leaving background jobs running
+    let started = std::time::Instant::now();
+
+    let res = get_with_authorization(&client, format!("http://{}/api/tasks/background_exit/run", local_addr), DEFAULT_BASIC_AUTH.to_owned()).await?;
+
+    let output = get_response_text(res).await;
+
+    // Then the run should finish without waiting for the background jobs
+    assert!(output.ends_with("Exiting\n[Exit code: 0]"), "Unexpected output: {}", output);
+    assert!(started.elapsed() < std::time::Duration::from_secs(10));
+
+    // And the background jobs should not be left running
+    wait_for_processes_to_stop(&get_background_pids(&output)).await;
+
+    server_fut.await
+}
+
+#[tokio::test]
+async fn should_cancel_run() -> Result<(), Box<dyn std::error::Error>> {
+    use hyper::body::HttpBody;
+
+    let (local_addr, server_fut) = init_test().await?;
+
+    let client = Client::new();
+
+    // Given a run has started
+    let res = get_with_authorization(&client, format!("http://{}/api/tasks/long/run", local_addr), DEFAULT_BASIC_AUTH.to_owned()).await?;
+
+    assert_eq!(res.status(), StatusCode::ACCEPTED);
+
+    let run_id = res.headers().get("X-Run-Id").unwrap().to_str()?.to_owned();
+
+    let res = get_with_authorization(&client, format!("http://{}/api/runs/{}/attach", local_addr, run_id), DEFAULT_BASIC_AUTH.to_owned()).await?;
+
+    let mut body = res.into_body();
+    let mut output = String::new();
+
+    while !output.contains("Started") {
+        let chunk = body.data().await.expect("Output ended early")?;
+        output.push_str(std::str::from_utf8(&chunk)?);
+    }
+
+    drop(body);
+
+    // When somebody else cancels it
+    let cancel_uri = format!("http://{}/api/runs/{}/cancel", local_addr, run_id);
+
+    let res = post_with_authorization(&client, cancel_uri.clone(), format!("Bearer ci.{}", CI_TOKEN_SECRET)).await?;
+
+    // Then the process should be stopped, and the final status returned
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let run_json: Value = serde_json::from_slice(&hyper::body::to_bytes(res.into_body()).await?)?;
+
+    assert_eq!(run_json["id"], json!(run_id));
+    assert_eq!(run_json["exit_code"], json!(4));
+    assert_eq!(run_json["cancelled_by"], json!("ci"));
+    assert!(run_json["finished_at"].is_string());
+
+    // And the output should say who cancelled it
+    let output = get_text(&client, format!("http://{}/api/runs/{}/attach", local_addr, run_id)).await?;
+
+    assert_eq!(output, "Started\nStopping\n[Cancelled by ci]");
+
+    // When cancelling it again
+    let res = post_with_authorization(&client, cancel_uri.clone(), DEFAULT_BASIC_AUTH.to_owned()).await?;
+
+    // Then there should be nothing left to cancel
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+
+    // When cancelling a run that doesn't exist
+    let res = post_with_authorization(&client, format!("http://{}/api/runs/{}/cancel", local_addr, uuid::Uuid::new_v4()), DEFAULT_BASIC_AUTH.to_owned()).await?;
+
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    server_fut.await
+}
+
+#[tokio::test]
+async fn should_queue_run_waiting_for_lock() -> Result<(), Box<dyn std::error::Error>> {
+    use hyper::body::HttpBody;
+
+    let (local_addr, server_fut) = init_test().await?;
+
+    let client = Client::new();
+
+    // Given a run is holding a lock
+    let res = get_with_authorization(&client, format!("http://{}/api/tasks/locked/run", local_addr), DEFAULT_BASIC_AUTH.to_owned()).await?;
+
+    assert_eq!(res.status(), StatusCode::ACCEPTED);
+
+    let run_id = res.headers().get("X-Run-Id").unwrap().to_str()?.to_owned();
+
+    let res = get_with_authorization(&client, format!("http://{}/api/runs/{}/attach", local_addr, run_id), DEFAULT_BASIC_AUTH.to_owned()).await?;
+
+    let mut body = res.into_body();
+    let mut output = String::new();
+
+    while !output.contains("Started") {
+        let chunk = body.data().await.expect("Output ended early")?;
+        output.push_str(std::str::from_utf8(&chunk)?);
+    }
+
+    drop(body);
+
+    // When another run needs the same lock (without queueing)
+    let res = get_with_authorization(&client, format!("http://{}/api/tasks/locked/run", local_addr), DEFAULT_BASIC_AUTH.to_owned()).await?;
+
+    // Then it should be rejected, saying which run holds the lock
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+
+    let message = get_response_html(res).await;
+
+    assert!(message.contains(&format!("Lock test-lock is held by run {} of task locked (started by admin", run_id)), "Unexpected message: {}", message);
+
+    // When a task that queues needs the same lock
+    let res = get_with_authorization(&client, format!("http://{}/api/tasks/queued/run", local_addr), DEFAULT_BASIC_AUTH.to_owned()).await?;
+
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let mut queued_body = res.into_body();
+
+    // Then it should wait for the lock
+    let chunk = queued_body.data().await.expect("Output ended early")?;
+
+    assert_eq!(std::str::from_utf8(&chunk)?, "[Queued: position 1]\n");
+
+    let waiting = tokio::time::timeout(std::time::Duration::from_millis(500), queued_body.data()).await;
+
+    assert!(waiting.is_err(), "Queued run should not have started: {:?}", waiting);
+
+    // And start once the lock is released
+    let res = post_with_authorization(&client, format!("http://{}/api/runs/{}/cancel", local_addr, run_id), DEFAULT_BASIC_AUTH.to_owned()).await?;
+
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let output = String::from_utf8(hyper::body::to_bytes(queued_body).await?.to_vec())?;
+
+    assert_eq!(output, "Got the lock\n[Exit code: 0]");
+
+    server_fut.await
+}
+
+#[tokio::test]
+async fn should_queue_runs_over_limit() -> Result<(), Box<dyn std::error::Error>> {
+    use hyper::body::HttpBody;
+
+    let (local_addr, server_fut) = init_test_with("server_queue.toml").await?;
+
+    let client = Client::new();
+
+    // Given the only slot is in use
+    let res = get_with_authorization(&client, format!("http://{}/api/tasks/long/run", local_addr), DEFAULT_BASIC_AUTH.to_owned()).await?;
+
+    assert_eq!(res.status(), StatusCode::ACCEPTED);
+
+    let long_run_id = res.headers().get("X-Run-Id").unwrap().to_str()?.to_owned();
+
+    // When running another task
+    let res = get_with_authorization(&client, format!("http://{}/api/tasks/guest/run", local_addr), DEFAULT_BASIC_AUTH.to_owned()).await?;
+
+    assert_eq!(res.status(), StatusCode::OK);
+
+    // Then it should say where it is in the queue
+    let mut queued_body = res.into_body();
+
+    let chunk = queued_body.data().await.expect("Output ended early")?;
+
+    assert_eq!(std::str::from_utf8(&chunk)?, "[Queued: position 1]\n");
+
+    // When running a detached task
+    let res = get_with_authorization(&client, format!("http://{}/api/tasks/detached/run", local_addr), DEFAULT_BASIC_AUTH.to_owned()).await?;
+
+    assert_eq!(res.status(), StatusCode::ACCEPTED);
+
+    let detached_run_id = res.headers().get("X-Run-Id").unwrap().to_str()?.to_owned();
+
+    // Then it should be queued behind the other one
+    let run_json = get_json(&client, format!("http://{}/api/runs/{}", local_addr, detached_run_id)).await?;
+
+    assert_eq!(run_json["queue_position"], json!(2));
+
+    let queue_json = get_json(&client, format!("http://{}/api/queue", local_addr)).await?;
+
+    assert_eq!(queue_json, json!({
+        "max_concurrent_runs": 1,
+        "running": 1,
+        "queued": 2,
+    }));
+
+    let res = get_with_authorization(&client, format!("http://{}/api/runs/{}/attach", local_addr, detached_run_id), DEFAULT_BASIC_AUTH.to_owned()).await?;
+
+    let mut detached_body = res.into_body();
+
+    let chunk = detached_body.data().await.expect("Output ended early")?;
+
+    assert_eq!(std::str::from_utf8(&chunk)?, "[Queued: position 2]\n");
+
+    // When the slot is free
+    let res = post_with_authorization(&client, format!("http://{}/api/runs/{}/cancel", local_addr, long_run_id), DEFAULT_BASIC_AUTH.to_owned()).await?;
+
+    assert_eq!(res.status(), StatusCode::OK);
+
+    // Then the queued runs should start in order
+    let output = String::from_utf8(hyper::body::to_bytes(queued_body).await?.to_vec())?;
+
+    assert_eq!(output, "Guest\n[Exit code: 0]");
+
+    // (may or may not see it move up the queue, depending on how quickly the first one finishes)
+    let output = String::from_utf8(hyper::body::to_bytes(detached_body).await?.to_vec())?;
+
+    assert!(output.ends_with("Started\nFinished\n[Exit code: 0]"), "Unexpected output: {}", output);
+
+    server_fut.await
+}
+
+async fn get_event_stream(client: &Client<hyper::client::HttpConnector>, uri: String) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let req = Request::builder()
+        .method(Method::GET)
+        .uri(uri)
+        .header(header::AUTHORIZATION, DEFAULT_BASIC_AUTH)
+        .header(header::ACCEPT, "text/event-stream")
+        .body(Body::empty())?;
+
+    Ok(client.request(req).await?)
+}
+
+#[tokio::test]
+async fn should_stream_server_sent_events() -> Result<(), Box<dyn std::error::Error>> {
+    let (local_addr, server_fut) = init_test().await?;
+
+    let client = Client::new();
+
+    // When I run a task asking for events
+    let res = get_event_stream(&client, format!("http://{}/api/tasks/streams/run", local_addr)).await?;
+
+    // Then the output should be sent as events
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers().get(header::CONTENT_TYPE), Some(&HeaderValue::from_static("text/event-stream")));
+
+    let run_id = res.headers().get("X-Run-Id").unwrap().to_str()?.to_owned();
+
+    let output = String::from_utf8(hyper::body::to_bytes(res.into_body()).await?.to_vec())?;
+
+    let started = Regex::new(&format!("^event: started\ndata: \\{{\"run_id\":\"{}\",\"pid\":[0-9]+\\}}\n\n", run_id))?;
+
+    assert!(started.is_match(&output), "Unexpected output: {}", output);
+
+    // (streams are read separately, so lines from each could arrive in either order)
+    assert!(output.contains("event: stdout\ndata: Out\n\n"), "Unexpected output: {}", output);
+    assert!(output.contains("event: stderr\ndata: Err\n\n"), "Unexpected output: {}", output);
+    assert!(output.ends_with("event: exit\ndata: {\"exit_code\":3,\"signal\":null}\n\n"), "Unexpected output: {}", output);
+
+    // When I attach to the finished run
+    let res = get_event_stream(&client, format!("http://{}/api/runs/{}/attach", local_addr, run_id)).await?;
+
+    // Then the stored output should be replayed as events
+    assert_eq!(res.headers().get(header::CONTENT_TYPE), Some(&HeaderValue::from_static("text/event-stream")));
+
+    let output = String::from_utf8(hyper::body::to_bytes(res.into_body()).await?.to_vec())?;
+
+    assert_eq!(output, format!(concat!(
+        "event: started\ndata: {{\"run_id\":\"{}\",\"pid\":null}}\n\n",
+        "event: stdout\ndata: Out\n\n",
+        "event: stderr\ndata: Err\n\n",
+        "event: exit\ndata: {{\"exit_code\":3,\"signal\":null}}\n\n"), run_id));
+
+    server_fut.await
+}
+
+fn ndjson_request(uri: String) -> Result<Request<Body>, Box<dyn std::error::Error>> {
+    Ok(Request::builder()
+        .method(Method::GET)
+        .uri(uri)
+        .header(header::AUTHORIZATION, DEFAULT_BASIC_AUTH)
+        .header(header::ACCEPT, "application/x-ndjson")
+        .header(header::TE, "trailers")
+        .body(Body::empty())?)
+}
+
+fn parse_ndjson(output: &str) -> Result<Vec<Value>, serde_json::Error> {
+    output.lines().map(serde_json::from_str).collect()
+}
+
+#[tokio::test]
+async fn should_stream_ndjson() -> Result<(), Box<dyn std::error::Error>> {
+    let (local_addr, server_fut) = init_test().await?;
+
+    let client = Client::new();
+
+    // When I run a task asking for NDJSON
+    let res = client.request(ndjson_request(format!("http://{}/api/tasks/streams/run", local_addr))?).await?;
+
+    // Then each line should be a JSON object
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers().get(header::CONTENT_TYPE), Some(&HeaderValue::from_static("application/x-ndjson")));
+    assert_eq!(res.headers().get(header::TRAILER), Some(&HeaderValue::from_static("X-Run-Status, X-Exit-Code")));
+
+    let run_id = res.headers().get("X-Run-Id").unwrap().to_str()?.to_owned();
+
+    let output = String::from_utf8(hyper::body::to_bytes(res.into_body()).await?.to_vec())?;
+
+    let lines = parse_ndjson(&output)?;
+
+    assert_eq!(lines.len(), 4, "Unexpected output: {}", output);
+
+    assert_eq!(lines[0]["type"], json!("started"));
+    assert_eq!(lines[0]["run_id"], json!(run_id));
+    assert!(lines[0]["pid"].is_number());
+
+    // (streams are read separately, so lines from each could arrive in either order)
+    let mut output_lines: Vec<&Value> = lines[1..3].iter().collect();
+    output_lines.sort_by_key(|line| line["line"].as_str().map(str::to_owned));
+
+    assert_eq!(output_lines[0]["type"], json!("output"));
+    assert_eq!(output_lines[0]["stream"], json!("stderr"));
+    assert_eq!(output_lines[0]["line"], json!("Err"));
+    assert!(output_lines[0]["time"].is_string());
+    assert_eq!(output_lines[1]["stream"], json!("stdout"));
+    assert_eq!(output_lines[1]["line"], json!("Out"));
+
+    let mut seqs: Vec<u64> = output_lines.iter().map(|line| line["seq"].as_u64().unwrap()).collect();
+    seqs.sort();
+    assert_eq!(seqs, vec![0, 1]);
+
+    // And the last line should say how the run ended
+    assert_eq!(lines[3]["type"], json!("exit"));
+    assert_eq!(lines[3]["run_id"], json!(run_id));
+    assert_eq!(lines[3]["exit_code"], json!(3));
+    assert_eq!(lines[3]["signal"], json!(null));
+    assert!(lines[3]["duration_secs"].is_number());
+
+    server_fut.await
+}
+
+#[tokio::test]
+async fn should_send_input_over_websocket() -> Result<(), Box<dyn std::error::Error>> {
+    use futures::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::Message;
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+    let (local_addr, server_fut) = init_test().await?;
+
+    // Given a plain request isn't enough
+    let res = Client::new().request(Request::builder()
+        .uri(format!("http://{}/api/tasks/stdin/ws", local_addr))
+        .header(header::AUTHORIZATION, DEFAULT_BASIC_AUTH)
+        .body(Body::empty())?).await?;
+
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    // When I run a task accepting input over a WebSocket
+    let mut req = format!("ws://{}/api/tasks/stdin/ws", local_addr).into_client_request()?;
+    req.headers_mut().insert(header::AUTHORIZATION, HeaderValue::from_static(DEFAULT_BASIC_AUTH));
+
+    let stream = tokio::net::TcpStream::connect(local_addr).await?;
+    let (mut socket, res) = tokio_tungstenite::client_async(req, stream).await?;
+
+    assert_eq!(res.status(), StatusCode::SWITCHING_PROTOCOLS);
+    assert!(res.headers().get("X-Run-Id").is_some());
+
+    // And send it some input followed by the end of input
+    socket.send(Message::Text(json!({"type": "input", "data": "42\n"}).to_string())).await?;
+    socket.send(Message::Text(json!({"type": "input", "data": "More\n"}).to_string())).await?;
+    socket.send(Message::Text(json!({"type": "eof"}).to_string())).await?;
+
+    let mut frames: Vec<Value> = vec![];
+    while let Some(message) = socket.next().await {
+        match message? {
+            Message::Text(text) => frames.push(serde_json::from_str(&text)?),
+            Message::Close(_) => break,
+            _ => {}
+        }
+    }
+
+    // Then I should get the task's output (showing it got the input)
+    let lines: Vec<&Value> = frames.iter().filter(|frame| frame["type"] == json!("output")).collect();
+
+    assert_eq!(lines.iter().map(|line| line["line"].as_str().unwrap()).collect::<Vec<_>>(),
+               vec!["Answer: 42", "More", "Done"]);
+    assert_eq!(lines[0]["stream"], json!("stdout"));
+
+    // And finally how the run ended
+    assert_eq!(frames.first().unwrap()["type"], json!("started"));
+    assert_eq!(frames.last().unwrap()["type"], json!("exit"));
+    assert_eq!(frames.last().unwrap()["exit_code"], json!(0));
+
+    server_fut.await
+}
+
+#[tokio::test]
+async fn should_reject_websocket_from_other_origins() -> Result<(), Box<dyn std::error::Error>> {
+    let (local_addr, server_fut) = init_test().await?;
+
+    let client = Client::new();
+
+    let upgrade_request = |origin: String| Request::builder()
+        .uri(format!("http://{}/api/tasks/stdin/ws", local_addr))
+        .header(header::AUTHORIZATION, DEFAULT_BASIC_AUTH)
+        .header(header::CONNECTION, "Upgrade")
+        .header(header::UPGRADE, "websocket")
+        .header(header::SEC_WEBSOCKET_VERSION, "13")
+        .header(header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==")
+        .header(header::ORIGIN, origin)
+        .body(Body::empty());
+
+    // When a page on another site opens a WebSocket (with the browser's cached credentials)
+    let res = client.request(upgrade_request("https://evil.example.com".to_owned())?).await?;
+
+    // Then it should be forbidden
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    // When the server's own page does
+    let res = client.request(upgrade_request(format!("http://{}", local_addr))?).await?;
+
+    // Then the connection should be upgraded
+    assert_eq!(res.status(), StatusCode::SWITCHING_PROTOCOLS);
+
+    server_fut.await
+}
+
+#[tokio::test]
+async fn should_render_output_as_html() -> Result<(), Box<dyn std::error::Error>> {
+    let (local_addr, server_fut) = init_test().await?;
+
+    let client = Client::new();
+
+    // When I run a task from a browser
+    let req = Request::builder()
+        .method(Method::GET)
+        .uri(format!("http://{}/api/tasks/streams/run", local_addr))
+        .header(header::AUTHORIZATION, DEFAULT_BASIC_AUTH)
+        .header(header::ACCEPT, "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8")
+        .body(Body::empty())?;
+
+    let res = client.request(req).await?;
+
+    // Then output should be a page, with error output marked
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers().get(header::CONTENT_TYPE), Some(&HeaderValue::from_static("text/html; charset=utf-8")));
+
+    let output = String::from_utf8(hyper::body::to_bytes(res.into_body()).await?.to_vec())?;
+
+    assert!(output.starts_with("<!DOCTYPE html>"), "Unexpected output: {}", output);
+    assert!(output.contains(">Out\n") || output.contains("\nOut\n"), "Unexpected output: {}", output);
+    assert!(output.contains("<span class=\"stderr\">Err</span>\n"), "Unexpected output: {}", output);
+    assert!(output.ends_with("<span class=\"status\">[Exit code: 3]</span>\n</pre>\n</body>\n</html>\n"), "Unexpected output: {}", output);
+
+    server_fut.await
+}
+
+#[tokio::test]
+async fn should_serve_run_page() -> Result<(), Box<dyn std::error::Error>> {
+    let (local_addr, server_fut) = init_test().await?;
+
+    let client = Client::new();
+
+    // When I open the page for a run
+    let req = Request::builder()
+        .method(Method::GET)
+        .uri(format!("http://{}/web/runs/c0ffee", local_addr))
+        .header(header::AUTHORIZATION, DEFAULT_BASIC_AUTH)
+        .body(Body::empty())?;
+
+    let res = client.request(req).await?;
+
+    // Then I should get the page (which loads the run itself)
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers().get(header::CONTENT_TYPE), Some(&HeaderValue::from_static("text/html; charset=utf-8")));
+
+    let page = String::from_utf8(hyper::body::to_bytes(res.into_body()).await?.to_vec())?;
+
+    assert!(page.contains("<script src=\"../modules/run\" type=\"module\"></script>"), "Unexpected page: {}", page);
+
+    // And its script
+    let req = Request::builder()
+        .method(Method::GET)
+        .uri(format!("http://{}/web/modules/run", local_addr))
+        .body(Body::empty())?;
+
+    let res = client.request(req).await?;
+
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers().get(header::CONTENT_TYPE), Some(&HeaderValue::from_static("application/javascript")));
+
+    server_fut.await
+}
+
+#[tokio::test]
+async fn should_filter_and_page_history() -> Result<(), Box<dyn std::error::Error>> {
+    let (local_addr, server_fut) = init_test().await?;
+
+    let client = Client::new();
+
+    let get_json = |query: &str| {
+        let req = Request::builder()
+            .method(Method::GET)
+            .uri(format!("http://{}/api/history?{}", local_addr, query))
+            .header(header::AUTHORIZATION, DEFAULT_BASIC_AUTH)
+            .body(Body::empty())
+            .unwrap();
+        let res = client.request(req);
+        async move {
+            let res = res.await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            serde_json::from_slice::<Value>(&hyper::body::to_bytes(res.into_body()).await.unwrap()).unwrap()
+        }
+    };
+
+    // Given a task has failed a couple of times
+    let mut run_ids = vec![];
+    for _ in 0..2 {
+        let res = client.request(ndjson_request(format!("http://{}/api/tasks/streams/run", local_addr))?).await?;
+        run_ids.push(res.headers().get("X-Run-Id").unwrap().to_str()?.to_owned());
+        hyper::body::to_bytes(res.into_body()).await?;
+    }
+
+    // When I ask for its failures, one at a time
+    let page1 = get_json("task=streams&outcome=failure&user=admin&per_page=1").await;
+
+    // Then I should get a page of one run
+    assert_eq!(page1["page"], json!(1));
+    assert_eq!(page1["per_page"], json!(1));
+    assert!(page1["total"].as_u64().unwrap() >= 2);
+    assert_eq!(page1["runs"].as_array().unwrap().len(), 1);
+
+    // And the most recent runs should come first (other tests run the same task too)
+    let recent = get_json("task=streams&outcome=failure&user=admin&per_page=100").await;
+    let recent_ids: Vec<&str> = recent["runs"].as_array().unwrap()
+        .iter()
+        .inspect(|run| {
+            assert_eq!(run["task"], json!("streams"));
+            assert_eq!(run["exit_code"], json!(3));
+        })
+        .map(|run| run["id"].as_str().unwrap())
+        .filter(|id| run_ids.iter().any(|run_id| run_id == id))
+        .collect();
+
+    assert_eq!(recent_ids, vec![run_ids[1].as_str(), run_ids[0].as_str()]);
+
+    // And none of them should be listed as successes
+    let successes = get_json("task=streams&outcome=success").await;
+    assert_eq!(successes["total"], json!(0));
+
+    // And unknown filters should be rejected
+    let req = Request::builder()
+        .method(Method::GET)
+        .uri(format!("http://{}/api/history?outcome=maybe", local_addr))
+        .header(header::AUTHORIZATION, DEFAULT_BASIC_AUTH)
+        .body(Body::empty())?;
+
+    assert_eq!(client.request(req).await?.status(), StatusCode::BAD_REQUEST);
+
+    server_fut.await
+}
+
+#[tokio::test]
+async fn should_send_run_status_trailers() -> Result<(), Box<dyn std::error::Error>> {
+    use hyper::body::HttpBody;
+
+    let (local_addr, server_fut) = init_test().await?;
+
+    // (trailers are only supported by HTTP/2)
+    let client: Client<hyper::client::HttpConnector> = Client::builder().http2_only(true).build_http();
+
+    // When I run a failing task asking for trailers
+    let res = client.request(ndjson_request(format!("http://{}/api/tasks/streams/run", local_addr))?).await?;
+
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let mut body = res.into_body();
+
+    while let Some(chunk) = body.data().await {
+        chunk?;
+    }
+
+    // Then the trailers should say that it failed
+    let trailers = body.trailers().await?.expect("Trailers not sent");
+
+    assert_eq!(trailers.get("X-Run-Status"), Some(&HeaderValue::from_static("failure")));
+    assert_eq!(trailers.get("X-Exit-Code"), Some(&HeaderValue::from_static("3")));
+
+    // When I run a successful task
+    let res = client.request(ndjson_request(format!("http://{}/api/tasks/guest/run", local_addr))?).await?;
+
+    let mut body = res.into_body();
+
+    while let Some(chunk) = body.data().await {
+        chunk?;
+    }
+
+    // Then the trailers should say that it succeeded
+    let trailers = body.trailers().await?.expect("Trailers not sent");
+
+    assert_eq!(trailers.get("X-Run-Status"), Some(&HeaderValue::from_static("success")));
+    assert_eq!(trailers.get("X-Exit-Code"), Some(&HeaderValue::from_static("0")));
+
+    server_fut.await
+}
+
+#[tokio::test]
+async fn should_list_schedules() -> Result<(), Box<dyn std::error::Error>> {
+    let (local_addr, server_fut) = init_test().await?;
+
+    let client = Client::new();
+
+    let before = Utc::now();
+
+    // When I list schedules
+    let schedules_json = get_json(&client, format!("http://{}/api/schedules", local_addr)).await?;
+
+    // Then the scheduled task should be listed, with when it next runs
+    let schedules = schedules_json.as_array().unwrap();
+
+    assert_eq!(schedules.len(), 1);
+
+    let schedule = &schedules[0];
+
+    assert_eq!(schedule["task"], json!("scheduled"));
+    assert_eq!(schedule["cron"], json!("*/5 * * * *"));
+    assert_eq!(schedule["params"], json!({"count": "2"}));
+    assert_eq!(schedule["missed"], json!("skip"));
+    assert_eq!(schedule["overlap"], json!("queue"));
+
+    let next_run_at = DateTime::parse_from_rfc3339(schedule["next_run_at"].as_str().unwrap())?;
+
+    assert_eq!(next_run_at.minute() % 5, 0);
+    assert_eq!(next_run_at.second(), 0);
+    assert!(next_run_at > before && next_run_at <= Utc::now() + chrono::Duration::minutes(5));
+
+    server_fut.await
+}
+
+#[tokio::test]
+async fn should_run_task_for_signed_webhook() -> Result<(), Box<dyn std::error::Error>> {
+    let (local_addr, server_fut) = init_test().await?;
+
+    let client = Client::new();
+
+    let body = r#"{"ref": "refs/heads/main", "repository": {"default_branch": "main"}}"#;
+
+    let sign = |secret: &str| {
+        let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, secret.as_bytes());
+        format!("sha256={}", hex::encode(ring::hmac::sign(&key, body.as_bytes()).as_ref()))
+    };
+
+    let hook_request = |uri: String, signature: String| {
+        Request::builder()
+            .method(Method::POST)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/json")
+            .header("X-Hub-Signature-256", signature)
+            .body(Body::from(body))
+    };
+
+    // When a webhook request is signed with the task's secret (without any user credentials)
+    let res = client.request(hook_request(format!("http://{}/hooks/hooked", local_addr), sign("It's a Secret to Everybody"))?).await?;
+
+    // Then the task should be run in the background, with parameters from the body
+    assert_eq!(res.status(), StatusCode::ACCEPTED);
+
+    let run_id = res.headers().get("X-Run-Id").unwrap().to_str()?.to_owned();
+
+    let output = get_text(&client, format!("http://{}/api/runs/{}/attach", local_addr, run_id)).await?;
+
+    assert_eq!(output, "Branch: main\n[Exit code: 0]");
+
+    let run_json = get_json(&client, format!("http://{}/api/runs/{}", local_addr, run_id)).await?;
+
+    assert_eq!(run_json["user"], json!("webhook"));
+
+    // When it's signed with some other secret
+    let res = client.request(hook_request(format!("http://{}/hooks/hooked", local_addr), sign("guess"))?).await?;
+
+    // Then it should be rejected
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    // And tasks without a webhook shouldn't be found
+    let res = client.request(hook_request(format!("http://{}/hooks/example1", local_addr), sign("It's a Secret to Everybody"))?).await?;
+
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    // When the body is too large (whether it says so up front or not)
+    let large_body = vec![b' '; 2 * 1024 * 1024];
+
+    let res = client.request(Request::builder()
+        .method(Method::POST)
+        .uri(format!("http://{}/hooks/hooked", local_addr))
+        .body(Body::from(large_body.clone()))?).await?;
+
+    // Then it should be rejected
+    assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+    let (mut sender, chunked_body) = Body::channel();
+    tokio::spawn(async move {
+        for chunk in large_body.chunks(64 * 1024) {
+            if sender.send_data(hyper::body::Bytes::copy_from_slice(chunk)).await.is_err() {
+                break; // (server stopped reading)
+            }
+        }
+    });
+
+    let res = client.request(Request::builder()
+        .method(Method::POST)
+        .uri(format!("http://{}/hooks/hooked", local_addr))
+        .body(chunked_body)?).await?;
+
+    assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+    server_fut.await
+}
+
+const NOTIFY_TASK_TOML: &str = r#"
+[task]
+method = ["GET"]
+
+[[task.parameters]]
+name = "code"
+type = "number"
+env = "CODE"
+
+[exec]
+command = "bash"
+args = ["-c", "echo Exiting with $CODE; exit $CODE"]
+
+[[notify]]
+url = "http://{receiver}/task"
+body = '{"text": "{{task}} ({{params.code}}): {{status}}"}'
+"#;
+
+#[tokio::test]
+async fn should_notify_when_runs_finish() -> Result<(), Box<dyn std::error::Error>> {
+    use hyper::service::{make_service_fn, service_fn};
+
+    // Given somewhere to send notifications
+    let (received_tx, mut received_rx) = tokio::sync::mpsc::unbounded_channel::<(String, Value)>();
+
+    let receiver = hyper::Server::bind(&"127.0.0.1:0".parse()?).serve(make_service_fn(move |_conn| {
+        let received_tx = received_tx.clone();
+        async move {
+            Ok::<_, std::convert::Infallible>(service_fn(move |req: Request<Body>| {
+                let received_tx = received_tx.clone();
+                async move {
+                    let path = req.uri().path().to_owned();
+                    let body = hyper::body::to_bytes(req.into_body()).await?;
+                    received_tx.send((path, serde_json::from_slice(&body).unwrap())).unwrap();
+                    Ok::<_, hyper::Error>(Response::new(Body::empty()))
+                }
+            }))
+        }
+    }));
+    let receiver_addr = receiver.local_addr();
+    tokio::spawn(receiver);
+
+    // And a server sending failures of any task there (as well as all runs of a task)
+    let config_dir: PathBuf = format!("{}/target/test-notify", CARGO_MANIFEST_DIR).into();
+
+    let _ = std::fs::remove_dir_all(&config_dir);
+    std::fs::create_dir_all(config_dir.join("tasks"))?;
+    std::fs::write(config_dir.join("server.toml"), format!("{}\n[[notify]]\nurl = \"http://{}/all\"\non = \"failure\"\n", RELOAD_SERVER_TOML, receiver_addr))?;
+
+    write_reload_task(&config_dir, "notified", &NOTIFY_TASK_TOML.replace("{receiver}", &receiver_addr.to_string()));
+    write_reload_task(&config_dir, "missing", "[task]\nmethod = [\"GET\"]\n\n[exec]\ncommand = \"./no-such-command\"\n");
+
+    let (local_addr, server_fut) = init_test_with(config_dir.join("server.toml").to_str().unwrap()).await?;
+
+    let client = Client::new();
+
+    async fn next_received(received_rx: &mut tokio::sync::mpsc::UnboundedReceiver<(String, Value)>) -> (String, Value) {
+        tokio::time::timeout(std::time::Duration::from_secs(5), received_rx.recv()).await.expect("Nothing received").unwrap()
+    }
+
+    // When a run succeeds
+    let res = get_with_authorization(&client, format!("http://{}/api/tasks/notified/run?code=0", local_addr), DEFAULT_BASIC_AUTH.to_owned()).await?;
+
+    assert_eq!(get_response_text(res).await, "Exiting with 0\n[Exit code: 0]");
+
+    // Then only the task's templated notification should be sent
+    assert_eq!(next_received(&mut received_rx).await, ("/task".to_owned(), json!({"text": "notified (0): success"})));
+
+    // When a run fails
+    let res = get_with_authorization(&client, format!("http://{}/api/tasks/notified/run?code=3", local_addr), DEFAULT_BASIC_AUTH.to_owned()).await?;
+
+    let run_id = res.headers().get("X-Run-Id").unwrap().to_str()?.to_owned();
+
+    assert_eq!(get_response_text(res).await, "Exiting with 3\n[Exit code: 3]");
+
+    // Then both should be sent
+    let mut received = [next_received(&mut received_rx).await, next_received(&mut received_rx).await];
+    received.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let (path, payload) = &received[0];
+
+    assert_eq!(path, "/all");
+    assert_eq!(payload["task"], json!("notified"));
+    assert_eq!(payload["run_id"], json!(run_id));
+    assert_eq!(payload["user"], json!("admin"));
+    assert_eq!(payload["params"], json!({"code": "3"}));
+    assert_eq!(payload["status"], json!("failure"));
+    assert_eq!(payload["exit_code"], json!(3));
+    assert!(payload["duration_secs"].is_number());
+    assert_eq!(payload["output_tail"], json!("Exiting with 3\n"));
+
+    assert_eq!(received[1], ("/task".to_owned(), json!({"text": "notified (3): failure"})));
+
+    // When a run can't even start
+    let res = get_with_authorization(&client, format!("http://{}/api/tasks/missing/run", local_addr), DEFAULT_BASIC_AUTH.to_owned()).await?;
+
+    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+    // Then that's a failure too
+    let (path, payload) = next_received(&mut received_rx).await;
+
+    assert_eq!(path, "/all");
+    assert_eq!(payload["task"], json!("missing"));
+    assert_eq!(payload["status"], json!("failure"));
+    assert_eq!(payload["output_tail"], json!("Error executing command\n"));
+
+    server_fut.await
+}
+
+const EMAIL_TASK_TOML: &str = r#"
+[task]
+method = ["GET"]
+
+[[task.parameters]]
+name = "code"
+type = "number"
+env = "CODE"
+
+[exec]
+command = "bash"
+args = ["-c", "echo Exiting with $CODE; exit $CODE"]
+
+[[email]]
+to = ["ops@example.com"]
+"#;
+
+/// Local stand-in for a mail server, sending each message it takes to the channel
+async fn fake_smtp(received_tx: tokio::sync::mpsc::UnboundedSender<String>) -> Result<u16, Box<dyn std::error::Error>> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let mut conn = BufReader::new(stream);
+            let mut message: Option<String> = None;
+
+            conn.get_mut().write_all(b"220 localhost ready\r\n").await.unwrap();
+
+            loop {
+                let mut line = String::new();
+                if conn.read_line(&mut line).await.unwrap() == 0 {
+                    break;
+                }
+
+                let reply = match message.as_mut() {
+                    Some(_) if line == ".\r\n" => {
+                        received_tx.send(message.take().unwrap()).unwrap();
+                        "250 queued\r\n"
+                    }
+                    Some(message) => {
+                        message.push_str(&line);
+                        continue;
+                    }
+                    None if line.starts_with("DATA") => {
+                        message = Some(String::new());
+                        "354 go ahead\r\n"
+                    }
+                    None if line.starts_with("QUIT") => "221 bye\r\n",
+                    None => "250 ok\r\n",
+                };
+
+                conn.get_mut().write_all(reply.as_bytes()).await.unwrap();
+            }
+        }
+    });
+
+    Ok(port)
+}
+
+#[tokio::test]
+async fn should_email_when_runs_fail() -> Result<(), Box<dyn std::error::Error>> {
+    // Given a mail server
+    let (received_tx, mut received_rx) = tokio::sync::mpsc::unbounded_channel::<String>();
+
+    let smtp_port = fake_smtp(received_tx).await?;
+
+    // And a server with a task that emails when it fails
+    let config_dir: PathBuf = format!("{}/target/test-email", CARGO_MANIFEST_DIR).into();
+
+    let _ = std::fs::remove_dir_all(&config_dir);
+    std::fs::create_dir_all(config_dir.join("tasks"))?;
+    std::fs::write(config_dir.join("server.toml"),
+                   format!("{}\n[smtp]\nhost = \"127.0.0.1\"\nport = {}\nstarttls = false\nfrom = \"henchman@example.com\"\n",
+                           RELOAD_SERVER_TOML.replace("[server]\n", "[server]\nurl = \"https://henchman.example.com\"\n"), smtp_port))?;
+
+    write_reload_task(&config_dir, "emailed", EMAIL_TASK_TOML);
+
+    let (local_addr, server_fut) = init_test_with(config_dir.join("server.toml").to_str().unwrap()).await?;
+
+    let client = Client::new();
+
+    // When a run succeeds, and then one fails
+    let res = get_with_authorization(&client, format!("http://{}/api/tasks/emailed/run?code=0", local_addr), DEFAULT_BASIC_AUTH.to_owned()).await?;
+
+    assert_eq!(get_response_text(res).await, "Exiting with 0\n[Exit code: 0]");
+
+    let res = get_with_authorization(&client, format!("http://{}/api/tasks/emailed/run?code=4", local_addr), DEFAULT_BASIC_AUTH.to_owned()).await?;
+
+    let run_id = res.headers().get("X-Run-Id").unwrap().to_str()?.to_owned();
+
+    assert_eq!(get_response_text(res).await, "Exiting with 4\n[Exit code: 4]");
+
+    // Then only the failure should be emailed
+    let message = tokio::time::timeout(std::time::Duration::from_secs(5), received_rx.recv()).await.expect("Nothing received").unwrap();
+
+    assert!(message.contains("To: ops@example.com\r\n"), "{}", message);
+    assert!(message.contains("Subject: Task emailed failed\r\n"), "{}", message);
+    assert!(message.contains("Outcome: exit code: 4\r\n"), "{}", message);
+    assert!(message.contains(&format!("https://henchman.example.com/web/runs/{}\r\n", run_id)), "{}", message);
+    assert!(message.contains("Exiting with 4\r\n"), "{}", message);
+
+    assert!(tokio::time::timeout(std::time::Duration::from_millis(200), received_rx.recv()).await.is_err());
+
+    server_fut.await
+}
+
+const SECRET_TASK_TOML: &str = r#"
+[task]
+method = ["GET"]
+
+[[task.parameters]]
+name = "user"
+env = "USER_NAME"
+
+[[task.parameters]]
+name = "token"
+type = "secret"
+default = "default-token"
+env = "TOKEN"
+
+[exec]
+command = "bash"
+args = ["-c", "echo $USER_NAME logging in with $TOKEN"]
+"#;
+
+#[tokio::test]
+async fn should_keep_secret_parameters_out_of_sight() -> Result<(), Box<dyn std::error::Error>> {
+    // Given a task with a secret parameter
+    let config_dir: PathBuf = format!("{}/target/test-secret-params", CARGO_MANIFEST_DIR).into();
+
+    let _ = std::fs::remove_dir_all(&config_dir);
+    std::fs::create_dir_all(config_dir.join("tasks"))?;
+    std::fs::write(config_dir.join("server.toml"), format!("{}\n[history]\ndir = \"history\"\n", RELOAD_SERVER_TOML))?;
+
+    write_reload_task(&config_dir, "secretive", SECRET_TASK_TOML);
+
+    let (local_addr, server_fut) = init_test_with(config_dir.join("server.toml").to_str().unwrap()).await?;
+
+    let client = Client::new();
+
+    // When getting the task
+    let task_json = get_json(&client, format!("http://{}/api/tasks/secretive", local_addr)).await?;
+
+    // Then its default shouldn't be there
+    assert_eq!(task_json["parameters"][1], json!({"name": "token", "required": false, "type": "secret"}));
+
+    // When running it
+    let res = get_with_authorization(&client, format!("http://{}/api/tasks/secretive/run?user=bob&token=hunter2", local_addr), DEFAULT_BASIC_AUTH.to_owned()).await?;
+
+    let run_id = res.headers().get("X-Run-Id").unwrap().to_str()?.to_owned();
+
+    // Then the secret should be masked in the output
+    assert_eq!(get_response_text(res).await, "bob logging in with ***\n[Exit code: 0]");
+
+    // And in the run's history
+    let run_json = get_json(&client, format!("http://{}/api/runs/{}", local_addr, run_id)).await?;
+
+    assert_eq!(run_json["params"], json!({"user": "bob", "token": "***"}));
+    assert_eq!(run_json["secret_params"], json!(["token"]));
+
+    let history = std::fs::read_dir(config_dir.join("history").join(&run_id))?
+        .map(|entry| std::fs::read_to_string(entry?.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    assert!(!history.is_empty());
+    assert!(history.iter().all(|file| !file.contains("hunter2")), "{:?}", history);
+
+    // As well as when the default is used
+    let res = get_with_authorization(&client, format!("http://{}/api/tasks/secretive/run?user=bob", local_addr), DEFAULT_BASIC_AUTH.to_owned()).await?;
+
+    assert_eq!(get_response_text(res).await, "bob logging in with ***\n[Exit code: 0]");
+
+    server_fut.await
+}
+
+const SECRET_ENV_TASK_TOML: &str = r#"
+[task]
+method = ["GET"]
+
+[exec]
+command = "bash"
+args = ["-c", "echo Deploying to $REGION with $AWS_KEY and $DB_PASSWORD"]
+
+[exec.env]
+REGION = "eu-west-1"
+AWS_KEY = { secret = "aws_key" }
+DB_PASSWORD = { secret = "db_password" }
+"#;
+
+#[cfg(unix)]
+#[tokio::test]
+async fn should_inject_secrets_into_environment() -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    // Given secrets in a file and a directory (only readable by their owner)
+    let config_dir: PathBuf = format!("{}/target/test-secret-store", CARGO_MANIFEST_DIR).into();
+
+    let _ = std::fs::remove_dir_all(&config_dir);
+    std::fs::create_dir_all(config_dir.join("tasks"))?;
+    std::fs::create_dir_all(config_dir.join("credentials"))?;
+
+    std::fs::write(config_dir.join("secrets.toml"), "aws_key = \"AKIAEXAMPLE\"\n")?;
+    std::fs::write(config_dir.join("credentials").join("db_password"), "hunter2\n")?;
+
+    for path in [config_dir.join("secrets.toml"), config_dir.join("credentials").join("db_password")] {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    }
+
+    // And a task using them in its environment
+    std::fs::write(config_dir.join("server.toml"),
+                   format!("{}\n[history]\ndir = \"history\"\n\n[secrets]\nfile = \"secrets.toml\"\ndir = \"credentials\"\n", RELOAD_SERVER_TOML))?;
+
+    write_reload_task(&config_dir, "deploy", SECRET_ENV_TASK_TOML);
+
+    let (local_addr, server_fut) = init_test_with(config_dir.join("server.toml").to_str().unwrap()).await?;
+
+    let client = Client::new();
+
+    // When running it
+    let res = get_with_authorization(&client, format!("http://{}/api/tasks/deploy/run", local_addr), DEFAULT_BASIC_AUTH.to_owned()).await?;
+
+    let run_id = res.headers().get("X-Run-Id").unwrap().to_str()?.to_owned();
+
+    // Then the process should get them, but they should be masked in the output
+    assert_eq!(get_response_text(res).await, "Deploying to eu-west-1 with *** and ***\n[Exit code: 0]");
+
+    // And not be anywhere in the run's history, or the task's JSON
+    let history = std::fs::read_dir(config_dir.join("history").join(&run_id))?
+        .map(|entry| std::fs::read_to_string(entry?.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    assert!(history.iter().all(|file| !file.contains("AKIAEXAMPLE") && !file.contains("hunter2")), "{:?}", history);
+
+    let task_json = get_json(&client, format!("http://{}/api/tasks/deploy", local_addr)).await?;
+
+    assert!(!task_json.to_string().contains("AKIAEXAMPLE"), "{}", task_json);
+
+    // When a secret it uses is removed
+    std::fs::remove_file(config_dir.join("credentials").join("db_password"))?;
+
+    let res = post_with_authorization(&client, format!("http://{}/api/admin/reload", local_addr), DEFAULT_BASIC_AUTH.to_owned()).await?;
+
+    // Then the configuration shouldn't be reloaded
+    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+    let reload_json: Value = serde_json::from_slice(&hyper::body::to_bytes(res.into_body()).await?)?;
+
+    assert_eq!(reload_json["error"], json!("Unknown secret: db_password (task deploy)"));
+    assert_eq!(reload_json["secrets"], json!(2));
+
+    server_fut.await
+}
+
+#[tokio::test]
+async fn should_record_runs_that_fail_to_start() -> Result<(), Box<dyn std::error::Error>> {
+    // Given a task whose command doesn't exist
+    let config_dir: PathBuf = format!("{}/target/test-spawn-error", CARGO_MANIFEST_DIR).into();
+
+    let _ = std::fs::remove_dir_all(&config_dir);
+    std::fs::create_dir_all(config_dir.join("tasks"))?;
+    std::fs::write(config_dir.join("server.toml"), format!("{}\n[history]\ndir = \"history\"\n", RELOAD_SERVER_TOML))?;
+
+    write_reload_task(&config_dir, "missing", "[task]\nmethod = [\"GET\"]\n\n[exec]\ncommand = \"./no-such-command\"\n");
+
+    let (local_addr, server_fut) = init_test_with(config_dir.join("server.toml").to_str().unwrap()).await?;
+
+    let client = Client::new();
+
+    // When running it
+    let res = get_with_authorization(&client, format!("http://{}/api/tasks/missing/run", local_addr), DEFAULT_BASIC_AUTH.to_owned()).await?;
+
+    // Then it should fail
+    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+    // And its history should show it finished (rather than running forever)
+    let history_json = get_json(&client, format!("http://{}/api/history?task=missing", local_addr)).await?;
+
+    assert_eq!(history_json["total"], json!(1));
+
+    let run_json = &history_json["runs"][0];
+
+    assert!(run_json["finished_at"].is_string(), "{}", run_json);
+
+    let history_json = get_json(&client, format!("http://{}/api/history?outcome=running", local_addr)).await?;
+
+    assert_eq!(history_json["total"], json!(0));
+
+    let output = get_text(&client, format!("http://{}/api/runs/{}/output", local_addr, run_json["id"].as_str().unwrap())).await?;
+
+    assert!(output.contains("Error executing command"), "{}", output);
+
+    server_fut.await
+}
@@ -0,0 +1,15 @@
+#!/bin/bash
+
+# starts background jobs (which would be left running, holding the output open, if only this script was killed)
+sleep 30 &
+echo "Background: $!"
+sleep 30 &
+echo "Background: $!"
+
+if [ "$1" == "exit" ]; then
+  echo "Exiting"
+  exit 0
+fi
+
+echo "Waiting"
+wait